@@ -0,0 +1,88 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs the scheduler and build workers for distributed compilation.
+
+#[macro_use]
+extern crate clap;
+extern crate num_cpus;
+extern crate sccache;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use sccache::dist::{get_token, Scheduler, Worker};
+use std::error::Error;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process;
+
+fn get_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("sccache-dist")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Runs the scheduler and build workers for distributed compilation")
+        .after_help("SCCACHE_DIST_TOKEN must be set to a token shared by the scheduler, the workers \
+                     and the sccache servers that use them.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("scheduler")
+                    .about("Run a scheduler")
+                    .arg(Arg::from_usage("--listen [ADDR] 'the address to listen on'")
+                         .default_value("127.0.0.1:10600")))
+        .subcommand(SubCommand::with_name("worker")
+                    .about("Run a build worker")
+                    .arg(Arg::from_usage("--scheduler <ADDR> 'the address of the scheduler'"))
+                    .arg(Arg::from_usage("--listen [ADDR] 'the address to accept jobs on'")
+                         .default_value("127.0.0.1:10601"))
+                    .arg(Arg::from_usage("--slots [N] 'how many jobs to run at once, by default one per CPU'"))
+                    .arg(Arg::from_usage("--dir <DIR> 'the directory to keep toolchains and run jobs in'")))
+}
+
+fn run_scheduler(matches: &ArgMatches) -> Result<(), Box<Error>> {
+    let addr = value_t!(matches, "listen", SocketAddr).unwrap_or_else(|e| e.exit());
+    Scheduler::bind(&addr, get_token()?)?.run()?;
+    Ok(())
+}
+
+fn run_worker(matches: &ArgMatches) -> Result<(), Box<Error>> {
+    let scheduler = value_t!(matches, "scheduler", SocketAddr).unwrap_or_else(|e| e.exit());
+    let addr = value_t!(matches, "listen", SocketAddr).unwrap_or_else(|e| e.exit());
+    let slots = if matches.is_present("slots") {
+        value_t!(matches, "slots", usize).unwrap_or_else(|e| e.exit())
+    } else {
+        num_cpus::get()
+    };
+    let dir = PathBuf::from(matches.value_of_os("dir").unwrap());
+    let listener = TcpListener::bind(&addr)?;
+    Worker::new(listener, scheduler, get_token()?, slots, &dir)?.run()?;
+    Ok(())
+}
+
+fn main() {
+    sccache::init_logging();
+    let matches = get_app().get_matches();
+    let result = match matches.subcommand() {
+        ("scheduler", Some(matches)) => run_scheduler(matches),
+        ("worker", Some(matches)) => run_worker(matches),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        let stderr = &mut io::stderr();
+        writeln!(stderr, "error: {}", e).unwrap();
+        let mut cause = e.cause();
+        while let Some(e) = cause {
+            writeln!(stderr, "caused by: {}", e).unwrap();
+            cause = e.cause();
+        }
+        process::exit(2);
+    }
+}
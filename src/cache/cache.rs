@@ -51,13 +51,16 @@ use zip::write::FileOptions;
 use errors::*;
 
 //TODO: might need to put this somewhere more central
-const APP_INFO: AppInfo = AppInfo {
+pub const APP_INFO: AppInfo = AppInfo {
     name: "sccache",
     author: "Mozilla",
 };
 
 const TEN_GIGS: u64 = 10 * 1024 * 1024 * 1024;
 
+/// The name of the cache entry object recording how long the compile took.
+const COMPILE_TIME: &'static str = "compile_time";
+
 /// Result of a cache lookup.
 pub enum Cache {
     /// Result was found in cache.
@@ -112,11 +115,28 @@ impl CacheRead {
         io::copy(&mut file, to)?;
         Ok(file.unix_mode())
     }
+
+    /// Get how long the compile that produced this cache entry took, if it
+    /// was recorded.
+    pub fn get_compile_time(&mut self) -> Option<Duration> {
+        let mut millis = String::new();
+        match self.zip.by_name(COMPILE_TIME) {
+            Ok(mut file) => {
+                if file.read_to_string(&mut millis).is_err() {
+                    return None;
+                }
+            }
+            Err(_) => return None,
+        }
+        millis.trim().parse().ok().map(Duration::from_millis)
+    }
 }
 
 /// Data to be stored in the compiler cache.
 pub struct CacheWrite {
     zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    /// The data of an entry created already finished.
+    finished: Option<Vec<u8>>,
 }
 
 impl CacheWrite {
@@ -125,6 +145,17 @@ impl CacheWrite {
     {
         CacheWrite {
             zip: ZipWriter::new(io::Cursor::new(vec!())),
+            finished: None,
+        }
+    }
+
+    /// Create a cache entry holding `data`, as returned by `finish`.
+    /// Nothing more can be put in it.
+    pub fn from_finished(data: Vec<u8>) -> CacheWrite
+    {
+        CacheWrite {
+            zip: ZipWriter::new(io::Cursor::new(vec!())),
+            finished: Some(data),
         }
     }
 
@@ -133,6 +164,9 @@ impl CacheWrite {
     pub fn put_object<T>(&mut self, name: &str, from: &mut T, mode: Option<u32>) -> Result<()>
         where T: Read,
     {
+        if self.finished.is_some() {
+            bail!("Cache entry is already finished");
+        }
         let opts = FileOptions::default().compression_method(CompressionMethod::Deflated);
         let opts = if let Some(mode) = mode { opts.unix_permissions(mode) } else { opts };
         self.zip.start_file(name, opts).chain_err(|| {
@@ -142,10 +176,19 @@ impl CacheWrite {
         Ok(())
     }
 
+    /// Record that the compile that produced this cache entry took `duration`.
+    pub fn put_compile_time(&mut self, duration: Duration) -> Result<()> {
+        let millis = duration.as_secs() * 1000 + (duration.subsec_nanos() / 1000_000) as u64;
+        self.put_object(COMPILE_TIME, &mut millis.to_string().as_bytes(), None)
+    }
+
     /// Finish writing data to the cache entry writer, and return the data.
     pub fn finish(self) -> Result<Vec<u8>>
     {
-        let CacheWrite { mut zip } = self;
+        let CacheWrite { mut zip, finished } = self;
+        if let Some(data) = finished {
+            return Ok(data);
+        }
         let cur = zip.finish().chain_err(|| "Failed to finish cache entry zip")?;
         Ok(cur.into_inner())
     }
@@ -176,6 +219,12 @@ pub trait Storage {
 
     /// Get the maximum storage size, if applicable.
     fn max_size(&self) -> Option<u64>;
+
+    /// Get when the credentials used to access the storage expire, in
+    /// seconds since the Unix epoch, if they do.
+    fn credentials_expire_at(&self) -> SFuture<Option<i64>> {
+        f_ok(None)
+    }
 }
 
 fn parse_size(val: &str) -> Option<u64> {
@@ -333,3 +382,31 @@ fn test_parse_size() {
     assert_eq!(Some(TEN_GIGS), parse_size("10G"));
     assert_eq!(Some(1024 * TEN_GIGS), parse_size("10T"));
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compile_time_round_trip() {
+        let mut entry = CacheWrite::new();
+        entry.put_compile_time(Duration::from_millis(1234)).unwrap();
+        let data = entry.finish().unwrap();
+        let mut entry = CacheRead::from(io::Cursor::new(data)).unwrap();
+        assert_eq!(Some(Duration::from_millis(1234)), entry.get_compile_time());
+        // Entries written before compile times were recorded don't have one.
+        let data = CacheWrite::new().finish().unwrap();
+        let mut entry = CacheRead::from(io::Cursor::new(data)).unwrap();
+        assert_eq!(None, entry.get_compile_time());
+    }
+
+    #[test]
+    fn test_from_finished() {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"data"[..], None).unwrap();
+        let data = entry.finish().unwrap();
+        let mut entry = CacheWrite::from_finished(data.clone());
+        assert!(entry.put_object("more", &mut &b"data"[..], None).is_err());
+        assert_eq!(data, entry.finish().unwrap());
+    }
+}
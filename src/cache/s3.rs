@@ -106,6 +106,12 @@ impl Storage for S3Cache {
         Box::new(response.map(move |_| start.elapsed()))
     }
 
+    fn credentials_expire_at(&self) -> SFuture<Option<i64>> {
+        Box::new(self.provider.credentials().chain_err(|| {
+            "failed to get AWS credentials"
+        }).map(|credentials| Some(credentials.expires_at().timestamp())))
+    }
+
     fn location(&self) -> String {
         format!("S3, bucket: {}", self.bucket)
     }
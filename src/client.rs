@@ -17,6 +17,7 @@ use protocol::{Request, Response};
 use retry::retry;
 use bincode;
 use errors::*;
+use server::ServerAddr;
 use std::io::{
     self,
     BufReader,
@@ -25,23 +26,36 @@ use std::io::{
     Write,
 };
 use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 
 /// A connection to an sccache server.
 pub struct ServerConnection {
     /// A reader for the socket connected to the server.
-    reader : BufReader<TcpStream>,
+    reader : BufReader<Box<Read>>,
     /// A writer for the socket connected to the server.
-    writer : BufWriter<TcpStream>,
+    writer : BufWriter<Box<Write>>,
 }
 
 impl ServerConnection {
     /// Create a new connection using `stream`.
     pub fn new(stream : TcpStream) -> io::Result<ServerConnection> {
         let writer = try!(stream.try_clone());
-        Ok(ServerConnection {
-            reader : BufReader::new(stream),
+        Ok(ServerConnection::from_halves(Box::new(stream), Box::new(writer)))
+    }
+
+    /// Create a new connection using the Unix socket `stream`.
+    #[cfg(unix)]
+    pub fn new_unix(stream : UnixStream) -> io::Result<ServerConnection> {
+        let writer = try!(stream.try_clone());
+        Ok(ServerConnection::from_halves(Box::new(stream), Box::new(writer)))
+    }
+
+    fn from_halves(reader : Box<Read>, writer : Box<Write>) -> ServerConnection {
+        ServerConnection {
+            reader : BufReader::new(reader),
             writer : BufWriter::new(writer),
-        })
+        }
     }
 
     /// Send `request` to the server, read and return a `Response`.
@@ -71,24 +85,33 @@ impl ServerConnection {
     }
 }
 
-/// Establish a TCP connection to an sccache server listening on `port`.
-pub fn connect_to_server(port: u16) -> io::Result<ServerConnection> {
-    trace!("connect_to_server({})", port);
-    let stream = try!(TcpStream::connect(("127.0.0.1", port)));
-    ServerConnection::new(stream)
+/// Establish a connection to an sccache server listening on `addr`.
+pub fn connect_to_server(addr: &ServerAddr) -> io::Result<ServerConnection> {
+    trace!("connect_to_server({})", addr);
+    match *addr {
+        ServerAddr::Tcp(port) => {
+            let stream = try!(TcpStream::connect(("127.0.0.1", port)));
+            ServerConnection::new(stream)
+        }
+        #[cfg(unix)]
+        ServerAddr::Unix(ref path) => {
+            let stream = try!(UnixStream::connect(path));
+            ServerConnection::new_unix(stream)
+        }
+    }
 }
 
-/// Attempt to establish a TCP connection to an sccache server listening on `port`.
+/// Attempt to establish a connection to an sccache server listening on `addr`.
 ///
 /// If the connection fails, retry a few times.
-pub fn connect_with_retry(port: u16) -> io::Result<ServerConnection> {
-    trace!("connect_with_retry({})", port);
+pub fn connect_with_retry(addr: &ServerAddr) -> io::Result<ServerConnection> {
+    trace!("connect_with_retry({})", addr);
     // TODOs:
     // * Pass the server Child in here, so we can stop retrying
     //   if the process exited.
     // * Send a pipe handle to the server process so it can notify
     //   us once it starts the server instead of us polling.
-    match retry(10, 500, || connect_to_server(port), |res| res.is_ok()) {
+    match retry(10, 500, || connect_to_server(addr), |res| res.is_ok()) {
         Ok(Ok(conn)) => Ok(conn),
         _ => Err(io::Error::new(io::ErrorKind::TimedOut,
                                 "Connection to server timed out")),
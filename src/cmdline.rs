@@ -34,10 +34,14 @@ arg_enum!{
 
 /// A specific command to run.
 pub enum Command {
-    /// Show cache statistics and exit.
-    ShowStats(StatsFormat),
+    /// Show cache statistics and exit, with lifetime statistics if the flag
+    /// is set.
+    ShowStats(StatsFormat, bool),
     /// Zero cache statistics and exit.
     ZeroStats,
+    /// Check the health of the server and exit, with a non-zero status if
+    /// it's unhealthy.
+    Status(StatsFormat),
     /// Run background server.
     InternalStartServer,
     /// Start background server as a subprocess.
@@ -72,10 +76,12 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
         .args_from_usage(
             "-s --show-stats 'show cache statistics'
              -z, --zero-stats 'zero statistics counters'
+             --status 'check that the server and its cache storage work'
+             --lifetime 'with --show-stats, show statistics since they were first saved'
              --start-server  'start background server'
              --stop-server   'stop background server'"
                 )
-        .arg(Arg::from_usage("--stats-format  'set output format of statistics'")
+        .arg(Arg::from_usage("--stats-format  'set output format of statistics or status'")
              .possible_values(&StatsFormat::variants())
              .default_value("text"))
         .arg(
@@ -130,6 +136,7 @@ pub fn parse() -> Result<Command> {
     let start_server = matches.is_present("start-server");
     let stop_server = matches.is_present("stop-server");
     let zero_stats = matches.is_present("zero-stats");
+    let status = matches.is_present("status");
     let cmd = matches.values_of_os("cmd");
     // Ensure that we've only received one command to run.
     fn is_some<T>(x : &Option<T>) -> bool {
@@ -140,6 +147,7 @@ pub fn parse() -> Result<Command> {
         show_stats,
         start_server,
         stop_server,
+        status,
         is_some(&cmd),
             ].iter()
         .fold(0, |acc, &x| acc + (x as usize)) > 1 {
@@ -150,13 +158,17 @@ pub fn parse() -> Result<Command> {
     } else if show_stats {
         let fmt = value_t!(matches.value_of("stats-format"), StatsFormat)
             .unwrap_or_else(|e| e.exit());
-        Ok(Command::ShowStats(fmt))
+        Ok(Command::ShowStats(fmt, matches.is_present("lifetime")))
     } else if start_server {
         Ok(Command::StartServer)
     } else if stop_server {
         Ok(Command::StopServer)
     } else if zero_stats {
         Ok(Command::ZeroStats)
+    } else if status {
+        let fmt = value_t!(matches.value_of("stats-format"), StatsFormat)
+            .unwrap_or_else(|e| e.exit());
+        Ok(Command::Status(fmt))
     } else if let Some(mut args) = cmd {
         if let Some(exe) = args.next() {
             let cmdline = args.map(|s| s.to_owned()).collect::<Vec<_>>();
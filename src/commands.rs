@@ -25,9 +25,9 @@ use mock_command::{
     ProcessCommandCreator,
     RunCommand,
 };
-use protocol::{Request, Response, CompileResponse, CompileFinished, Compile};
+use protocol::{Request, Response, CompileResponse, CompileFinished, CompileOutput, Compile, Version};
 use serde_json;
-use server::{self, ServerInfo};
+use server::{self, ServerAddr, ServerInfo, ServerStatus};
 use std::env;
 use std::ffi::{OsStr,OsString};
 use std::fs::{File, OpenOptions};
@@ -40,7 +40,11 @@ use std::os::unix::process::ExitStatusExt;
 use std::path::{
     Path,
 };
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::process;
+use std::thread;
+use std::time::Duration;
 use tokio_core::reactor::Core;
 use util::run_input_output;
 use which::which_in;
@@ -72,14 +76,70 @@ fn get_port() -> u16 {
         .unwrap_or(DEFAULT_PORT)
 }
 
+/// Get the address on which the server should listen.
+///
+/// Setting `SCCACHE_SERVER_UDS` to an absolute path makes the server listen
+/// on a Unix socket there instead of on `SCCACHE_SERVER_PORT`. Setting it to
+/// `1` uses a per-user default path, and setting it to `0` or nothing is the
+/// same as not setting it.
+#[cfg(unix)]
+fn get_server_addr() -> Result<ServerAddr> {
+    server_addr(env::var_os("SCCACHE_SERVER_UDS"))
+}
+
+/// Get the server's address given the value of `SCCACHE_SERVER_UDS`.
+#[cfg(unix)]
+fn server_addr(uds: Option<OsString>) -> Result<ServerAddr> {
+    let path = match uds {
+        Some(ref val) if val == "1" => return Ok(ServerAddr::Unix(default_socket_path()?)),
+        Some(ref val) if !val.is_empty() && val != "0" => PathBuf::from(val),
+        _ => return Ok(ServerAddr::Tcp(get_port())),
+    };
+    // Clients and the server run in different directories, so a relative
+    // path wouldn't name the same socket for all of them.
+    if !path.is_absolute() {
+        bail!("SCCACHE_SERVER_UDS must be an absolute path, not {}", path.display());
+    }
+    Ok(ServerAddr::Unix(path))
+}
+
+#[cfg(not(unix))]
+fn get_server_addr() -> Result<ServerAddr> {
+    Ok(ServerAddr::Tcp(get_port()))
+}
+
+/// Get the default path of the server's Unix socket for the current user.
+///
+/// The socket lives in `$XDG_RUNTIME_DIR/sccache` if that's set, or in a
+/// per-user directory under the system temporary directory otherwise. The
+/// directory is created if necessary, and must only be accessible to the
+/// current user.
+#[cfg(unix)]
+fn default_socket_path() -> Result<PathBuf> {
+    use std::fs::{self, DirBuilder};
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    let uid = unsafe { ::libc::getuid() };
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(ref dir) if !dir.is_empty() => Path::new(dir).join("sccache"),
+        _ => env::temp_dir().join(format!("sccache-{}", uid)),
+    };
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir).chain_err(|| {
+        format!("failed to create socket directory {}", dir.display())
+    })?;
+    let meta = fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        bail!("Socket directory {} must only be accessible to the current user",
+              dir.display());
+    }
+    Ok(dir.join("server.sock"))
+}
+
 /// Re-execute the current executable as a background server, and wait
 /// for it to start up.
 #[cfg(not(windows))]
 fn run_server_process() -> Result<ServerStartup> {
-    extern crate tokio_uds;
-
     use futures::{Future, Stream};
-    use std::time::Duration;
     use tempdir::TempDir;
     use tokio_io::io::read_exact;
     use tokio_core::reactor::Timeout;
@@ -89,7 +149,7 @@ fn run_server_process() -> Result<ServerStartup> {
     let socket_path = tempdir.path().join("sock");
     let mut core = Core::new()?;
     let handle = core.handle();
-    let listener = tokio_uds::UnixListener::bind(&socket_path, &handle)?;
+    let listener = ::tokio_uds::UnixListener::bind(&socket_path, &handle)?;
     let exe_path = env::current_exe()?;
     let _child = process::Command::new(exe_path)
             .env("SCCACHE_START_SERVER", "1")
@@ -244,7 +304,6 @@ fn run_server_process() -> Result<ServerStartup> {
     use std::mem;
     use std::os::windows::ffi::OsStrExt;
     use std::ptr;
-    use std::time::Duration;
     use tokio_io::io::read_exact;
     use tokio_core::reactor::{Core, Timeout, PollEvented};
     use uuid::Uuid;
@@ -339,24 +398,122 @@ fn run_server_process() -> Result<ServerStartup> {
     }
 }
 
-/// Attempt to connect to an sccache server listening on `port`, or start one if no server is running.
-fn connect_or_start_server(port: u16) -> Result<ServerConnection> {
-    trace!("connect_or_start_server({})", port);
-    match connect_to_server(port) {
-        Ok(server) => Ok(server),
+/// Attempt to connect to an sccache server listening on `addr`, or start one if no server is running.
+///
+/// If the server is a different version than this client, restart it.
+fn connect_or_start_server(addr: &ServerAddr) -> Result<ServerConnection> {
+    trace!("connect_or_start_server({})", addr);
+    match connect_to_server(addr) {
+        Ok(mut server) => {
+            match check_server_version(&mut server) {
+                Ok(()) => Ok(server),
+                Err(e) => {
+                    writeln!(io::stderr(), "sccache: restarting server: {}", e).unwrap();
+                    restart_server(addr)
+                }
+            }
+        }
         Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused ||
-                      e.kind() == io::ErrorKind::TimedOut => {
-            // If the connection was refused we probably need to start
-            // the server.
+                      e.kind() == io::ErrorKind::TimedOut ||
+                      e.kind() == io::ErrorKind::NotFound => {
+            // If the connection was refused, or there's no socket to
+            // connect to, we probably need to start the server.
             //TODO: check startup value!
             let _startup = run_server_process()?;
-            let server = connect_with_retry(port)?;
+            let server = connect_with_retry(addr)?;
             Ok(server)
         }
         Err(e) => Err(e.into())
     }
 }
 
+/// Check that the server on `conn` is the same version as this client.
+///
+/// Servers that don't understand the request at all are too old, so any
+/// failure to get the version counts as a mismatch.
+fn check_server_version(conn: &mut ServerConnection) -> Result<()> {
+    let client = Version::current();
+    match conn.request(Request::GetVersion) {
+        Ok(Response::Version(ref server)) if *server == client => Ok(()),
+        Ok(Response::Version(server)) => {
+            bail!("server version {} (protocol {}) doesn't match client version {} (protocol {})",
+                  server.version, server.protocol, client.version, client.protocol)
+        }
+        Ok(_) => bail!("server sent an unexpected response to a version request"),
+        Err(e) => Err(e).chain_err(|| "server didn't answer a version request"),
+    }
+}
+
+/// Ask the server listening on `addr` to shut down, unless it's the same
+/// version as this client, in which case the connection to it is returned.
+///
+/// The version is checked on the connection that `Shutdown` is sent on, so
+/// that a server of this version started by another client in the meantime
+/// isn't stopped. Servers too old to know about version requests drop the
+/// connection instead of answering, so that's checked once more before
+/// sending `Shutdown`, which every version understands, on a new one.
+fn stop_old_server(addr: &ServerAddr) -> Option<ServerConnection> {
+    for _ in 0..2 {
+        let mut conn = match connect_to_server(addr) {
+            Ok(conn) => conn,
+            Err(_) => return None,
+        };
+        match conn.request(Request::GetVersion) {
+            Ok(Response::Version(ref server)) if *server == Version::current() => {
+                return Some(conn);
+            }
+            Ok(_) => {
+                drop(conn.request(Request::Shutdown));
+                return None;
+            }
+            Err(e) => debug!("server didn't answer a version request: {}", e),
+        }
+    }
+    if let Ok(mut conn) = connect_to_server(addr) {
+        drop(conn.request(Request::Shutdown));
+    }
+    None
+}
+
+/// Stop the server listening on `addr`, wait for it to go away, and start
+/// a new one.
+///
+/// Other clients of the new version may be doing the same at once, so a
+/// server of this version showing up at any point counts as success.
+fn restart_server(addr: &ServerAddr) -> Result<ServerConnection> {
+    if let Some(conn) = stop_old_server(addr) {
+        return Ok(conn);
+    }
+    // The server finishes any compiles in progress before it stops
+    // listening, which can take a little while.
+    let mut stopped = false;
+    for _ in 0..30 {
+        match connect_to_server(addr) {
+            Err(_) => {
+                stopped = true;
+                break;
+            }
+            Ok(mut conn) => {
+                if check_server_version(&mut conn).is_ok() {
+                    return Ok(conn);
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    if !stopped {
+        bail!("timed out waiting for the old server to shut down");
+    }
+    // Starting a server fails if another client started one first, which is
+    // fine as long as it's this version.
+    if let Err(e) = run_server_process() {
+        debug!("failed to start server: {}", e);
+    }
+    let mut conn = connect_with_retry(addr)?;
+    check_server_version(&mut conn).chain_err(|| "a server of another version started")?;
+    Ok(conn)
+}
+
 /// Send a `ZeroStats` request to the server, and return the `ServerInfo` request if successful.
 pub fn request_zero_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_stats");
@@ -383,6 +540,19 @@ pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send a `Status` request to the server, and return the `ServerStatus` if successful.
+pub fn request_status(mut conn: ServerConnection) -> Result<ServerStatus> {
+    debug!("request_status");
+    let response = conn.request(Request::Status).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Status(status) = response {
+        Ok(status)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
 /// Send a `Shutdown` request to the server, and return the `ServerInfo` contained within the response if successful.
 pub fn request_shutdown(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_shutdown");
@@ -409,6 +579,10 @@ fn request_compile<W, X, Y>(conn: &mut ServerConnection, exe: W, args: &Vec<X>,
         cwd: cwd.as_ref().to_owned().into(),
         args: args.iter().map(|a| a.as_ref().to_owned()).collect(),
         env_vars: env_vars,
+        // This command only holds the one job slot it was run in. Taking
+        // more tokens from the build's jobserver could block, so commands
+        // with several inputs compile them one at a time.
+        extra_tokens: 0,
     });
     trace!("request_compile: {:?}", req);
     //TODO: better error mapping?
@@ -441,9 +615,6 @@ fn handle_compile_finished(response: CompileFinished,
                            stdout: &mut Write,
                            stderr: &mut Write) -> Result<i32> {
     trace!("handle_compile_finished");
-    // It might be nice if the server sent stdout/stderr as the process
-    // ran, but then it would have to also save them in the cache as
-    // interleaved streams to really make it work.
     stdout.write_all(&response.stdout)?;
     stderr.write_all(&response.stderr)?;
 
@@ -459,14 +630,24 @@ fn handle_compile_finished(response: CompileFinished,
     }
 }
 
+/// Whether to compile locally when the server fails to finish a compile
+/// for reasons other than the compiler failing, instead of failing.
+fn fallback_on_server_error() -> bool {
+    env::var("SCCACHE_ON_SERVER_ERROR").map(|v| v != "fail").unwrap_or(true)
+}
+
 /// Handle `response`, the response from sending a `Compile` request to the server. Return the compiler exit status.
 ///
-/// If the server returned `CompileStarted`, wait for a `CompileFinished` and
-/// print the results.
+/// If the server returned `CompileStarted`, print any `CompileOutput`s as
+/// they arrive, then wait for a `CompileFinished` and print the results.
+/// If the server couldn't finish the compile because of an error of its own,
+/// run the compilation command locally instead, unless configured not to,
+/// or unless the compiler already ran and printed something, which would be
+/// printed twice.
 ///
 /// If the server returned `UnhandledCompile`, run the compilation command
 /// locally using `creator` and return the result.
-fn handle_compile_response<T>(mut creator: T,
+fn handle_compile_response<T>(creator: T,
                               core: &mut Core,
                               conn: &mut ServerConnection,
                               response: CompileResponse,
@@ -480,23 +661,45 @@ fn handle_compile_response<T>(mut creator: T,
     match response {
         CompileResponse::CompileStarted => {
             debug!("Server sent CompileStarted");
-            // Wait for CompileFinished.
-            match conn.read_one_response() {
-                Ok(Response::CompileFinished(result)) => {
-                    return handle_compile_finished(result, stdout, stderr)
-                }
-                Ok(_) => bail!("unexpected response from server"),
-                Err(Error(ErrorKind::Io(ref e), _))
-                    if e.kind() == io::ErrorKind::UnexpectedEof =>
-                {
-					writeln!(io::stderr(),
-							 "warning: sccache server looks like it shut down \
-                              unexpectedly, compiling locally instead").unwrap();
+            let mut streamed = false;
+            // Wait for CompileFinished, passing on output until then.
+            loop {
+                match conn.read_one_response() {
+                    Ok(Response::CompileOutput(CompileOutput::Stdout(data))) => {
+                        streamed = true;
+                        stdout.write_all(&data)?;
+                        stdout.flush()?;
+                    }
+                    Ok(Response::CompileOutput(CompileOutput::Stderr(data))) => {
+                        streamed = true;
+                        stderr.write_all(&data)?;
+                        stderr.flush()?;
+                    }
+                    Ok(Response::CompileFinished(mut result)) => {
+                        if fallback_on_server_error() && !streamed {
+                            if let Some(e) = result.server_error.take() {
+                                writeln!(io::stderr(),
+                                         "warning: sccache server failed to compile: {}, \
+                                          compiling locally instead", e).unwrap();
+                                break;
+                            }
+                        }
+                        return handle_compile_finished(result, stdout, stderr)
+                    }
+                    Ok(_) => bail!("unexpected response from server"),
+                    Err(Error(ErrorKind::Io(ref e), _))
+                        if e.kind() == io::ErrorKind::UnexpectedEof && !streamed =>
+                    {
+                        writeln!(io::stderr(),
+                                 "warning: sccache server looks like it shut down \
+                                  unexpectedly, compiling locally instead").unwrap();
+                        break;
+                    }
+                    Err(e) => return Err(e).chain_err(|| {
+                        //TODO: something better here?
+                        "error reading compile response from server"
+                    }),
                 }
-				Err(e) => return Err(e).chain_err(|| {
-                    //TODO: something better here?
-                    "error reading compile response from server"
-                }),
             }
         }
         CompileResponse::UnhandledCompile => {
@@ -504,6 +707,20 @@ fn handle_compile_response<T>(mut creator: T,
         }
     };
 
+    compile_locally(creator, core, exe, cmdline, cwd, stdout, stderr)
+}
+
+/// Run the compilation command locally using `creator`, and return the
+/// compiler exit status.
+fn compile_locally<T>(mut creator: T,
+                      core: &mut Core,
+                      exe: &Path,
+                      cmdline: Vec<OsString>,
+                      cwd: &Path,
+                      stdout: &mut Write,
+                      stderr: &mut Write) -> Result<i32>
+    where T: CommandCreatorSync,
+{
     //TODO: possibly capture output here for testing.
     let mut cmd = creator.new_command_sync(exe);
     cmd.args(&cmdline)
@@ -557,23 +774,27 @@ pub fn do_compile<T>(creator: T,
 /// Run `cmd` and return the process exit status.
 pub fn run_command(cmd: Command) -> Result<i32> {
     match cmd {
-        Command::ShowStats(fmt) => {
-            trace!("Command::ShowStats({:?})", fmt);
-            let srv = connect_or_start_server(get_port())?;
+        Command::ShowStats(fmt, lifetime) => {
+            trace!("Command::ShowStats({:?}, {})", fmt, lifetime);
+            let srv = connect_or_start_server(&get_server_addr()?)?;
             let stats = request_stats(srv).chain_err(|| {
                 "failed to get stats from server"
             })?;
             match fmt {
+                StatsFormat::text if lifetime => stats.print_lifetime(),
                 StatsFormat::text => stats.print(),
                 StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &stats)?,
             }
         }
         Command::InternalStartServer => {
             trace!("Command::InternalStartServer");
+            // Daemonizing changes the working directory, so get the address
+            // first.
+            let addr = get_server_addr()?;
             // Can't report failure here, we're already daemonized.
             daemonize()?;
             redirect_error_log()?;
-            server::start_server(get_port())?;
+            server::start_server(&addr)?;
         }
         Command::StartServer => {
             trace!("Command::StartServer");
@@ -594,18 +815,45 @@ pub fn run_command(cmd: Command) -> Result<i32> {
         Command::StopServer => {
             trace!("Command::StopServer");
             println!("Stopping sccache server...");
-            let server = connect_to_server(get_port()).chain_err(|| {
+            let addr = get_server_addr()?;
+            let mut server = connect_to_server(&addr).chain_err(|| {
                 "couldn't connect to server"
             })?;
-            let stats = request_shutdown(server)?;
-            stats.print();
+            match check_server_version(&mut server) {
+                Ok(()) => {
+                    let stats = request_shutdown(server)?;
+                    stats.print();
+                }
+                Err(e) => {
+                    // The statistics of other versions can't be read, but
+                    // every version understands `Shutdown`.
+                    debug!("{}", e);
+                    let mut server = connect_to_server(&addr).chain_err(|| {
+                        "couldn't connect to server"
+                    })?;
+                    drop(server.request(Request::Shutdown));
+                    println!("Stopped a server of another version, whose statistics can't be shown");
+                }
+            }
         }
         Command::Compile { exe, cmdline, cwd, env_vars } => {
             trace!("Command::Compile {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
             let jobserver = unsafe { Client::new() };
-            let conn = connect_or_start_server(get_port())?;
             let mut core = Core::new()?;
-            let res = do_compile(ProcessCommandCreator::new(&core.handle(), &jobserver),
+            let creator = ProcessCommandCreator::new(&core.handle(), &jobserver);
+            let conn = match connect_or_start_server(&get_server_addr()?) {
+                Ok(conn) => conn,
+                Err(ref e) if fallback_on_server_error() => {
+                    writeln!(io::stderr(),
+                             "warning: couldn't connect to sccache server: {}, \
+                              compiling locally instead", e).unwrap();
+                    let exe_path = which_in(&exe, env::var_os("PATH"), &cwd)?;
+                    return compile_locally(creator, &mut core, &exe_path, cmdline, &cwd,
+                                           &mut io::stdout(), &mut io::stderr());
+                }
+                Err(e) => return Err(e),
+            };
+            let res = do_compile(creator,
                                  &mut core,
                                  conn,
                                  exe.as_ref(),
@@ -619,9 +867,26 @@ pub fn run_command(cmd: Command) -> Result<i32> {
                 "failed to execute compile"
             })
         }
+        Command::Status(fmt) => {
+            trace!("Command::Status({:?})", fmt);
+            // Starting a server would hide that none was running.
+            let srv = connect_to_server(&get_server_addr()?).chain_err(|| {
+                "couldn't connect to server"
+            })?;
+            let status = request_status(srv).chain_err(|| {
+                "failed to get status from server"
+            })?;
+            match fmt {
+                StatsFormat::text => status.print(),
+                StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &status)?,
+            }
+            if !status.problems().is_empty() {
+                return Ok(1);
+            }
+        }
         Command::ZeroStats => {
             trace!("Command::ZeroStats");
-            let conn = connect_or_start_server(get_port())?;
+            let conn = connect_or_start_server(&get_server_addr()?)?;
             let stats = request_zero_stats(conn).chain_err(|| {
                 "couldn't zero stats on server"
             })?;
@@ -631,3 +896,20 @@ pub fn run_command(cmd: Command) -> Result<i32> {
 
     Ok(0)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_server_addr() {
+        let tcp = ServerAddr::Tcp(get_port());
+        assert_eq!(tcp, server_addr(None).unwrap());
+        assert_eq!(tcp, server_addr(Some("".into())).unwrap());
+        assert_eq!(tcp, server_addr(Some("0".into())).unwrap());
+        assert_eq!(ServerAddr::Unix("/run/sccache.sock".into()),
+                   server_addr(Some("/run/sccache.sock".into())).unwrap());
+        assert!(server_addr(Some("sccache.sock".into())).is_err());
+    }
+}
@@ -1,6 +1,9 @@
 use std::cmp::Ordering;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use util::OsStrExt;
 
 pub type Delimiter = Option<u8>;
 
@@ -483,6 +486,212 @@ where
     }
 }
 
+/// How the contents of a response file are split into arguments.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ResponseFileQuoting {
+    /// The rules used by GCC and clang, see `split_gnu_args`.
+    Gnu,
+    /// The rules used by MSVC, see `split_msvc_args`.
+    Msvc,
+}
+
+/// The maximum number of response files expanded for a single commandline.
+/// This guards against response files that (indirectly) include themselves.
+const MAX_RESPONSE_FILES: usize = 100;
+
+/// An `Iterator` over raw arguments that expands `@file` arguments, which
+/// stand for reading `file` and interpreting its contents as more arguments.
+///
+/// Response files may themselves contain `@file` arguments, which are
+/// expanded recursively. Files are looked up relative to `cwd`.
+pub struct ExpandResponseFiles<'a> {
+    cwd: &'a Path,
+    stack: Vec<OsString>,
+    quoting: ResponseFileQuoting,
+    expanded: usize,
+}
+
+impl<'a> ExpandResponseFiles<'a> {
+    pub fn new(arguments: &[OsString], cwd: &'a Path, quoting: ResponseFileQuoting) -> Self {
+        ExpandResponseFiles {
+            cwd: cwd,
+            stack: arguments.iter().rev().map(|a| a.to_owned()).collect(),
+            quoting: quoting,
+            expanded: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for ExpandResponseFiles<'a> {
+    type Item = OsString;
+
+    fn next(&mut self) -> Option<OsString> {
+        loop {
+            let arg = match self.stack.pop() {
+                Some(arg) => arg,
+                None => return None,
+            };
+            let file = match arg.split_prefix("@") {
+                Some(arg) => self.cwd.join(&arg),
+                None => return Some(arg),
+            };
+            if self.expanded >= MAX_RESPONSE_FILES {
+                debug!("too many response files, not expanding `{}`", file.display());
+                return Some(arg)
+            }
+
+            // According to gcc [1], if file does not exist, or cannot be
+            // read, then the option will be treated literally, and not
+            // removed. MSVC errors out instead. Either way, just return the
+            // argument; `@` arguments are otherwise considered too hard to
+            // cache, so we'll just call the compiler.
+            //
+            // [1]: https://gcc.gnu.org/onlinedocs/gcc/Overall-Options.html#Overall-Options
+            let contents = match read_response_file(&file) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    debug!("failed to read @-file `{}`: {}", file.display(), e);
+                    return Some(arg)
+                }
+            };
+            self.expanded += 1;
+            let new_args = match self.quoting {
+                ResponseFileQuoting::Gnu => split_gnu_args(&contents),
+                ResponseFileQuoting::Msvc => split_msvc_args(&contents),
+            };
+            self.stack.extend(new_args.into_iter().rev().map(OsString::from));
+        }
+    }
+}
+
+/// Read the contents of the response file `file`.
+///
+/// MSVC accepts UTF-16 response files, which are recognized by their byte
+/// order mark. Anything else must be UTF-8.
+fn read_response_file(file: &Path) -> io::Result<String> {
+    let mut bytes = vec!();
+    File::open(file)?.read_to_end(&mut bytes)?;
+    if bytes.starts_with(&[0xff, 0xfe]) {
+        let wide = bytes[2..].chunks(2)
+            .map(|c| c[0] as u16 | (*c.get(1).unwrap_or(&0) as u16) << 8)
+            .collect::<Vec<_>>();
+        return String::from_utf16(&wide)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    if bytes.starts_with(&[0xef, 0xbb, 0xbf]) {
+        bytes.drain(..3);
+    }
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Split the contents of a GCC response file into arguments.
+///
+/// Arguments are separated by whitespace. A whitespace character may be
+/// included in an argument by surrounding it with either single or double
+/// quotes. Any character (including a backslash) may be included by prefixing
+/// it with a backslash.
+pub fn split_gnu_args(s: &str) -> Vec<String> {
+    let mut args = vec!();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                in_arg = true;
+                if let Some(c) = chars.next() {
+                    arg.push(c);
+                }
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => arg.push(c),
+            ('\'', None) | ('"', None) => {
+                in_arg = true;
+                quote = Some(c);
+            }
+            (c, None) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(arg.split_off(0));
+                    in_arg = false;
+                }
+            }
+            (c, None) => {
+                in_arg = true;
+                arg.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
+
+/// Split the contents of an MSVC response file into arguments.
+///
+/// This follows the rules the Microsoft C runtime uses to split a commandline:
+/// arguments are separated by whitespace, unless it is surrounded by double
+/// quotes. Backslashes are literal, unless they precede a double quote, in
+/// which case each pair of backslashes stands for one backslash, and an
+/// odd backslash makes the double quote literal. Within double quotes, two
+/// consecutive double quotes stand for a literal double quote.
+pub fn split_msvc_args(s: &str) -> Vec<String> {
+    let mut args = vec!();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                in_arg = true;
+                let mut backslashes = 1;
+                while chars.peek() == Some(&'\\') {
+                    chars.next();
+                    backslashes += 1;
+                }
+                if chars.peek() == Some(&'"') {
+                    for _ in 0..backslashes / 2 {
+                        arg.push('\\');
+                    }
+                    if backslashes % 2 == 1 {
+                        chars.next();
+                        arg.push('"');
+                    }
+                } else {
+                    for _ in 0..backslashes {
+                        arg.push('\\');
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                if quoted && chars.peek() == Some(&'"') {
+                    chars.next();
+                    arg.push('"');
+                } else {
+                    quoted = !quoted;
+                }
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(arg.split_off(0));
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                arg.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
+
 /// Helper macro used to define ArgInfo::Flag's.
 ///     flag!("-foo")
 ///     flag!("-foo", extra_data)
@@ -818,6 +1027,73 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_split_gnu_args() {
+        assert_eq!(split_gnu_args(""), Vec::<String>::new());
+        assert_eq!(split_gnu_args("  -c\tfoo.c\n-o foo.o \n"),
+                   stringvec!["-c", "foo.c", "-o", "foo.o"]);
+        assert_eq!(split_gnu_args("-DFOO=\"a b\" 'c d' e\\ f"),
+                   stringvec!["-DFOO=a b", "c d", "e f"]);
+        assert_eq!(split_gnu_args("'a\"b' \"c'd\" \\\\ '' \"\""),
+                   stringvec!["a\"b", "c'd", "\\", "", ""]);
+        assert_eq!(split_gnu_args("\"a\\\"b\""), stringvec!["a\"b"]);
+    }
+
+    #[test]
+    fn test_split_msvc_args() {
+        assert_eq!(split_msvc_args(""), Vec::<String>::new());
+        assert_eq!(split_msvc_args("/c foo.c\r\n/Fofoo.obj"),
+                   stringvec!["/c", "foo.c", "/Fofoo.obj"]);
+        assert_eq!(split_msvc_args("\"C:\\Program Files\\foo\" /I\"a b\\\\\""),
+                   stringvec!["C:\\Program Files\\foo", "/Ia b\\"]);
+        assert_eq!(split_msvc_args("a\\\\b a\\\"b a\\\\\\\"b \"a\"\"b\" \"\""),
+                   stringvec!["a\\\\b", "a\"b", "a\\\"b", "a\"b", ""]);
+    }
+
+    #[test]
+    fn test_expand_response_files() {
+        use std::io::Write;
+        use tempdir::TempDir;
+
+        let td = TempDir::new("sccache").unwrap();
+        File::create(td.path().join("outer")).unwrap()
+            .write_all(b"-c \"foo bar.c\" @inner -o foo.o").unwrap();
+        File::create(td.path().join("inner")).unwrap()
+            .write_all(b"-DA=1 -DB='2 3'").unwrap();
+        File::create(td.path().join("loop")).unwrap()
+            .write_all(b"-DC @loop").unwrap();
+        let expand = |args: Vec<OsString>| {
+            ExpandResponseFiles::new(&args, td.path(), ResponseFileQuoting::Gnu)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(expand(ovec!["-g", "@outer", "-O2"]),
+                   ovec!["-g", "-c", "foo bar.c", "-DA=1", "-DB=2 3", "-o", "foo.o", "-O2"]);
+        // Files that can't be read are passed through literally.
+        assert_eq!(expand(ovec!["@missing"]), ovec!["@missing"]);
+        // Response files that include themselves eventually stop expanding.
+        let looped = expand(ovec!["@loop"]);
+        assert_eq!(looped.len(), MAX_RESPONSE_FILES + 1);
+        assert_eq!(looped.last(), Some(&OsString::from("@loop")));
+    }
+
+    #[test]
+    fn test_expand_response_files_utf16() {
+        use std::io::Write;
+        use tempdir::TempDir;
+
+        let td = TempDir::new("sccache").unwrap();
+        let mut contents = vec![0xff, 0xfe];
+        for c in "/c foo.c".encode_utf16() {
+            contents.push(c as u8);
+            contents.push((c >> 8) as u8);
+        }
+        File::create(td.path().join("rsp")).unwrap().write_all(&contents).unwrap();
+        let args = ovec!["@rsp"];
+        let expanded = ExpandResponseFiles::new(&args, td.path(), ResponseFileQuoting::Msvc)
+            .collect::<Vec<_>>();
+        assert_eq!(expanded, ovec!["/c", "foo.c"]);
+    }
+
     #[test]
     fn test_argument_into_iter() {
         assert_eq!(Vec::from_iter(arg!(Raw("value"))), ovec!["value"]);
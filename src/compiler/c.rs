@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use compiler::{Cacheable, Compiler, CompilerArguments, CompilerHasher, CompilerKind, Compilation, HashResult};
+use dist::CompileJob;
 use futures::Future;
 use futures_cpupool::CpuPool;
 use mock_command::CommandCreatorSync;
@@ -20,10 +21,13 @@ use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::fs::File;
+use std::io::Read;
 use std::hash::Hash;
 use std::path::{Path, PathBuf};
 use std::process;
-use util::{HashToDigest, Digest};
+use sandbox::{SandboxCompiler, SandboxInputs};
+use util::{hash_all, HashToDigest, Digest, OsStrExt, OutputSink};
 
 use errors::*;
 
@@ -54,6 +58,13 @@ pub enum Language {
     Cxx,
     ObjectiveC,
     ObjectiveCxx,
+    CHeader,
+    CxxHeader,
+    ObjectiveCHeader,
+    ObjectiveCxxHeader,
+    Cuda,
+    AssemblerWithCpp,
+    Assembler,
 }
 
 /// The results of parsing a compiler commandline.
@@ -72,6 +83,11 @@ pub struct ParsedArguments {
     pub preprocessor_args: Vec<OsString>,
     /// Commandline arguments for the preprocessor or the compiler.
     pub common_args: Vec<OsString>,
+    /// Files other than the input whose contents affect the compiler output,
+    /// such as profile data.
+    pub extra_hash_files: Vec<PathBuf>,
+    /// Whether the compiler output depends on the current directory.
+    pub hash_cwd: bool,
     /// Whether or not the `-showIncludes` argument is passed on MSVC
     pub msvc_show_includes: bool,
 }
@@ -92,6 +108,12 @@ impl Language {
             Some("cc") | Some("cpp") | Some("cxx") => Some(Language::Cxx),
             Some("m") => Some(Language::ObjectiveC),
             Some("mm") => Some(Language::ObjectiveCxx),
+            // `.h` could be any of the header languages, so it needs an
+            // explicit `-x`.
+            Some("hh") | Some("hpp") | Some("hxx") => Some(Language::CxxHeader),
+            Some("cu") => Some(Language::Cuda),
+            Some("S") | Some("sx") => Some(Language::AssemblerWithCpp),
+            Some("s") => Some(Language::Assembler),
             e => {
                 trace!("Unknown source extension: {}", e.unwrap_or("(None)"));
                 None
@@ -105,6 +127,24 @@ impl Language {
             Language::Cxx => "c++",
             Language::ObjectiveC => "objc",
             Language::ObjectiveCxx => "objc++",
+            Language::CHeader => "c-header",
+            Language::CxxHeader => "c++-header",
+            Language::ObjectiveCHeader => "objc-header",
+            Language::ObjectiveCxxHeader => "objc++-header",
+            Language::Cuda => "cuda",
+            Language::AssemblerWithCpp => "assembler-with-cpp",
+            Language::Assembler => "assembler",
+        }
+    }
+
+    /// Whether compiling this language produces a precompiled header.
+    pub fn is_header(&self) -> bool {
+        match *self {
+            Language::CHeader |
+            Language::CxxHeader |
+            Language::ObjectiveCHeader |
+            Language::ObjectiveCxxHeader => true,
+            _ => false,
         }
     }
 }
@@ -114,6 +154,27 @@ struct CCompilation<I: CCompilerImpl> {
     parsed_args: ParsedArguments,
     executable: PathBuf,
     compiler: I,
+    /// The preprocessed input, which build workers compile.
+    preprocessor_output: Vec<u8>,
+    /// The headers the preprocessor read, whose contents are in its output.
+    read_files: Vec<PathBuf>,
+    /// Files other than the input and headers that went into the hash key.
+    extra_files: Vec<PathBuf>,
+    /// Whether the hash key covers everything the compile reads, so that
+    /// its result can be cached.
+    cacheable: Cacheable,
+}
+
+/// The result of running the C preprocessor.
+pub struct Preprocessed {
+    /// The output of the preprocessor.
+    pub output: process::Output,
+    /// The files the preprocessor read, whose contents are in its output.
+    pub read_files: Vec<PathBuf>,
+    /// The files the compilation depends on that the preprocessor found,
+    /// but whose contents don't appear in its output, such as precompiled
+    /// headers and the headers of implicitly built modules.
+    pub dependencies: Vec<PathBuf>,
 }
 
 /// Supported C compilers.
@@ -125,6 +186,8 @@ pub enum CCompilerKind {
     Clang,
     /// Microsoft Visual C++
     MSVC,
+    /// NVIDIA CUDA compiler driver
+    Nvcc,
 }
 
 /// An interface to a specific C compiler.
@@ -141,16 +204,32 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
                      executable: &Path,
                      parsed_args: &ParsedArguments,
                      cwd: &Path,
-                     env_vars: &[(OsString, OsString)])
-                     -> SFuture<process::Output> where T: CommandCreatorSync;
+                     env_vars: &[(OsString, OsString)],
+                     pool: &CpuPool)
+                     -> SFuture<Preprocessed> where T: CommandCreatorSync;
+    /// Find other files the compilation depends on, without running the
+    /// preprocessor, such as the programs the compiler runs.
+    fn scan_dependencies<T>(&self,
+                            _creator: &T,
+                            _executable: &Path,
+                            _parsed_args: &ParsedArguments,
+                            _cwd: &Path,
+                            _env_vars: &[(OsString, OsString)])
+                            -> SFuture<Vec<PathBuf>> where T: CommandCreatorSync
+    {
+        f_ok(vec!())
+    }
     /// Run the C compiler with the specified set of arguments, using the
     /// previously-generated `preprocessor_output` as input if possible.
+    /// If `output` is `Some`, also send the compiler's output to it as it
+    /// is produced.
     fn compile<T>(&self,
                   creator: &T,
                   executable: &Path,
                   parsed_args: &ParsedArguments,
                   cwd: &Path,
-                  env_vars: &[(OsString, OsString)])
+                  env_vars: &[(OsString, OsString)],
+                  output: Option<OutputSink>)
                   -> SFuture<(Cacheable, process::Output)>
         where T: CommandCreatorSync;
 }
@@ -175,14 +254,18 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
     fn parse_arguments(&self,
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<Box<CompilerHasher<T> + 'static>> {
+        let hasher = |args: ParsedArguments| -> Box<CompilerHasher<T> + 'static> {
+            Box::new(CCompilerHasher {
+                parsed_args: args,
+                executable: self.executable.clone(),
+                executable_digest: self.executable_digest.clone(),
+                compiler: self.compiler.clone(),
+            })
+        };
         match self.compiler.parse_arguments(arguments, cwd) {
-            CompilerArguments::Ok(args) => {
-                CompilerArguments::Ok(Box::new(CCompilerHasher {
-                    parsed_args: args,
-                    executable: self.executable.clone(),
-                    executable_digest: self.executable_digest.clone(),
-                    compiler: self.compiler.clone(),
-                }))
+            CompilerArguments::Ok(args) => CompilerArguments::Ok(hasher(args)),
+            CompilerArguments::Multiple(args) => {
+                CompilerArguments::Multiple(args.into_iter().map(hasher).collect())
             }
             CompilerArguments::CannotCache(why) => CompilerArguments::CannotCache(why),
             CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
@@ -202,45 +285,87 @@ impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
                          creator: &T,
                          cwd: &Path,
                          env_vars: &[(OsString, OsString)],
-                         _pool: &CpuPool)
+                         pool: &CpuPool)
                          -> SFuture<HashResult<T>>
     {
         let me = *self;
         let CCompilerHasher { parsed_args, executable, executable_digest, compiler } = me;
-        let result = compiler.preprocess(creator, &executable, &parsed_args, cwd, env_vars);
-        let out_pretty = parsed_args.output_pretty().into_owned();
-        let env_vars = env_vars.to_vec();
-        let result = result.map_err(move |e| {
-            debug!("[{}]: preprocessor failed: {:?}", out_pretty, e);
-            e
-        });
-        let out_pretty = parsed_args.output_pretty().into_owned();
-        Box::new(result.or_else(move |err| {
-            match err {
-                Error(ErrorKind::ProcessError(output), _) => {
-                    debug!("[{}]: preprocessor returned error status {:?}",
-                           out_pretty,
-                           output.status.code());
-                    // Drop the stdout since it's the preprocessor output, just hand back stderr and
-                    // the exit status.
-                    bail!(ErrorKind::ProcessError(process::Output {
-                        stdout: vec!(),
-                        .. output
-                    }))
+        let extra_files = parsed_args.extra_hash_files.clone();
+        let pool = pool.clone();
+        let dependencies = compiler.scan_dependencies(creator, &executable, &parsed_args, cwd, env_vars);
+        let preprocessed: SFuture<(Vec<u8>, Vec<PathBuf>, Vec<PathBuf>, Cacheable)> = if parsed_args.language == Language::Assembler {
+            // Plain assembler isn't run through the preprocessor, so hash the
+            // source file as-is. The files a source includes wouldn't be part
+            // of the hash, so those sources aren't cached.
+            let input = cwd.join(&parsed_args.input);
+            let out_pretty = parsed_args.output_pretty().into_owned();
+            Box::new(pool.spawn_fn(move || -> Result<_> {
+                let mut source = vec!();
+                File::open(&input).and_then(|mut f| f.read_to_end(&mut source))
+                    .chain_err(|| format!("Failed to read assembler input: {:?}", input))?;
+                let cacheable = if includes_files(&source) {
+                    debug!("[{}]: Assembler source includes other files, not caching", out_pretty);
+                    Cacheable::No
+                } else {
+                    Cacheable::Yes
+                };
+                Ok((source, vec!(), vec!(), cacheable))
+            }))
+        } else {
+            let result = compiler.preprocess(creator, &executable, &parsed_args, cwd, env_vars, &pool);
+            let out_pretty = parsed_args.output_pretty().into_owned();
+            let result = result.map_err(move |e| {
+                debug!("[{}]: preprocessor failed: {:?}", out_pretty, e);
+                e
+            });
+            let out_pretty = parsed_args.output_pretty().into_owned();
+            Box::new(result.or_else(move |err| {
+                match err {
+                    Error(ErrorKind::ProcessError(output), _) => {
+                        debug!("[{}]: preprocessor returned error status {:?}",
+                               out_pretty,
+                               output.status.code());
+                        // Drop the stdout since it's the preprocessor output, just hand back stderr and
+                        // the exit status.
+                        bail!(ErrorKind::ProcessError(process::Output {
+                            stdout: vec!(),
+                            .. output
+                        }))
+                    }
+                    e @ _ => Err(e),
                 }
-                e @ _ => Err(e),
+            }).map(|p| (p.output.stdout, p.read_files, p.dependencies, Cacheable::Yes)))
+        };
+        let hashed = preprocessed.join(dependencies).and_then({
+            let pool = pool.clone();
+            move |((preprocessor_output, read_files, found, cacheable), scanned)| {
+                let mut seen = HashSet::new();
+                let files = extra_files.into_iter()
+                    .chain(found)
+                    .chain(scanned)
+                    .filter(|f| seen.insert(f.clone()))
+                    .collect::<Vec<_>>();
+                hash_all(files.clone(), &pool)
+                    .map(move |hashes| (preprocessor_output, read_files, files, hashes, cacheable))
             }
-        }).and_then(move |preprocessor_result| {
+        });
+        let cwd = cwd.to_owned();
+        let env_vars = env_vars.to_vec();
+        Box::new(hashed.and_then(move |(preprocessor_output, read_files, extra_files, mut extra_hashes, cacheable)| {
             trace!("[{}]: Preprocessor output is {} bytes",
                    parsed_args.output_pretty(),
-                   preprocessor_result.stdout.len());
+                   preprocessor_output.len());
 
+            if parsed_args.hash_cwd {
+                extra_hashes.push(cwd.to_string_lossy().into_owned());
+            }
             let key = {
                 hash_key(&executable_digest,
                          parsed_args.language,
                          &parsed_args.common_args,
+                         &extra_hashes,
                          &env_vars,
-                         &preprocessor_result.stdout)
+                         &preprocessor_output)
             };
             Ok(HashResult {
                 key: key,
@@ -248,6 +373,10 @@ impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
                     parsed_args: parsed_args,
                     executable: executable,
                     compiler: compiler,
+                    preprocessor_output: preprocessor_output,
+                    read_files: read_files,
+                    extra_files: extra_files,
+                    cacheable: cacheable,
                 }),
             })
         }))
@@ -258,6 +387,11 @@ impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
         self.parsed_args.output_pretty()
     }
 
+    fn language(&self) -> &'static str
+    {
+        self.parsed_args.language.as_str()
+    }
+
     fn box_clone(&self) -> Box<CompilerHasher<T>>
     {
         Box::new((*self).clone())
@@ -268,22 +402,144 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compilation<T> for CCompilation<I>
     fn compile(self: Box<Self>,
                creator: &T,
                cwd: &Path,
-               env_vars: &[(OsString, OsString)])
+               env_vars: &[(OsString, OsString)],
+               output: Option<OutputSink>)
                -> SFuture<(Cacheable, process::Output)>
     {
         let me = *self;
-        let CCompilation { parsed_args, executable, compiler } = me;
-        compiler.compile(creator, &executable, &parsed_args, cwd, env_vars)
+        let CCompilation { parsed_args, executable, compiler, cacheable, .. } = me;
+        let result = compiler.compile(creator, &executable, &parsed_args, cwd, env_vars, output);
+        Box::new(result.map(move |(compiled_cacheable, output)| {
+            match cacheable {
+                Cacheable::Yes => (compiled_cacheable, output),
+                Cacheable::No => (Cacheable::No, output),
+            }
+        }))
     }
 
     fn outputs<'a>(&'a self) -> Box<Iterator<Item=(&'a str, &'a Path)> + 'a>
     {
         Box::new(self.parsed_args.outputs.iter().map(|(k, v)| (*k, &**v)))
     }
+
+    fn dist_job(&self, cwd: &Path, env_vars: &[(OsString, OsString)]) -> Option<CompileJob> {
+        // Build workers compile the preprocessor output with gcc or clang.
+        let (language, input) = match (self.compiler.kind(), self.parsed_args.language) {
+            (CCompilerKind::GCC, Language::C) |
+            (CCompilerKind::Clang, Language::C) => ("cpp-output", "input.i"),
+            (CCompilerKind::GCC, Language::Cxx) |
+            (CCompilerKind::Clang, Language::Cxx) => ("c++-cpp-output", "input.ii"),
+            _ => return None,
+        };
+        // Outputs that depend on the directory of the compile, like debug
+        // info, would point at the worker's directory instead.
+        let debug_info = self.parsed_args.common_args.iter()
+            .any(|a| a.starts_with("-g") && a != "-g0");
+        if self.parsed_args.hash_cwd || debug_info || self.parsed_args.outputs.len() != 1 {
+            return None;
+        }
+        // Workers only get the preprocessor output, so compiles that read
+        // or write other files, like profile data or plugins, run locally.
+        if !self.extra_files.is_empty() ||
+            self.parsed_args.common_args.iter().any(|a| may_use_local_file(a, cwd)) {
+            return None;
+        }
+        let obj = match self.parsed_args.outputs.get("obj") {
+            Some(obj) => cwd.join(obj),
+            None => return None,
+        };
+        let mut arguments = ["-x", language, "-c", input, "-o", "output.o"].iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        arguments.extend(self.parsed_args.common_args.iter().cloned());
+        Some(CompileJob {
+            executable: self.executable.clone(),
+            arguments: arguments,
+            env_vars: env_vars.iter()
+                .filter(|&&(ref k, _)| is_dist_env_var(k))
+                .cloned()
+                .collect(),
+            inputs: vec![(PathBuf::from(input), self.preprocessor_output.clone())],
+            outputs: vec![(PathBuf::from("output.o"), obj)],
+        })
+    }
+
+    fn sandbox_inputs(&self, cwd: &Path) -> Option<SandboxInputs> {
+        match self.compiler.kind() {
+            CCompilerKind::GCC | CCompilerKind::Clang => {}
+            _ => return None,
+        }
+        // Compiles that aren't cached may read files the hash doesn't know
+        // about.
+        if self.cacheable == Cacheable::No {
+            return None;
+        }
+        // The compiler reads the input again, along with the headers that
+        // the preprocessor read.
+        let mut paths = vec![cwd.join(&self.parsed_args.input)];
+        paths.extend(self.read_files.iter().map(|f| cwd.join(f)));
+        paths.extend(self.extra_files.iter().map(|f| cwd.join(f)));
+        Some(SandboxInputs {
+            compiler: SandboxCompiler::C(self.executable.clone()),
+            paths: paths,
+        })
+    }
+}
+
+/// Whether the assembler source `source` includes other files, with
+/// `.include` or `.incbin`.
+fn includes_files(source: &[u8]) -> bool {
+    let source = String::from_utf8_lossy(source).to_lowercase();
+    source.contains(".include") || source.contains(".incbin")
+}
+
+/// Whether the environment variable `var` should be passed to build
+/// workers. Others, like `PATH`, only make sense on this machine.
+fn is_dist_env_var(var: &OsStr) -> bool {
+    CACHED_ENV_VARS.contains(var) || var == "LANG" || var.starts_with("LC_")
+}
+
+/// Compiler arguments that make the compiler read or write a file whose
+/// name may not look like a path, as prefixes.
+const FILE_ARGUMENTS: &[&str] = &[
+    "--serialize-diagnostics",
+    "--sysroot",
+    "-B",
+    "-Xclang",
+    "-fauto-profile",
+    "-fplugin",
+    "-fprofile",
+    "-fsanitize-blacklist",
+    "-fsanitize-ignorelist",
+    "-ftest-coverage",
+    "-specs",
+];
+
+/// Whether the compiler argument `arg` could make the compiler use a file
+/// on this machine when run in `cwd`.
+fn may_use_local_file(arg: &OsStr, cwd: &Path) -> bool {
+    let arg = match arg.to_str() {
+        Some(arg) => arg,
+        None => return true,
+    };
+    if FILE_ARGUMENTS.iter().any(|a| arg.starts_with(a)) || arg.contains('/') {
+        return true;
+    }
+    // Flags that take a file name as `-flag=name`, and values of separate
+    // arguments.
+    let value = if arg.starts_with('-') {
+        match arg.find('=') {
+            Some(i) => &arg[i + 1..],
+            None => return false,
+        }
+    } else {
+        arg
+    };
+    !value.is_empty() && cwd.join(value).exists()
 }
 
 /// The cache is versioned by the inputs to `hash_key`.
-pub const CACHE_VERSION: &[u8] = b"6";
+pub const CACHE_VERSION: &[u8] = b"7";
 
 lazy_static! {
     /// Environment variables that are factored into the cache key.
@@ -294,9 +550,12 @@ lazy_static! {
 }
 
 /// Compute the hash key of `compiler` compiling `preprocessor_output` with `args`.
+///
+/// `extra_hashes` are digests of any other inputs to the compilation.
 pub fn hash_key(compiler_digest: &str,
                 language: Language,
                 arguments: &[OsString],
+                extra_hashes: &[String],
                 env_vars: &[(OsString, OsString)],
                 preprocessor_output: &[u8]) -> String
 {
@@ -308,6 +567,9 @@ pub fn hash_key(compiler_digest: &str,
     for arg in arguments {
         arg.hash(&mut HashToDigest { digest: &mut m });
     }
+    for hash in extra_hashes {
+        m.update(hash.as_bytes());
+    }
     for &(ref var, ref val) in env_vars.iter() {
         if CACHED_ENV_VARS.contains(var.as_os_str()) {
             var.hash(&mut HashToDigest { digest: &mut m });
@@ -321,14 +583,20 @@ pub fn hash_key(compiler_digest: &str,
 
 #[cfg(test)]
 mod test {
+    use compiler::gcc::GCC;
+    use futures_cpupool::CpuPool;
+    use mock_command::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
     use super::*;
+    use test::utils::*;
 
     #[test]
     fn test_hash_key_executable_contents_differs() {
         let args = ovec!["a", "b", "c"];
         const PREPROCESSED : &'static [u8] = b"hello world";
-        assert_neq!(hash_key("abcd", Language::C, &args, &[], &PREPROCESSED),
-                    hash_key("wxyz", Language::C, &args, &[], &PREPROCESSED));
+        assert_neq!(hash_key("abcd", Language::C, &args, &[], &[], &PREPROCESSED),
+                    hash_key("wxyz", Language::C, &args, &[], &[], &PREPROCESSED));
     }
 
     #[test]
@@ -339,21 +607,33 @@ mod test {
         let ab = ovec!["a", "b"];
         let a = ovec!["a"];
         const PREPROCESSED: &'static [u8] = b"hello world";
-        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, Language::C, &xyz, &[], &PREPROCESSED));
+        assert_neq!(hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED),
+                    hash_key(digest, Language::C, &xyz, &[], &[], &PREPROCESSED));
 
-        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, Language::C, &ab, &[], &PREPROCESSED));
+        assert_neq!(hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED),
+                    hash_key(digest, Language::C, &ab, &[], &[], &PREPROCESSED));
 
-        assert_neq!(hash_key(digest, Language::C, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, Language::C, &a, &[], &PREPROCESSED));
+        assert_neq!(hash_key(digest, Language::C, &abc, &[], &[], &PREPROCESSED),
+                    hash_key(digest, Language::C, &a, &[], &[], &PREPROCESSED));
     }
 
     #[test]
     fn test_hash_key_preprocessed_content_differs() {
         let args = ovec!["a", "b", "c"];
-        assert_neq!(hash_key("abcd", Language::C, &args, &[], &b"hello world"[..]),
-                    hash_key("abcd", Language::C, &args, &[], &b"goodbye"[..]));
+        assert_neq!(hash_key("abcd", Language::C, &args, &[], &[], &b"hello world"[..]),
+                    hash_key("abcd", Language::C, &args, &[], &[], &b"goodbye"[..]));
+    }
+
+    #[test]
+    fn test_hash_key_extra_hashes_differs() {
+        let args = ovec!["a", "b", "c"];
+        let digest = "abcd";
+        const PREPROCESSED: &'static [u8] = b"hello world";
+        let h1 = hash_key(digest, Language::C, &args, &[], &[], &PREPROCESSED);
+        let h2 = hash_key(digest, Language::C, &args, &stringvec!["1234"], &[], &PREPROCESSED);
+        let h3 = hash_key(digest, Language::C, &args, &stringvec!["5678"], &[], &PREPROCESSED);
+        assert_neq!(h1, h2);
+        assert_neq!(h2, h3);
     }
 
     #[test]
@@ -362,13 +642,188 @@ mod test {
         let digest = "abcd";
         const PREPROCESSED: &'static [u8] = b"hello world";
         for var in CACHED_ENV_VARS.iter() {
-            let h1 = hash_key(digest, Language::C, &args, &[], &PREPROCESSED);
+            let h1 = hash_key(digest, Language::C, &args, &[], &[], &PREPROCESSED);
             let vars = vec![(OsString::from(var), OsString::from("something"))];
-            let h2 = hash_key(digest, Language::C, &args, &vars, &PREPROCESSED);
+            let h2 = hash_key(digest, Language::C, &args, &[], &vars, &PREPROCESSED);
             let vars = vec![(OsString::from(var), OsString::from("something else"))];
-            let h3 = hash_key(digest, Language::C, &args, &vars, &PREPROCESSED);
+            let h3 = hash_key(digest, Language::C, &args, &[], &vars, &PREPROCESSED);
             assert_neq!(h1, h2);
             assert_neq!(h2, h3);
         }
     }
+
+    /// Writes `contents` to `name` in a new fixture and returns the hash key
+    /// of a GCC compile with `args` there. The preprocessor output is always
+    /// the same, so keys only differ if the file is hashed.
+    fn hash_with_file(name: &str, args: Vec<OsString>, contents: &[u8]) -> String {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        File::create(f.tempdir.path().join(name)).unwrap().write_all(contents).unwrap();
+        let parsed_args = match GCC.parse_arguments(&args, f.tempdir.path()) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let hasher = Box::new(CCompilerHasher {
+            parsed_args: parsed_args,
+            executable: PathBuf::from("gcc"),
+            executable_digest: "abcd".to_owned(),
+            compiler: GCC,
+        });
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
+        hasher.generate_hash_key(&creator, f.tempdir.path(), &[], &pool).wait().unwrap().key
+    }
+
+    #[test]
+    fn test_generate_hash_key_profile_data() {
+        let hash = |contents: &[u8]| {
+            hash_with_file("foo.gcda", ovec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"],
+                           contents)
+        };
+        // The profile data changes the output, so it's part of the key.
+        let h1 = hash(b"profile 1");
+        let h2 = hash(b"profile 2");
+        assert_neq!(h1, h2);
+        assert_eq!(h2, hash(b"profile 2"));
+    }
+
+    #[test]
+    fn test_language_from_file_name_assembler() {
+        assert_eq!(Some(Language::Assembler), Language::from_file_name(Path::new("foo.s")));
+        assert_eq!(Some(Language::AssemblerWithCpp), Language::from_file_name(Path::new("foo.S")));
+        assert_eq!(Some(Language::AssemblerWithCpp), Language::from_file_name(Path::new("foo.sx")));
+    }
+
+    #[test]
+    fn test_generate_hash_key_assembler() {
+        // Plain assembler isn't preprocessed, so the source itself is hashed.
+        let hash = |contents: &[u8]| {
+            hash_with_file("foo.s", ovec!["-c", "foo.s", "-o", "foo.o"], contents)
+        };
+        let h1 = hash(b"nop");
+        let h2 = hash(b"ret");
+        assert_neq!(h1, h2);
+        assert_eq!(h2, hash(b"ret"));
+    }
+
+    #[test]
+    fn test_generate_hash_key_assembler_includes() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let compile = |source: &str| {
+            File::create(f.tempdir.path().join("foo.s")).unwrap()
+                .write_all(source.as_bytes()).unwrap();
+            let args = ovec!["-c", "foo.s", "-o", "foo.o"];
+            let parsed_args = match GCC.parse_arguments(&args, f.tempdir.path()) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            };
+            let hasher = Box::new(CCompilerHasher {
+                parsed_args: parsed_args,
+                executable: PathBuf::from("gcc"),
+                executable_digest: "abcd".to_owned(),
+                compiler: GCC,
+            });
+            let res = hasher.generate_hash_key(&creator, f.tempdir.path(), &[], &pool)
+                .wait().unwrap();
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
+            res.compilation.compile(&creator, f.tempdir.path(), &[], None).wait().unwrap().0
+        };
+        assert_eq!(Cacheable::Yes, compile("\tnop\n"));
+        // The included files aren't hashed, so these can't be cached.
+        assert_eq!(Cacheable::No, compile("\t.include \"macros.s\"\n\tnop\n"));
+        assert_eq!(Cacheable::No, compile("data:\t.INCBIN \"blob.bin\"\n"));
+    }
+
+    #[test]
+    fn test_dist_job() {
+        let compilation = |args: Vec<OsString>| {
+            let parsed_args = match GCC.parse_arguments(&args, ".".as_ref()) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            };
+            CCompilation {
+                parsed_args: parsed_args,
+                executable: PathBuf::from("/usr/bin/gcc"),
+                compiler: GCC,
+                preprocessor_output: b"int x;".to_vec(),
+                read_files: vec![],
+                extra_files: vec![],
+                cacheable: Cacheable::Yes,
+            }
+        };
+        let env_vars = vec![(OsString::from("PATH"), OsString::from("/usr/bin")),
+                            (OsString::from("LANG"), OsString::from("C"))];
+        let dist_job = |c: &CCompilation<GCC>| {
+            <CCompilation<GCC> as Compilation<Arc<Mutex<MockCommandCreator>>>>::dist_job(
+                c, Path::new("/build"), &env_vars)
+        };
+        let job = dist_job(&compilation(ovec!["-c", "foo.c", "-O2", "-o", "foo.o"])).unwrap();
+        assert_eq!(PathBuf::from("/usr/bin/gcc"), job.executable);
+        assert_eq!(ovec!["-x", "cpp-output", "-c", "input.i", "-o", "output.o", "-O2"],
+                   job.arguments);
+        // Only variables that make sense on another machine are passed on.
+        assert_eq!(vec![(OsString::from("LANG"), OsString::from("C"))], job.env_vars);
+        assert_eq!(vec![(PathBuf::from("input.i"), b"int x;".to_vec())], job.inputs);
+        assert_eq!(vec![(PathBuf::from("output.o"), Path::new("/build").join("foo.o"))],
+                   job.outputs);
+        // Debug info records the directory of the compile.
+        assert!(dist_job(&compilation(ovec!["-c", "foo.c", "-g", "-o", "foo.o"])).is_none());
+        assert!(dist_job(&compilation(ovec!["-c", "foo.s", "-o", "foo.o"])).is_none());
+        // So do arguments that refer to local files.
+        for args in vec![ovec!["-fprofile-use=/tmp/foo.gcda"],
+                         ovec!["-fprofile-generate"],
+                         ovec!["-fsanitize-blacklist=/etc/blacklist.txt"],
+                         ovec!["-B/opt/gcc/libexec"],
+                         ovec!["-fplugin=foo.so"],
+                         ovec!["--sysroot", "/opt/sysroot"]] {
+            let mut args = args;
+            args.extend(ovec!["-c", "foo.c", "-o", "foo.o"]);
+            assert!(dist_job(&compilation(args.clone())).is_none(), "{:?}", args);
+        }
+        let mut c = compilation(ovec!["-c", "foo.c", "-o", "foo.o"]);
+        c.extra_files = vec![PathBuf::from("foo.h.gch")];
+        assert!(dist_job(&c).is_none());
+    }
+
+    #[test]
+    fn test_may_use_local_file() {
+        let f = TestFixture::new();
+        f.touch("blacklist.txt").unwrap();
+        let cwd = f.tempdir.path();
+        assert!(!may_use_local_file("-O2".as_ref(), cwd));
+        assert!(!may_use_local_file("-march=native".as_ref(), cwd));
+        assert!(!may_use_local_file("max-inline-insns=10".as_ref(), cwd));
+        assert!(may_use_local_file("-fsanitize=blacklist.txt".as_ref(), cwd));
+        assert!(may_use_local_file("blacklist.txt".as_ref(), cwd));
+        assert!(may_use_local_file("-fprofile-use=/tmp/foo.gcda".as_ref(), cwd));
+        assert!(may_use_local_file("-specs=foo.specs".as_ref(), cwd));
+    }
+
+    #[test]
+    fn test_sandbox_inputs() {
+        let parsed_args = match GCC.parse_arguments(&ovec!["-c", "foo.c", "-o", "foo.o"],
+                                                    ".".as_ref()) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let compilation = CCompilation {
+            parsed_args: parsed_args,
+            executable: PathBuf::from("/usr/bin/gcc"),
+            compiler: GCC,
+            preprocessor_output: b"int x;\n".to_vec(),
+            read_files: vec![PathBuf::from("foo.c"), PathBuf::from("/usr/include/stdio.h")],
+            extra_files: vec![PathBuf::from("foo.h.gch")],
+            cacheable: Cacheable::Yes,
+        };
+        let inputs = <CCompilation<GCC> as Compilation<Arc<Mutex<MockCommandCreator>>>>::sandbox_inputs(
+            &compilation, Path::new("/build")).unwrap();
+        assert_eq!(SandboxCompiler::C(PathBuf::from("/usr/bin/gcc")), inputs.compiler);
+        assert_eq!(vec![PathBuf::from("/build/foo.c"),
+                        PathBuf::from("/build/foo.c"),
+                        PathBuf::from("/usr/include/stdio.h"),
+                        PathBuf::from("/build/foo.h.gch")],
+                   inputs.paths);
+    }
 }
@@ -21,7 +21,7 @@ use ::compiler::{
     write_temp_file,
 };
 use compiler::args::*;
-use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments, Preprocessed};
 use compiler::gcc::GCCArgAttribute::*;
 use futures::future::{self, Future};
 use futures_cpupool::CpuPool;
@@ -36,9 +36,9 @@ use std::io::{
     self,
     Write,
 };
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
-use util::{run_input_output, OsStrExt};
+use util::{run_input_output, OsStrExt, OutputSink};
 
 use errors::*;
 
@@ -60,10 +60,22 @@ impl CCompilerImpl for Clang {
                      executable: &Path,
                      parsed_args: &ParsedArguments,
                      cwd: &Path,
-                     env_vars: &[(OsString, OsString)])
-                     -> SFuture<process::Output> where T: CommandCreatorSync
+                     env_vars: &[(OsString, OsString)],
+                     pool: &CpuPool)
+                     -> SFuture<Preprocessed> where T: CommandCreatorSync
     {
-        gcc::preprocess(creator, executable, parsed_args, cwd, env_vars)
+        let result = gcc::preprocess(creator, executable, parsed_args, cwd, env_vars, pool);
+        // With modules, the preprocessor output only refers to the modules
+        // instead of containing the headers they were built from, so those
+        // headers need to be hashed too.
+        let modules = parsed_args.common_args.iter().any(|a| a == "-fmodules" || a == "-fcxx-modules");
+        Box::new(result.map(move |(output, read)| {
+            Preprocessed {
+                output: output,
+                dependencies: if modules { read.clone() } else { vec!() },
+                read_files: read,
+            }
+        }))
     }
 
     fn compile<T>(&self,
@@ -71,22 +83,27 @@ impl CCompilerImpl for Clang {
                   executable: &Path,
                   parsed_args: &ParsedArguments,
                   cwd: &Path,
-                  env_vars: &[(OsString, OsString)])
+                  env_vars: &[(OsString, OsString)],
+                  output: Option<OutputSink>)
                   -> SFuture<(Cacheable, process::Output)>
         where T: CommandCreatorSync
     {
-        gcc::compile(creator, executable, parsed_args, cwd, env_vars)
+        gcc::compile(creator, executable, parsed_args, cwd, env_vars, output)
     }
 }
 
-static ARGS: [(ArgInfo, gcc::GCCArgAttribute); 8] = [
+static ARGS: [(ArgInfo, gcc::GCCArgAttribute); 12] = [
     take_arg!("--serialize-diagnostics", String, Separated, PassThrough),
     take_arg!("--target", String, Separated, PassThrough),
     take_arg!("-Xclang", String, Separated, PassThrough),
-    flag!("-fcxx-modules", TooHard),
-    flag!("-fmodules", TooHard),
+    flag!("-fcxx-modules", PassThrough),
+    take_arg!("-fmodule-file", Path, Concatenated('='), PreprocessorInput),
+    take_arg!("-fmodule-map-file", Path, Concatenated('='), PreprocessorInput),
+    flag!("-fmodules", PassThrough),
+    take_arg!("-fprofile-instr-use", Path, Concatenated, ClangProfileUse),
+    take_arg!("-fprofile-use", Path, Concatenated, ClangProfileUse),
     take_arg!("-gcc-toolchain", String, Separated, PassThrough),
-    take_arg!("-include-pch", Path, CanBeSeparated, PreprocessorArgument),
+    take_arg!("-include-pch", Path, CanBeSeparated, PreprocessorInput),
     take_arg!("-target", String, Separated, PassThrough),
 ];
 
@@ -149,11 +166,107 @@ mod test {
         parses!("-c", "foo.c", "-gcc-toolchain", "somewhere", "-o", "foo.o");
     }
 
+    #[test]
+    fn test_parse_arguments_cuda() {
+        let a = parses!("-c", "foo.cu", "-o", "foo.o");
+        assert_eq!(Language::Cuda, a.language);
+        let a = parses!("-x", "cuda", "-c", "foo.cpp", "-o", "foo.o");
+        assert_eq!(Language::Cuda, a.language);
+    }
+
     #[test]
     fn test_parse_arguments_clangmodules() {
-        assert_eq!(CompilerArguments::CannotCache("-fcxx-modules"),
-                   _parse_arguments(&stringvec!["-c", "foo.c", "-fcxx-modules", "-o", "foo.o"]));
-        assert_eq!(CompilerArguments::CannotCache("-fmodules"),
-                   _parse_arguments(&stringvec!["-c", "foo.c", "-fmodules", "-o", "foo.o"]));
+        let a = parses!("-c", "foo.c", "-fcxx-modules", "-o", "foo.o");
+        assert_eq!(ovec!["-fcxx-modules"], a.common_args);
+        let a = parses!("-c", "foo.c", "-fmodules", "-fmodules-cache-path=mcp", "-o", "foo.o");
+        assert_eq!(ovec!["-fmodules", "-fmodules-cache-path=mcp"], a.common_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_pch() {
+        let f = TestFixture::new();
+        let parse = |args: Vec<OsString>| {
+            match Clang.parse_arguments(&args, f.tempdir.path()) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        };
+        f.touch("foo.pch").unwrap();
+        f.touch("bar.h.pch").unwrap();
+        f.touch("foo.pcm").unwrap();
+        let a = parse(ovec!["-c", "foo.c", "-include-pch", "foo.pch", "-include", "bar.h",
+                            "-fmodule-file=foo=foo.pcm", "-o", "foo.o"]);
+        assert_eq!(ovec!["-include-pch", "foo.pch", "-include", "bar.h", "-fmodule-file=foo=foo.pcm"],
+                   a.preprocessor_args);
+        assert_eq!(vec![f.tempdir.path().join("foo.pch"),
+                        f.tempdir.path().join("foo.pcm"),
+                        f.tempdir.path().join("bar.h.pch")],
+                   a.extra_hash_files);
+    }
+
+    #[test]
+    fn test_parse_arguments_generate_pch() {
+        let a = parses!("-x", "c++-header", "foo.h", "-o", "foo.pch");
+        assert_eq!(Language::CxxHeader, a.language);
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.pch")));
+        let a = parses!("-c", "include/foo.hpp");
+        assert_eq!(Language::CxxHeader, a.language);
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("include/foo.hpp.gch")));
+        let a = parses!("-Xclang", "-emit-pch", "-x", "c-header", "foo.h", "-o", "foo.pch");
+        assert_eq!(Language::CHeader, a.language);
+        assert_eq!(ovec!["-Xclang", "-emit-pch"], a.common_args);
+    }
+
+    #[test]
+    fn test_preprocess_modules() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        // The preprocessor lists the headers that modules were built from.
+        fn preprocess(args: &[OsString]) -> Result<MockChild> {
+            assert_eq!(ovec!["-x", "c", "-E", "-P", "foo.c", "-MD", "-MF"], &args[..7]);
+            let mut deps = File::create(&args[7])?;
+            deps.write_all(b"foo.o: foo.c foo.h \\\n  bar.h\n")?;
+            Ok(MockChild::new(exit_status(0), "preprocessed", ""))
+        }
+        // The command's own dependency file is written when it's compiled.
+        let a = parses!("-c", "foo.c", "-fmodules", "-MD", "-MF", "foo.d", "-o", "foo.o");
+        next_command_calls(&creator, preprocess);
+        let p = Clang.preprocess(&creator, "clang".as_ref(), &a, f.tempdir.path(), &[], &pool)
+            .wait().unwrap();
+        assert_eq!(b"preprocessed", &p.output.stdout[..]);
+        let read = vec![f.tempdir.path().join("foo.c"),
+                        f.tempdir.path().join("foo.h"),
+                        f.tempdir.path().join("bar.h")];
+        assert_eq!(read, p.read_files);
+        assert_eq!(read, p.dependencies);
+        // Without modules, the preprocessor output has everything.
+        let a = parses!("-c", "foo.c", "-o", "foo.o");
+        next_command_calls(&creator, preprocess);
+        let p = Clang.preprocess(&creator, "clang".as_ref(), &a, f.tempdir.path(), &[], &pool)
+            .wait().unwrap();
+        assert_eq!(read, p.read_files);
+        assert!(p.dependencies.is_empty());
+    }
+
+    #[test]
+    fn test_parse_arguments_profile_use() {
+        let f = TestFixture::new();
+        let parse = |args: Vec<OsString>| {
+            match Clang.parse_arguments(&args, f.tempdir.path()) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        };
+        f.touch("code.profdata").unwrap();
+        f.touch("profiles/default.profdata").unwrap();
+        let a = parse(ovec!["-c", "foo.c", "-fprofile-instr-use=code.profdata", "-o", "foo.o"]);
+        assert_eq!(vec![f.tempdir.path().join("code.profdata")], a.extra_hash_files);
+        assert_eq!(ovec!["-fprofile-instr-use=code.profdata"], a.common_args);
+        let a = parse(ovec!["-c", "foo.c", "-fprofile-use=profiles", "-o", "foo.o"]);
+        assert_eq!(vec![f.tempdir.path().join("profiles").join("default.profdata")],
+                   a.extra_hash_files);
+        let a = parse(ovec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"]);
+        assert!(a.extra_hash_files.is_empty());
     }
 }
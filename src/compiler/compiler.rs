@@ -14,6 +14,7 @@
 
 use cache::{
     Cache,
+    CacheRead,
     CacheWrite,
     Storage,
 };
@@ -22,25 +23,35 @@ use compiler::c::{CCompiler, CCompilerKind};
 use compiler::clang::Clang;
 use compiler::gcc::GCC;
 use compiler::msvc::MSVC;
+use compiler::normalize::Normalizer;
+use compiler::nvcc::Nvcc;
 use compiler::rust::Rust;
+use dist;
 use futures::{Future, IntoFuture};
+use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
+use jobserver::JobLimit;
 use mock_command::{
     CommandChild,
     CommandCreatorSync,
     RunCommand,
     exit_status,
 };
+use rand;
+use sandbox::{Sandbox, SandboxInputs};
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fmt;
 #[cfg(unix)]
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::{self,Stdio};
+use std::rc::Rc;
 use std::str;
 use std::sync::Arc;
 use std::time::{
@@ -49,7 +60,7 @@ use std::time::{
 };
 use tempdir::TempDir;
 use tempfile::NamedTempFile;
-use util::fmt_duration_as_secs;
+use util::{fmt_duration_as_secs, OutputSink};
 use tokio_core::reactor::{Handle, Timeout};
 
 use errors::*;
@@ -63,6 +74,19 @@ pub enum CompilerKind {
     Rust,
 }
 
+impl CompilerKind {
+    /// A short name for the kind of compiler, for statistics.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CompilerKind::C(CCompilerKind::GCC) => "gcc",
+            CompilerKind::C(CCompilerKind::Clang) => "clang",
+            CompilerKind::C(CCompilerKind::MSVC) => "msvc",
+            CompilerKind::C(CCompilerKind::Nvcc) => "nvcc",
+            CompilerKind::Rust => "rust",
+        }
+    }
+}
+
 /// An interface to a compiler for argument parsing.
 pub trait Compiler<T>: Send + 'static
     where T: CommandCreatorSync,
@@ -96,6 +120,17 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                          -> SFuture<HashResult<T>>;
     /// Look up a cached compile result in `storage`. If not found, run the
     /// compile and store the result.
+    ///
+    /// The result includes the hash key used, if hashing succeeded. If
+    /// `output` is `Some`, the output of any compile that is run is also
+    /// sent to it as it is produced. If `limit` is `Some`, the compile only
+    /// runs once it gets a token from it; hashing isn't limited. If `dist`
+    /// is `Some`, the compile runs on a build worker if it can, and locally
+    /// otherwise. If `normalizer` is `Some`, outputs are normalized by it
+    /// before they're stored in the cache, and its settings are part of the
+    /// hash key. If `in_flight` is `Some`, a compile with the same hash key
+    /// as one in progress there waits for it to finish, and then uses its
+    /// cache entry.
     fn get_cached_or_compile(self: Box<Self>,
                              creator: T,
                              storage: Arc<Storage>,
@@ -104,8 +139,14 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                              env_vars: Vec<(OsString, OsString)>,
                              cache_control: CacheControl,
                              pool: CpuPool,
-                             handle: Handle)
-                             -> SFuture<(CompileResult, process::Output)>
+                             handle: Handle,
+                             output: Option<OutputSink>,
+                             limit: Option<JobLimit>,
+                             dist: Option<Arc<dist::Client>>,
+                             sandbox: Option<Arc<Sandbox>>,
+                             normalizer: Option<Arc<Normalizer>>,
+                             in_flight: Option<InFlight>)
+                             -> SFuture<(CompileResult, process::Output, Option<String>)>
     {
         let out_pretty = self.output_pretty().into_owned();
         debug!("[{}]: get_cached_or_compile: {:?}", out_pretty, arguments);
@@ -115,37 +156,64 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
             debug!("[{}]: generate_hash_key took {}", out_pretty, fmt_duration_as_secs(&start.elapsed()));
             let (key, compilation) = match res {
                 Err(Error(ErrorKind::ProcessError(output), _)) => {
-                    return f_ok((CompileResult::Error, output));
+                    return f_ok((CompileResult::Error, output, None));
                 }
                 Err(e) => return f_err(e),
                 Ok(HashResult { key, compilation }) => (key, compilation),
             };
+            // Outputs are stored differently depending on how they're
+            // normalized, so the settings are part of the key.
+            let key = match normalizer {
+                Some(ref normalizer) => normalizer.hash_key(&key),
+                None => key,
+            };
             trace!("[{}]: Hash key: {}", out_pretty, key);
-            // If `ForceRecache` is enabled, we won't check the cache.
-            let start = Instant::now();
-            let cache_status = if cache_control == CacheControl::ForceRecache {
-                f_ok(Cache::Recache)
-            } else {
-                storage.get(&key)
+            // If an identical compile is in progress, wait for it to finish,
+            // and then use its cache entry. Otherwise, this compile is in
+            // progress until `guard` is finished or dropped.
+            let wait = match in_flight {
+                Some(ref in_flight) => in_flight.wait_or_start(&key),
+                None => f_ok((None, None)),
             };
+            let lookup_storage = storage.clone();
+            let lookup_key = key.clone();
+            let lookup_handle = handle.clone();
+            let o = out_pretty.clone();
+            let cache_status = wait.and_then(move |(finished, guard)| {
+                // If `ForceRecache` is enabled, we won't check the cache.
+                let start = Instant::now();
+                let cache_status = if let Some(entry) = finished {
+                    debug!("[{}]: Using the result of an identical compile", o);
+                    f_ok(Cache::Hit(entry))
+                } else if cache_control == CacheControl::ForceRecache {
+                    f_ok(Cache::Recache)
+                } else {
+                    lookup_storage.get(&lookup_key)
+                };
 
-            // Set a maximum time limit for the cache to respond before we forge
-            // ahead ourselves with a compilation.
-            let timeout = Duration::new(60, 0);
-            let timeout = Timeout::new(timeout, &handle).into_future().flatten();
-
-            let cache_status = cache_status.map(Some);
-            let timeout = timeout.map(|_| None).chain_err(|| "timeout error");
-            let cache_status = cache_status.select(timeout).then(|r| {
-                match r {
-                    Ok((e, _other)) => Ok(e),
-                    Err((e, _other)) => Err(e),
-                }
+                // Set a maximum time limit for the cache to respond before we forge
+                // ahead ourselves with a compilation.
+                let timeout = Duration::new(60, 0);
+                let timeout = Timeout::new(timeout, &lookup_handle).into_future().flatten();
+
+                let cache_status = cache_status.map(Some);
+                let timeout = timeout.map(|_| None).chain_err(|| "timeout error");
+                cache_status.select(timeout).then(move |r| -> Result<_> {
+                    let r = match r {
+                        Ok((e, _other)) => Ok(e),
+                        Err((e, _other)) => Err(e),
+                    };
+                    Ok((r, start.elapsed(), guard))
+                })
             });
 
             // Check the result of the cache lookup.
-            Box::new(cache_status.then(move |result| {
-                let duration = start.elapsed();
+            Box::new(cache_status.and_then(move |(result, duration, guard)| {
+                let verify = match cache_control {
+                    CacheControl::Verify(fraction) => rand::random::<f64>() < fraction,
+                    _ => false,
+                };
+                let cache_duration = duration;
                 let pwd = Path::new(&cwd);
                 let outputs = compilation.outputs()
                     .map(|(key, path)| (key.to_string(), pwd.join(path)))
@@ -154,36 +222,85 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                 let miss_type = match result {
                     Ok(Some(Cache::Hit(mut entry))) => {
                         debug!("[{}]: Cache hit in {}", out_pretty, fmt_duration_as_secs(&duration));
-                        let mut stdout = Vec::new();
-                        let mut stderr = Vec::new();
-                        drop(entry.get_object("stdout", &mut stdout));
-                        drop(entry.get_object("stderr", &mut stderr));
-                        let write = pool.spawn_fn(move ||{
-                            for (key, path) in &outputs {
-                                let dir = match path.parent() {
-                                    Some(d) => d,
-                                    None => bail!("Output file without a parent directory!"),
+                        if verify {
+                            debug!("[{}]: Verifying cache hit by compiling", out_pretty);
+                            let token = match limit {
+                                Some(limit) => Box::new(limit.acquire().map(Some)) as SFuture<_>,
+                                None => f_ok(None),
+                            };
+                            let o = out_pretty.clone();
+                            let p = pool.clone();
+                            // The client gets the cache hit either way, so it
+                            // doesn't see this compile's output.
+                            let compile = token.and_then(move |token| {
+                                let start = Instant::now();
+                                compile_locally(compilation, creator, cwd, env_vars, None,
+                                                sandbox, p, o)
+                                    .then(move |res| -> Result<_> {
+                                        drop(token);
+                                        Ok((start.elapsed(), res))
+                                    })
+                            });
+                            return Box::new(compile.and_then(move |(compile_duration, res)| -> SFuture<_> {
+                                let success = match res {
+                                    Ok((_, compiler_result)) => compiler_result.status.success(),
+                                    // A compile that fails is checked below.
+                                    Err(Error(ErrorKind::ProcessError(_), _)) => false,
+                                    Err(e) => {
+                                        // The entry is still good to use.
+                                        warn!("[{}]: Failed to compile to verify cache entry {}: {}",
+                                              out_pretty, key, e);
+                                        let write = write_cache_hit(entry, outputs, &pool);
+                                        return Box::new(write.map(move |(output, compile_time)| {
+                                            let result = CompileResult::VerifyError(duration,
+                                                                                    compile_time);
+                                            (result, output, Some(key))
+                                        }));
+                                    }
                                 };
-                                // Write the cache entry to a tempfile and then atomically
-                                // move it to its final location so that other rustc invocations
-                                // happening in parallel don't see a partially-written file.
-                                let mut tmp = NamedTempFile::new_in(dir)?;
-                                let mode = entry.get_object(&key, &mut tmp)?;
-                                tmp.persist(path)?;
-                                if let Some(mode) = mode {
-                                    set_file_mode(&path, mode)?;
-                                }
-                            }
-                            Ok(())
-                        });
-                        let output = process::Output {
-                            status: exit_status(0),
-                            stdout: stdout,
-                            stderr: stderr,
-                        };
-                        let result = CompileResult::CacheHit(duration);
-                        return Box::new(write.map(|_| {
-                            (result, output)
+                                let p = pool.clone();
+                                let mismatches = pool.spawn_fn(move || -> Result<_> {
+                                    let mismatches = if success {
+                                        mismatched_outputs(&mut entry, &outputs, normalizer)
+                                    } else {
+                                        // The entry should never have been
+                                        // cached if the compile can fail.
+                                        let mut names = outputs.keys().cloned().collect::<Vec<_>>();
+                                        names.sort();
+                                        Ok(names)
+                                    };
+                                    Ok((entry, outputs, mismatches))
+                                });
+                                // Whatever the compile produced, the client
+                                // gets the cached outputs.
+                                Box::new(mismatches.and_then(move |(entry, outputs, mismatches)| {
+                                    match mismatches {
+                                        Ok(ref mismatches) if !mismatches.is_empty() => {
+                                            warn!("[{}]: Compiling again doesn't match cache entry {}: {}",
+                                                  out_pretty, key, mismatches.join(", "));
+                                        }
+                                        Ok(_) => {}
+                                        Err(ref e) => {
+                                            warn!("[{}]: Failed to compare cache entry {}: {}",
+                                                  out_pretty, key, e);
+                                        }
+                                    }
+                                    write_cache_hit(entry, outputs, &p).map(move |(output, compile_time)| {
+                                        let result = match mismatches {
+                                            Ok(mismatches) => CompileResult::Verified(duration,
+                                                                                      compile_duration,
+                                                                                      mismatches),
+                                            Err(_) => CompileResult::VerifyError(duration, compile_time),
+                                        };
+                                        (result, output, Some(key))
+                                    })
+                                }))
+                            })) as SFuture<_>;
+                        }
+                        let write = write_cache_hit(entry, outputs, &pool);
+                        return Box::new(write.map(move |(output, compile_time)| {
+                            let result = CompileResult::CacheHit(duration, compile_time);
+                            (result, output, Some(key))
                         })) as SFuture<_>
                     }
                     Ok(Some(Cache::Miss)) => {
@@ -207,23 +324,35 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                     }
                 };
 
-                // Cache miss, so compile it.
-                let start = Instant::now();
+                // Cache miss, so compile it, once the request's limit allows.
                 let out_pretty = out_pretty.clone();
-                let compile = compilation.compile(&creator, &cwd, &env_vars);
-                Box::new(compile.and_then(move |(cacheable, compiler_result)| {
-                    let duration = start.elapsed();
+                let hash_key = Some(key.clone());
+                let token = match limit {
+                    Some(limit) => Box::new(limit.acquire().map(Some)) as SFuture<_>,
+                    None => f_ok(None),
+                };
+                let cwd = cwd.clone();
+                let o = out_pretty.clone();
+                let compile = token.and_then(move |token| {
+                    let start = Instant::now();
+                    compile(compilation, creator, cwd, env_vars, output, dist, sandbox, pool, o)
+                        .map(move |res| {
+                            drop(token);
+                            (start.elapsed(), res)
+                        })
+                });
+                Box::new(compile.and_then(move |(duration, (cacheable, compiler_result))| {
                     if !compiler_result.status.success() {
                         debug!("[{}]: Compiled but failed, not storing in cache",
                                out_pretty);
-                        return f_ok((CompileResult::CompileFailed, compiler_result))
+                        return f_ok((CompileResult::CompileFailed, compiler_result, hash_key))
                             as SFuture<_>
                     }
                     if cacheable != Cacheable::Yes {
                         // Not cacheable
                         debug!("[{}]: Compiled but not cacheable",
                                out_pretty);
-                        return f_ok((CompileResult::NotCacheable, compiler_result))
+                        return f_ok((CompileResult::NotCacheable, compiler_result, hash_key))
                     }
                     debug!("[{}]: Compiled in {}, storing in cache", out_pretty, fmt_duration_as_secs(&duration));
                     let write = pool.spawn_fn(move || -> Result<_> {
@@ -231,7 +360,25 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                         for (key, path) in &outputs {
                             let mut f = File::open(&path)?;
                             let mode = get_file_mode(&path)?;
-                            entry.put_object(key, &mut f, mode).chain_err(|| {
+                            let res = match normalizer {
+                                Some(ref normalizer) => {
+                                    let mut data = vec!();
+                                    f.read_to_end(&mut data)?;
+                                    if normalizer.normalize(&mut data) {
+                                        trace!("Normalized output {:?}", path);
+                                        // Leave the same output behind as a
+                                        // cache hit would.
+                                        File::create(&path)
+                                            .and_then(|mut f| f.write_all(&data))
+                                            .chain_err(|| {
+                                                format!("failed to write normalized output {:?}", path)
+                                            })?;
+                                    }
+                                    entry.put_object(key, &mut &data[..], mode)
+                                }
+                                None => entry.put_object(key, &mut f, mode),
+                            };
+                            res.chain_err(|| {
                                 format!("failed to put object `{:?}` in zip", path)
                             })?;
                         }
@@ -248,6 +395,17 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                             let mut stderr = &compiler_result.stderr[..];
                             entry.put_object("stderr", &mut stderr, None)?;
                         }
+                        entry.put_compile_time(duration)?;
+                        // Identical compiles waiting for this one can use
+                        // the entry now, rather than once it's stored.
+                        let entry = match guard {
+                            Some(guard) => {
+                                let data = entry.finish()?;
+                                guard.finish(&data);
+                                CacheWrite::from_finished(data)
+                            }
+                            None => entry,
+                        };
 
                         // Try to finish storing the newly-written cache
                         // entry. We'll get the result back elsewhere.
@@ -264,7 +422,8 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                                 })
                             });
                         let future = Box::new(future);
-                        Ok((CompileResult::CacheMiss(miss_type, duration, future), compiler_result))
+                        Ok((CompileResult::CacheMiss(miss_type, cache_duration, duration, future),
+                            compiler_result, hash_key))
                     }).chain_err(move || {
                         format!("failed to store `{}` to cache", o)
                     }))
@@ -279,6 +438,9 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
     /// artifact generation.
     fn output_pretty(&self) -> Cow<str>;
 
+    /// The language of the input being compiled, for statistics.
+    fn language(&self) -> &'static str;
+
     fn box_clone(&self) -> Box<CompilerHasher<T>>;
 }
 
@@ -291,10 +453,14 @@ pub trait Compilation<T>
     where T: CommandCreatorSync,
 {
     /// Given information about a compiler command, execute the compiler.
+    ///
+    /// If `output` is `Some`, the compiler's output is also sent to it as
+    /// it is produced.
     fn compile(self: Box<Self>,
                creator: &T,
                cwd: &Path,
-               env_vars: &[(OsString, OsString)])
+               env_vars: &[(OsString, OsString)],
+               output: Option<OutputSink>)
                -> SFuture<(Cacheable, process::Output)>;
 
     /// Returns an iterator over the results of this compilation.
@@ -302,6 +468,126 @@ pub trait Compilation<T>
     /// Each item is a descriptive (and unique) name of the output paired with
     /// the path where it'll show up.
     fn outputs<'a>(&'a self) -> Box<Iterator<Item=(&'a str, &'a Path)> + 'a>;
+
+    /// Describe this compilation as a job for a build worker, if it can run
+    /// on one.
+    fn dist_job(&self,
+                _cwd: &Path,
+                _env_vars: &[(OsString, OsString)])
+                -> Option<dist::CompileJob> {
+        None
+    }
+
+    /// Describe what the compile reads, as far as the hash key knows, so
+    /// that it can run in a sandbox, if it can.
+    fn sandbox_inputs(&self, _cwd: &Path) -> Option<SandboxInputs> {
+        None
+    }
+}
+
+/// Run `compilation` on a build worker using `dist` if possible, and
+/// locally if not, or if that fails.
+fn compile<T>(compilation: Box<Compilation<T>>,
+              creator: T,
+              cwd: PathBuf,
+              env_vars: Vec<(OsString, OsString)>,
+              output: Option<OutputSink>,
+              dist: Option<Arc<dist::Client>>,
+              sandbox: Option<Arc<Sandbox>>,
+              pool: CpuPool,
+              out_pretty: String)
+              -> SFuture<(Cacheable, process::Output)>
+    where T: CommandCreatorSync,
+{
+    let remote = match dist {
+        Some(dist) => compilation.dist_job(&cwd, &env_vars).map(|job| dist.compile(job)),
+        None => None,
+    };
+    let remote = match remote {
+        Some(remote) => remote,
+        None => {
+            return compile_locally(compilation, creator, cwd, env_vars, output, sandbox, pool,
+                                   out_pretty)
+        }
+    };
+    debug!("[{}]: Compiling on a build worker", out_pretty);
+    Box::new(remote.then(move |res| {
+        match res {
+            Ok(result) => f_ok((Cacheable::Yes, result)),
+            Err(e) => {
+                warn!("[{}]: Distributed compile failed, compiling locally: {}", out_pretty, e);
+                compile_locally(compilation, creator, cwd, env_vars, output, sandbox, pool,
+                                out_pretty)
+            }
+        }
+    }))
+}
+
+/// Run `compilation` on this machine, in a sandbox made by `sandbox` if
+/// given and the compilation knows what it reads.
+fn compile_locally<T>(compilation: Box<Compilation<T>>,
+                      creator: T,
+                      cwd: PathBuf,
+                      env_vars: Vec<(OsString, OsString)>,
+                      output: Option<OutputSink>,
+                      sandbox: Option<Arc<Sandbox>>,
+                      pool: CpuPool,
+                      out_pretty: String)
+                      -> SFuture<(Cacheable, process::Output)>
+    where T: CommandCreatorSync,
+{
+    let sandbox = match sandbox {
+        Some(sandbox) => sandbox,
+        None => return compilation.compile(&creator, &cwd, &env_vars, output),
+    };
+    let inputs = match compilation.sandbox_inputs(&cwd) {
+        Some(inputs) => inputs,
+        None => {
+            debug!("[{}]: Compile can't be sandboxed", out_pretty);
+            return compilation.compile(&creator, &cwd, &env_vars, output);
+        }
+    };
+    let outputs = compilation.outputs()
+        .map(|(_, path)| cwd.join(path))
+        .collect::<Vec<_>>();
+    let prepare_cwd = cwd.clone();
+    let prepared = pool.spawn_fn(move || sandbox.prepare(&prepare_cwd, &inputs, &outputs));
+    Box::new(prepared.and_then(move |sandboxed| {
+        debug!("[{}]: Compiling in a sandbox", out_pretty);
+        let creator = creator.wrapped(sandboxed.wrapper().clone());
+        compilation.compile(&creator, &cwd, &env_vars, output).then(move |res| {
+            // Move whatever outputs there are into place even if the
+            // compile failed, so that the sandbox is always cleaned up.
+            let finished = sandboxed.finish();
+            let res = res?;
+            finished?;
+            Ok(res)
+        })
+    }))
+}
+
+/// Get the names of the `outputs` whose contents differ from those in the
+/// cache `entry`, or that are missing. Outputs are normalized by
+/// `normalizer` first, like they are when they're stored.
+fn mismatched_outputs(entry: &mut CacheRead,
+                      outputs: &HashMap<String, PathBuf>,
+                      normalizer: Option<Arc<Normalizer>>)
+                      -> Result<Vec<String>> {
+    let mut mismatches = vec!();
+    for (key, path) in outputs {
+        let mut cached = vec!();
+        entry.get_object(key, &mut cached)?;
+        let mut compiled = vec!();
+        let read = File::open(path).and_then(|mut f| f.read_to_end(&mut compiled));
+        if let Some(ref normalizer) = normalizer {
+            normalizer.normalize(&mut compiled);
+        }
+        if read.is_err() || compiled != cached {
+            mismatches.push(key.clone());
+        }
+    }
+    mismatches.sort();
+    Ok(mismatches)
 }
 
 /// Result of generating a hash from a compiler command.
@@ -318,6 +604,9 @@ pub enum CompilerArguments<T>
 {
     /// Commandline can be handled.
     Ok(T),
+    /// Commandline compiles several inputs at once, each of which can be
+    /// handled separately.
+    Multiple(Vec<T>),
     /// Cannot cache this compilation.
     CannotCache(&'static str),
     /// This commandline is not a compile.
@@ -337,6 +626,18 @@ pub enum MissType {
     CacheReadError,
 }
 
+impl MissType {
+    /// A short name for the kind of miss, for logging.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MissType::Normal => "normal",
+            MissType::ForcedRecache => "forced_recache",
+            MissType::TimedOut => "timed_out",
+            MissType::CacheReadError => "cache_read_error",
+        }
+    }
+}
+
 /// Information about a successful cache write.
 pub struct CacheWriteInfo {
     pub object_file_pretty: String,
@@ -348,16 +649,30 @@ pub enum CompileResult {
     /// An error made the compilation not possible.
     Error,
     /// Result was found in cache.
-    CacheHit(Duration),
+    ///
+    /// The durations are those of the cache lookup and, if it was recorded,
+    /// of the compile that produced the cache entry.
+    CacheHit(Duration, Option<Duration>),
     /// Result was not found in cache.
     ///
-    /// The `CacheWriteFuture` will resolve when the result is finished
-    /// being stored in the cache.
-    CacheMiss(MissType, Duration, SFuture<CacheWriteInfo>),
+    /// The durations are those of the cache lookup and of the compile. The
+    /// `CacheWriteFuture` will resolve when the result is finished being
+    /// stored in the cache.
+    CacheMiss(MissType, Duration, Duration, SFuture<CacheWriteInfo>),
     /// Not in cache, but the compilation result was determined to be not cacheable.
     NotCacheable,
     /// Not in cache, but compilation failed.
     CompileFailed,
+    /// Result was found in cache, and compiled again to check it.
+    ///
+    /// The durations are those of the cache lookup and of the compile,
+    /// followed by the names of the outputs that didn't match the cache.
+    Verified(Duration, Duration, Vec<String>),
+    /// Result was found in cache, and couldn't be compiled again to check
+    /// it, so the cache entry was used.
+    ///
+    /// The durations are as for `CacheHit`.
+    VerifyError(Duration, Option<Duration>),
 }
 
 
@@ -366,10 +681,12 @@ impl fmt::Debug for CompileResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &CompileResult::Error => write!(f, "CompileResult::Error"),
-            &CompileResult::CacheHit(ref d) => write!(f, "CompileResult::CacheHit({:?})", d),
-            &CompileResult::CacheMiss(ref m, ref d, _) => write!(f, "CompileResult::CacheMiss({:?}, {:?}, _)", d, m),
+            &CompileResult::CacheHit(ref d, ref c) => write!(f, "CompileResult::CacheHit({:?}, {:?})", d, c),
+            &CompileResult::CacheMiss(ref m, ref d, ref c, _) => write!(f, "CompileResult::CacheMiss({:?}, {:?}, {:?}, _)", m, d, c),
             &CompileResult::NotCacheable => write!(f, "CompileResult::NotCacheable"),
             &CompileResult::CompileFailed => write!(f, "CompileResult::CompileFailed"),
+            &CompileResult::Verified(ref d, ref c, ref m) => write!(f, "CompileResult::Verified({:?}, {:?}, {:?})", d, c, m),
+            &CompileResult::VerifyError(ref d, ref c) => write!(f, "CompileResult::VerifyError({:?}, {:?})", d, c),
         }
     }
 }
@@ -379,10 +696,12 @@ impl PartialEq<CompileResult> for CompileResult {
     fn eq(&self, other: &CompileResult) -> bool {
         match (self, other) {
             (&CompileResult::Error, &CompileResult::Error) => true,
-            (&CompileResult::CacheHit(_), &CompileResult::CacheHit(_)) => true,
-            (&CompileResult::CacheMiss(ref m, _, _), &CompileResult::CacheMiss(ref n, _, _)) => m == n,
+            (&CompileResult::CacheHit(_, _), &CompileResult::CacheHit(_, _)) => true,
+            (&CompileResult::CacheMiss(ref m, _, _, _), &CompileResult::CacheMiss(ref n, _, _, _)) => m == n,
             (&CompileResult::NotCacheable, &CompileResult::NotCacheable) => true,
             (&CompileResult::CompileFailed, &CompileResult::CompileFailed) => true,
+            (&CompileResult::Verified(_, _, ref m), &CompileResult::Verified(_, _, ref n)) => m == n,
+            (&CompileResult::VerifyError(_, _), &CompileResult::VerifyError(_, _)) => true,
             _ => false,
         }
     }
@@ -425,12 +744,130 @@ pub enum Cacheable {
 }
 
 /// Control of caching behavior.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CacheControl {
     /// Default caching behavior.
     Default,
     /// Ignore existing cache entries, force recompilation.
     ForceRecache,
+    /// Default caching behavior, but compile the given fraction of cache
+    /// hits anyway, to check that the results match.
+    Verify(f64),
+}
+
+/// Compiles in progress on a single event loop, by hash key, so that a
+/// compile that starts while an identical one is running can wait for it
+/// instead of compiling again.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    /// Requests waiting for each compile in progress, to be sent its cache
+    /// entry.
+    compiles: Rc<RefCell<HashMap<String, Vec<oneshot::Sender<Vec<u8>>>>>>,
+}
+
+/// Keeps a compile in progress under an `InFlight` until finished or
+/// dropped.
+pub struct InFlightGuard {
+    in_flight: InFlight,
+    key: String,
+}
+
+impl InFlight {
+    /// Create an empty table of compiles in progress.
+    pub fn new() -> InFlight {
+        InFlight::default()
+    }
+
+    /// If a compile with hash key `key` is in progress, returns a future
+    /// that resolves to its cache entry once it's finished. Otherwise,
+    /// marks this compile as in progress, and resolves to a guard that
+    /// keeps it so.
+    ///
+    /// If the compile that's waited for doesn't finish with a cache entry,
+    /// the waiting compiles start again, so that the first of them to do so
+    /// runs and the rest wait for it.
+    pub fn wait_or_start(&self, key: &str)
+                         -> SFuture<(Option<CacheRead>, Option<InFlightGuard>)> {
+        let mut compiles = self.compiles.borrow_mut();
+        if !compiles.contains_key(key) {
+            compiles.insert(key.to_owned(), vec!());
+            let guard = InFlightGuard {
+                in_flight: self.clone(),
+                key: key.to_owned(),
+            };
+            return f_ok((None, Some(guard)));
+        }
+        let (tx, rx) = oneshot::channel();
+        compiles.get_mut(key).unwrap().push(tx);
+        let in_flight = self.clone();
+        let key = key.to_owned();
+        Box::new(rx.then(move |res| -> SFuture<_> {
+            match res {
+                Ok(data) => match CacheRead::from(io::Cursor::new(data)) {
+                    Ok(entry) => f_ok((Some(entry), None)),
+                    Err(e) => f_err(e),
+                },
+                Err(_) => in_flight.wait_or_start(&key),
+            }
+        }))
+    }
+}
+
+impl InFlightGuard {
+    /// Finish the compile, giving the compiles waiting for it `data`, the
+    /// cache entry it produced.
+    pub fn finish(self, data: &[u8]) {
+        let waiting = self.in_flight.compiles.borrow_mut().remove(&self.key);
+        for tx in waiting.into_iter().flat_map(|w| w) {
+            drop(tx.send(data.to_vec()));
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        // Any compiles still waiting start again.
+        self.in_flight.compiles.borrow_mut().remove(&self.key);
+    }
+}
+
+/// Write the outputs in cache entry `entry` to their paths in `outputs`.
+///
+/// Resolves to the compiler output recorded in the entry, and how long the
+/// compile that produced it took, if that was recorded.
+fn write_cache_hit(mut entry: CacheRead,
+                   outputs: HashMap<String, PathBuf>,
+                   pool: &CpuPool)
+                   -> SFuture<(process::Output, Option<Duration>)> {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    drop(entry.get_object("stdout", &mut stdout));
+    drop(entry.get_object("stderr", &mut stderr));
+    let compile_time = entry.get_compile_time();
+    let write = pool.spawn_fn(move ||{
+        for (key, path) in &outputs {
+            let dir = match path.parent() {
+                Some(d) => d,
+                None => bail!("Output file without a parent directory!"),
+            };
+            // Write the cache entry to a tempfile and then atomically
+            // move it to its final location so that other rustc invocations
+            // happening in parallel don't see a partially-written file.
+            let mut tmp = NamedTempFile::new_in(dir)?;
+            let mode = entry.get_object(&key, &mut tmp)?;
+            tmp.persist(path)?;
+            if let Some(mode) = mode {
+                set_file_mode(&path, mode)?;
+            }
+        }
+        Ok(())
+    });
+    let output = process::Output {
+        status: exit_status(0),
+        stdout: stdout,
+        stderr: stderr,
+    };
+    Box::new(write.map(move |_| (output, compile_time)))
 }
 
 /// Creates a future that will write `contents` to `path` inside of a temporary
@@ -513,7 +950,9 @@ fn detect_c_compiler<T>(creator: T, executable: PathBuf, pool: CpuPool)
 {
     trace!("detect_c_compiler");
 
-    let test = b"#if defined(_MSC_VER)
+    let test = b"#if defined(__NVCC__)
+nvcc
+#elif defined(_MSC_VER)
 msvc
 #elif defined(__clang__)
 clang
@@ -552,6 +991,10 @@ gcc
                 debug!("Found clang");
                 return Box::new(CCompiler::new(Clang, executable, &pool)
                                 .map(|c| Some(Box::new(c) as Box<Compiler<T>>)));
+            } else if line == "nvcc" {
+                debug!("Found nvcc");
+                return Box::new(CCompiler::new(Nvcc, executable, &pool)
+                                .map(|c| Some(Box::new(c) as Box<Compiler<T>>)));
             } else if line == "msvc" {
                 debug!("Found MSVC");
                 let prefix = msvc::detect_showincludes_prefix(&creator,
@@ -561,6 +1004,7 @@ gcc
                     trace!("showIncludes prefix: '{}'", prefix);
                     CCompiler::new(MSVC {
                         includes_prefix: prefix,
+                        pdb_mode: msvc::PdbMode::from_environment(),
                     }, executable, &pool)
                         .map(|c| Some(Box::new(c) as Box<Compiler<T>>))
                 }))
@@ -591,11 +1035,11 @@ mod test {
     use super::*;
     use cache::Storage;
     use cache::disk::DiskCache;
-    use futures::Future;
+    use futures::{future, Future};
     use futures_cpupool::CpuPool;
     use mock_command::*;
     use std::fs::{self,File};
-    use std::io::Write;
+    use std::io::{Read, Write};
     use std::sync::Arc;
     use std::time::Duration;
     use std::u64;
@@ -623,6 +1067,16 @@ mod test {
         assert_eq!(CompilerKind::C(CCompilerKind::Clang), c.kind());
     }
 
+    #[test]
+    fn test_detect_compiler_kind_nvcc() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "nvcc\n", "")));
+        let c = detect_compiler(&creator, &f.bins[0], &pool).wait().unwrap().unwrap();
+        assert_eq!(CompilerKind::C(CCompilerKind::Nvcc), c.kind());
+    }
+
     #[test]
     fn test_detect_compiler_kind_msvc() {
         use env_logger;
@@ -712,7 +1166,7 @@ mod test {
                                   &f.bins[0],
                                   &pool).wait().unwrap();
         // The preprocessor invocation.
-        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
         // The compiler invocation.
         const COMPILER_STDOUT : &'static [u8] = b"compiler stdout";
         const COMPILER_STDERR : &'static [u8] = b"compiler stderr";
@@ -731,18 +1185,24 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(creator.clone(),
+        let (cached, res, _) = hasher.get_cached_or_compile(creator.clone(),
                                                          storage.clone(),
                                                          arguments.clone(),
                                                          cwd.to_path_buf(),
                                                          vec![],
                                                          CacheControl::Default,
                                                          pool.clone(),
-                                                         handle.clone()).wait().unwrap();
+                                                         handle.clone(),
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+            CompileResult::CacheMiss(MissType::Normal, _, _, f) => {
                 // wait on cache write future so we don't race with it!
                 f.wait().unwrap();
             }
@@ -754,24 +1214,105 @@ mod test {
         // Now compile again, which should be a cache hit.
         fs::remove_file(&obj).unwrap();
         // The preprocessor invocation.
-        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
         // There should be no actual compiler invocation.
-        let (cached, res) = hasher2.get_cached_or_compile(creator.clone(),
+        let (cached, res, _) = hasher2.get_cached_or_compile(creator.clone(),
                                                           storage.clone(),
                                                           arguments,
                                                           cwd.to_path_buf(),
                                                           vec![],
                                                           CacheControl::Default,
                                                           pool.clone(),
-                                                          handle).wait().unwrap();
+                                                          handle,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
-        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0), None), cached);
+        // The cache entry remembers how long the original compile took.
+        match cached {
+            CompileResult::CacheHit(_, Some(_)) => {}
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_compiler_get_cached_or_compile_normalized() {
+        use env_logger;
+        drop(env_logger::init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &pool).wait().unwrap();
+        fn archive(mtime: &str) -> Vec<u8> {
+            format!("!<arch>\nfoo.o/          {:12}0     0     100644  4         `\nfoo!", mtime)
+                .into_bytes()
+        }
+        let normalizer = Arc::new(Normalizer::new(true, None).unwrap());
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
+        let key = hasher.clone().generate_hash_key(&creator, cwd, &[], &pool).wait().unwrap().key;
+        // The preprocessor invocation.
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
+        // The compiler invocation.
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            // Pretend to make an archive.
+            let mut f = File::create(&o)?;
+            f.write_all(&archive("1500000000"))?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let (cached, _, hash_key) = hasher.get_cached_or_compile(creator.clone(),
+                                                                 storage.clone(),
+                                                                 arguments.clone(),
+                                                                 cwd.to_path_buf(),
+                                                                 vec![],
+                                                                 CacheControl::Default,
+                                                                 pool.clone(),
+                                                                 handle.clone(),
+                                                                 None,
+                                                                 None,
+                                                                 None,
+                                                                 None,
+                                                                 Some(normalizer.clone()),
+                                                                 None).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, _, f) => {
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        // The normalizer's settings are part of the key.
+        assert_eq!(Some(normalizer.hash_key(&key)), hash_key);
+        // The output is left the same as a cache hit would leave it.
+        let mut data = vec!();
+        File::open(&obj).and_then(|mut f| f.read_to_end(&mut data)).unwrap();
+        assert_eq!(archive("0"), data);
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_cached() {
         use env_logger;
@@ -791,7 +1332,7 @@ mod test {
                                   &f.bins[0],
                                   &pool).wait().unwrap();
         // The preprocessor invocation.
-        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
         // The compiler invocation.
         const COMPILER_STDOUT : &'static [u8] = b"compiler stdout";
         const COMPILER_STDERR : &'static [u8] = b"compiler stderr";
@@ -810,18 +1351,24 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(creator.clone(),
+        let (cached, res, _) = hasher.get_cached_or_compile(creator.clone(),
                                                          storage.clone(),
                                                          arguments.clone(),
                                                          cwd.to_path_buf(),
                                                          vec![],
                                                          CacheControl::Default,
                                                          pool.clone(),
-                                                         handle.clone()).wait().unwrap();
+                                                         handle.clone(),
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+            CompileResult::CacheMiss(MissType::Normal, _, _, f) => {
                 // wait on cache write future so we don't race with it!
                 f.wait().unwrap();
             }
@@ -834,19 +1381,25 @@ mod test {
         // Now compile again, which should be a cache hit.
         fs::remove_file(&obj).unwrap();
         // The preprocessor invocation.
-        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
         // There should be no actual compiler invocation.
-        let (cached, res) = hasher2.get_cached_or_compile(creator,
+        let (cached, res, _) = hasher2.get_cached_or_compile(creator,
                                                           storage,
                                                           arguments,
                                                           cwd.to_path_buf(),
                                                           vec![],
                                                           CacheControl::Default,
                                                           pool,
-                                                          handle).wait().unwrap();
+                                                          handle,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
-        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0), None), cached);
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
@@ -871,7 +1424,7 @@ mod test {
                                   &f.bins[0],
                                   &pool).wait().unwrap();
         // The preprocessor invocation.
-        next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
         // The compiler invocation.
         const COMPILER_STDOUT : &'static [u8] = b"compiler stdout";
         const COMPILER_STDERR : &'static [u8] = b"compiler stderr";
@@ -891,18 +1444,24 @@ mod test {
         };
         // The cache will return an error.
         storage.next_get(f_err("Some Error"));
-        let (cached, res) = hasher.get_cached_or_compile(creator.clone(),
+        let (cached, res, _) = hasher.get_cached_or_compile(creator.clone(),
                                                          storage.clone(),
                                                          arguments.clone(),
                                                          cwd.to_path_buf(),
                                                          vec![],
                                                          CacheControl::Default,
                                                          pool.clone(),
-                                                         handle.clone()).wait().unwrap();
+                                                         handle.clone(),
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::CacheReadError, _, f) => {
+            CompileResult::CacheMiss(MissType::CacheReadError, _, _, f) => {
                 // wait on cache write future so we don't race with it!
                 f.wait().unwrap();
             }
@@ -939,7 +1498,7 @@ mod test {
         let obj = f.tempdir.path().join("foo.o");
         for _ in 0..2 {
             // The preprocessor invocation.
-            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
             // The compiler invocation.
             let o = obj.clone();
             next_command_calls(&creator, move |_| {
@@ -956,18 +1515,24 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(creator.clone(),
+        let (cached, res, _) = hasher.get_cached_or_compile(creator.clone(),
                                                          storage.clone(),
                                                          arguments.clone(),
                                                          cwd.to_path_buf(),
                                                          vec![],
                                                          CacheControl::Default,
                                                          pool.clone(),
-                                                         handle.clone()).wait().unwrap();
+                                                         handle.clone(),
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::Normal, _, f) => {
+            CompileResult::CacheMiss(MissType::Normal, _, _, f) => {
                 // wait on cache write future so we don't race with it!
                 f.wait().unwrap();
             }
@@ -978,18 +1543,24 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
         // Now compile again, but force recaching.
         fs::remove_file(&obj).unwrap();
-        let (cached, res) = hasher2.get_cached_or_compile(creator,
+        let (cached, res, _) = hasher2.get_cached_or_compile(creator,
                                                           storage,
                                                           arguments,
                                                           cwd.to_path_buf(),
                                                           vec![],
                                                           CacheControl::ForceRecache,
                                                           pool,
-                                                          handle).wait().unwrap();
+                                                          handle,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
-            CompileResult::CacheMiss(MissType::ForcedRecache, _, f) => {
+            CompileResult::CacheMiss(MissType::ForcedRecache, _, _, f) => {
                 // wait on cache write future so we don't race with it!
                 f.wait().unwrap();
             }
@@ -1000,6 +1571,287 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_in_flight() {
+        let in_flight = InFlight::new();
+        let (_, guard) = in_flight.wait_or_start("a").wait().unwrap();
+        let guard = guard.unwrap();
+        // Compiles with other keys don't wait.
+        let (_, other) = in_flight.wait_or_start("b").wait().unwrap();
+        assert!(other.is_some());
+        let first = in_flight.wait_or_start("a");
+        let second = in_flight.wait_or_start("a");
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"data"[..], None).unwrap();
+        let data = entry.finish().unwrap();
+        guard.finish(&data);
+        for waiting in vec![first, second] {
+            let (entry, guard) = waiting.wait().unwrap();
+            assert!(guard.is_none());
+            let mut data = vec!();
+            entry.unwrap().get_object("obj", &mut data).unwrap();
+            assert_eq!(b"data", data.as_slice());
+        }
+        // Once it's finished, the next compile with the key starts.
+        let (_, guard) = in_flight.wait_or_start("a").wait().unwrap();
+        let guard = guard.unwrap();
+        // If a compile goes away without an entry, the compiles waiting for
+        // it start again, one at a time.
+        let first = in_flight.wait_or_start("a");
+        drop(guard);
+        let (entry, guard) = first.wait().unwrap();
+        assert!(entry.is_none());
+        let next = in_flight.wait_or_start("a");
+        guard.unwrap().finish(&data);
+        let (entry, guard) = next.wait().unwrap();
+        assert!(entry.is_some());
+        assert!(guard.is_none());
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_in_flight() {
+        use env_logger;
+        drop(env_logger::init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        let in_flight = InFlight::new();
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &pool).wait().unwrap();
+        // Both compiles run the preprocessor, but only one runs the
+        // compiler, in whichever order they get to them.
+        let obj = f.tempdir.path().join("foo.o");
+        for _ in 0..3 {
+            let o = obj.clone();
+            next_command_calls(&creator, move |args| {
+                if args.iter().any(|a| a == "-E") {
+                    return preprocessor_child(args, "preprocessor output");
+                }
+                // Pretend to compile something.
+                let mut f = File::create(&o)?;
+                f.write_all(b"file contents")?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+        }
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let compiles = (0..2).map(|_| {
+            hasher.clone().get_cached_or_compile(creator.clone(),
+                                                 storage.clone(),
+                                                 arguments.clone(),
+                                                 cwd.to_path_buf(),
+                                                 vec![],
+                                                 CacheControl::Default,
+                                                 pool.clone(),
+                                                 handle.clone(),
+                                                 None,
+                                                 None,
+                                                 None,
+                                                 None,
+                                                 None,
+                                                 Some(in_flight.clone()))
+        }).collect::<Vec<_>>();
+        let results = core.run(future::join_all(compiles)).unwrap();
+        let mut misses = 0;
+        for (cached, res, _) in results {
+            match cached {
+                CompileResult::CacheMiss(MissType::Normal, _, _, f) => {
+                    misses += 1;
+                    f.wait().unwrap();
+                }
+                // The other compile gets its entry without waiting for it
+                // to be stored.
+                CompileResult::CacheHit(..) => {}
+                _ => panic!("Unexpected compile result: {:?}", cached),
+            }
+            assert_eq!(exit_status(0), res.status);
+        }
+        assert_eq!(1, misses);
+        assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_verify() {
+        use env_logger;
+        drop(env_logger::init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &pool).wait().unwrap();
+        // The compiler should be invoked twice, since the hit is verified,
+        // and writes something different the second time.
+        let obj = f.tempdir.path().join("foo.o");
+        for contents in &[&b"file contents"[..], &b"other contents"[..]] {
+            // The preprocessor invocation.
+            next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
+            // The compiler invocation.
+            let o = obj.clone();
+            let contents = contents.to_vec();
+            next_command_calls(&creator, move |_| {
+                // Pretend to compile something.
+                let mut f = File::create(&o)?;
+                f.write_all(&contents)?;
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+        }
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, _, _) = hasher.get_cached_or_compile(creator.clone(),
+                                                       storage.clone(),
+                                                       arguments.clone(),
+                                                       cwd.to_path_buf(),
+                                                       vec![],
+                                                       CacheControl::Default,
+                                                       pool.clone(),
+                                                       handle.clone(),
+                                                       None,
+                                                       None,
+                                                       None,
+                                                       None,
+                                                       None,
+                                                       None).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        // Now compile again, verifying every hit.
+        let (cached, res, _) = hasher2.get_cached_or_compile(creator,
+                                                          storage,
+                                                          arguments,
+                                                          cwd.to_path_buf(),
+                                                          vec![],
+                                                          CacheControl::Verify(1.0),
+                                                          pool,
+                                                          handle,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None).wait().unwrap();
+        assert_eq!(CompileResult::Verified(Duration::new(0, 0),
+                                           Duration::new(0, 0),
+                                           vec!["obj".to_owned()]),
+                   cached);
+        assert_eq!(exit_status(0), res.status);
+        // The client still gets the cached output.
+        let mut contents = vec!();
+        File::open(&obj).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(&b"file contents"[..], contents.as_slice());
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_verify_error() {
+        use env_logger;
+        drop(env_logger::init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                     u64::MAX,
+                                     &pool);
+        let storage: Arc<Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator,
+                                  &f.bins[0],
+                                  &pool).wait().unwrap();
+        let obj = f.tempdir.path().join("foo.o");
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            // Pretend to compile something.
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        // The compile to verify the hit can't even start.
+        next_command_calls(&creator, |args| preprocessor_child(args, "preprocessor output"));
+        next_command(&creator, Err("failed to spawn compiler".into()));
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, _, _) = hasher.get_cached_or_compile(creator.clone(),
+                                                       storage.clone(),
+                                                       arguments.clone(),
+                                                       cwd.to_path_buf(),
+                                                       vec![],
+                                                       CacheControl::Default,
+                                                       pool.clone(),
+                                                       handle.clone(),
+                                                       None,
+                                                       None,
+                                                       None,
+                                                       None,
+                                                       None,
+                                                       None).wait().unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, _, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        fs::remove_file(&obj).unwrap();
+        let (cached, res, _) = hasher2.get_cached_or_compile(creator,
+                                                          storage,
+                                                          arguments,
+                                                          cwd.to_path_buf(),
+                                                          vec![],
+                                                          CacheControl::Verify(1.0),
+                                                          pool,
+                                                          handle,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None,
+                                                          None).wait().unwrap();
+        // The cache entry is used instead.
+        assert_eq!(CompileResult::VerifyError(Duration::new(0, 0), None), cached);
+        assert_eq!(exit_status(0), res.status);
+        let mut contents = vec!();
+        File::open(&obj).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(&b"file contents"[..], contents.as_slice());
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_preprocessor_error() {
         use env_logger;
@@ -1027,14 +1879,20 @@ mod test {
             CompilerArguments::Ok(h) => h,
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        let (cached, res) = hasher.get_cached_or_compile(creator,
+        let (cached, res, _) = hasher.get_cached_or_compile(creator,
                                                          storage,
                                                          arguments,
                                                          cwd.to_path_buf(),
                                                          vec![],
                                                          CacheControl::Default,
                                                          pool,
-                                                         handle).wait().unwrap();
+                                                         handle,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None,
+                                                         None).wait().unwrap();
         assert_eq!(cached, CompileResult::Error);
         assert_eq!(exit_status(1), res.status);
         // Shouldn't get anything on stdout, since that would just be preprocessor spew!
@@ -17,20 +17,22 @@ use ::compiler::{
     CompilerArguments,
 };
 use compiler::args::*;
-use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments, Preprocessed};
 use log::LogLevel::Trace;
-use futures::future::Future;
+use futures::future::{self, Future};
+use futures_cpupool::CpuPool;
 use mock_command::{
     CommandCreatorSync,
     RunCommand,
 };
 use std::collections::HashMap;
-use std::io::Read;
 use std::ffi::OsString;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process;
-use util::{run_input_output, OsStrExt};
+use tempdir::TempDir;
+use util::{run_input_output, run_input_output_streaming, OsStrExt, OutputSink};
 
 use errors::*;
 
@@ -44,7 +46,17 @@ impl CCompilerImpl for GCC {
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<ParsedArguments>
     {
-        parse_arguments(arguments, cwd, &ARGS[..])
+        // GCC itself doesn't compile CUDA, only clang and nvcc do.
+        let supported = |args: &ParsedArguments| args.language != Language::Cuda;
+        match parse_arguments(arguments, cwd, &ARGS[..]) {
+            CompilerArguments::Ok(ref args) if !supported(args) => {
+                CompilerArguments::CannotCache("unknown source language")
+            }
+            CompilerArguments::Multiple(ref args) if !args.iter().all(supported) => {
+                CompilerArguments::CannotCache("unknown source language")
+            }
+            a => a,
+        }
     }
 
     fn preprocess<T>(&self,
@@ -52,10 +64,28 @@ impl CCompilerImpl for GCC {
                      executable: &Path,
                      parsed_args: &ParsedArguments,
                      cwd: &Path,
-                     env_vars: &[(OsString, OsString)])
-                     -> SFuture<process::Output> where T: CommandCreatorSync
+                     env_vars: &[(OsString, OsString)],
+                     pool: &CpuPool)
+                     -> SFuture<Preprocessed> where T: CommandCreatorSync
     {
-        preprocess(creator, executable, parsed_args, cwd, env_vars)
+        // GCC uses `foo.h.gch` instead of any `foo.h` that's included, if
+        // it's a valid precompiled header, but the preprocessor always reads
+        // the header itself.
+        let result = preprocess(creator, executable, parsed_args, cwd, env_vars, pool);
+        let pool = pool.clone();
+        let cwd = cwd.to_owned();
+        Box::new(result.and_then(move |(output, read)| {
+            pool.spawn_fn(move || -> Result<_> {
+                let pch_files = read.iter()
+                    .flat_map(|header| precompiled_headers(header, &cwd))
+                    .collect();
+                Ok(Preprocessed {
+                    output: output,
+                    read_files: read,
+                    dependencies: pch_files,
+                })
+            })
+        }))
     }
 
     fn compile<T>(&self,
@@ -63,11 +93,12 @@ impl CCompilerImpl for GCC {
                   executable: &Path,
                   parsed_args: &ParsedArguments,
                   cwd: &Path,
-                  env_vars: &[(OsString, OsString)])
+                  env_vars: &[(OsString, OsString)],
+                  output: Option<OutputSink>)
                   -> SFuture<(Cacheable, process::Output)>
         where T: CommandCreatorSync
     {
-        compile(creator, executable, parsed_args, cwd, env_vars)
+        compile(creator, executable, parsed_args, cwd, env_vars, output)
     }
 }
 
@@ -76,19 +107,24 @@ pub enum GCCArgAttribute {
     TooHard,
     PassThrough,
     PreprocessorArgument,
+    PreprocessorInput,
     DoCompilation,
     Output,
     NeedDepTarget,
     DepTarget,
     Language,
     SplitDwarf,
+    ProfileGenerate,
+    ProfileUse,
+    ClangProfileUse,
 }
 
 use self::GCCArgAttribute::*;
 
 // Mostly taken from https://github.com/ccache/ccache/blob/master/compopt.c#L32-L84
-pub static ARGS: [(ArgInfo, GCCArgAttribute); 60] = [
+pub static ARGS: [(ArgInfo, GCCArgAttribute); 63] = [
     flag!("-", TooHard),
+    flag!("--coverage", ProfileGenerate),
     take_arg!("--param", String, Separated, PassThrough),
     flag!("--save-temps", TooHard),
     take_arg!("--serialize-diagnostics", Path, Separated, PassThrough),
@@ -121,7 +157,11 @@ pub static ARGS: [(ArgInfo, GCCArgAttribute); 60] = [
     flag!("-c", DoCompilation),
     flag!("-fno-working-directory", PreprocessorArgument),
     flag!("-fplugin=libcc1plugin", TooHard),
-    flag!("-fprofile-use", TooHard),
+    flag!("-fprofile-arcs", ProfileGenerate),
+    // These take an optional "=path" value, which is kept as part of the
+    // value, see `profile_path`.
+    take_arg!("-fprofile-generate", Path, Concatenated, ProfileGenerate),
+    take_arg!("-fprofile-use", Path, Concatenated, ProfileUse),
     flag!("-frepo", TooHard),
     flag!("-fsyntax-only", TooHard),
     flag!("-fworking-directory", PreprocessorArgument),
@@ -158,7 +198,8 @@ pub static ARGS: [(ArgInfo, GCCArgAttribute); 60] = [
 /// return `CompilerArguments::NotCompilation`.
 /// Otherwise, return `CompilerArguments::Ok(ParsedArguments)`, with
 /// the `ParsedArguments` struct containing information parsed from
-/// `arguments`.
+/// `arguments`, or `CompilerArguments::Multiple` with one `ParsedArguments`
+/// per input file if `arguments` compiles several files at once.
 pub fn parse_arguments<S>(
     arguments: &[OsString],
     cwd: &Path,
@@ -168,22 +209,24 @@ where
     S: SearchableArgInfo<Info = (ArgInfo, GCCArgAttribute)>,
 {
     let mut output_arg = None;
-    let mut input_arg = None;
+    let mut input_args = vec!();
     let mut dep_target = None;
+    let mut dep_file = false;
     let mut common_args = vec!();
     let mut preprocessor_args = vec!();
     let mut compilation = false;
-    let mut multiple_input = false;
     let mut split_dwarf = false;
     let mut need_explicit_dep_target = false;
     let mut language = None;
+    let mut profile_generate = false;
+    let mut profile_use = None;
+    let mut emit_pch = false;
+    let mut include_files = vec!();
+    let mut preprocessor_inputs = vec!();
 
     // Custom iterator to expand `@` arguments which stand for reading a file
     // and interpreting it as a list of more arguments.
-    let it = ExpandIncludeFile {
-        stack: arguments.iter().rev().map(|a| a.to_owned()).collect(),
-        cwd: cwd,
-    };
+    let it = ExpandResponseFiles::new(arguments, cwd, ResponseFileQuoting::Gnu);
 
     for item in ArgsIter::new(it, arg_info) {
         // Refuse to cache arguments such as "-include@foo" because they're a
@@ -204,8 +247,38 @@ where
             Some(Output) => output_arg = item.arg.get_value().map(|s| s.unwrap_path()),
             Some(NeedDepTarget) => need_explicit_dep_target = true,
             Some(DepTarget) => dep_target = item.arg.get_value().map(OsString::from),
-            Some(PreprocessorArgument) |
-            Some(PassThrough) => {}
+            Some(PreprocessorArgument) => {
+                match item.arg.to_str() {
+                    Some("-MF") => dep_file = true,
+                    Some("-include") => {
+                        include_files.extend(item.arg.get_value().map(|s| s.unwrap_path()))
+                    }
+                    _ => {}
+                }
+            }
+            Some(PreprocessorInput) => {
+                let path = item.arg.get_value().map(OsString::from).unwrap_or_default();
+                let path = match path.to_str() {
+                    // `-fmodule-file=` can also be given the module name, as
+                    // `-fmodule-file=name=path`.
+                    Some(s) if item.arg.to_str() == Some("-fmodule-file") && s.contains('=') => {
+                        s.splitn(2, '=').nth(1).unwrap().into()
+                    }
+                    _ => path,
+                };
+                preprocessor_inputs.push(cwd.join(path));
+            }
+            Some(PassThrough) => {
+                if item.arg.to_str() == Some("-Xclang") &&
+                    item.arg.get_value().map(OsString::from) == Some("-emit-pch".into()) {
+                    emit_pch = true;
+                }
+            }
+            Some(ProfileGenerate) => profile_generate = true,
+            Some(ProfileUse) => profile_use = Some(ProfileData::Gcda(profile_path(&item.arg))),
+            Some(ClangProfileUse) => {
+                profile_use = Some(ProfileData::Profdata(profile_path(&item.arg)))
+            }
             Some(Language) => {
                 let lang = item.arg.get_value().map(OsString::from);
                 let lang = lang.as_ref().map(|a| a.to_string_lossy());
@@ -214,17 +287,19 @@ where
                     Some("c++") => Some(Language::Cxx),
                     Some("objective-c") => Some(Language::ObjectiveC),
                     Some("objective-c++") => Some(Language::ObjectiveCxx),
+                    Some("c-header") => Some(Language::CHeader),
+                    Some("c++-header") => Some(Language::CxxHeader),
+                    Some("objective-c-header") => Some(Language::ObjectiveCHeader),
+                    Some("objective-c++-header") => Some(Language::ObjectiveCxxHeader),
+                    Some("cu") | Some("cuda") => Some(Language::Cuda),
+                    Some("assembler-with-cpp") => Some(Language::AssemblerWithCpp),
+                    Some("assembler") => Some(Language::Assembler),
                     _ => return CompilerArguments::CannotCache("-x"),
                 };
             }
             None => {
                 match item.arg {
-                    Argument::Raw(ref val) => {
-                        if input_arg.is_some() {
-                            multiple_input = true;
-                        }
-                        input_arg = Some(val.clone());
-                    }
+                    Argument::Raw(ref val) => input_args.push(val.clone()),
                     Argument::UnknownFlag(_) => {}
                     _ => unreachable!(),
                 }
@@ -232,8 +307,12 @@ where
         }
         let args = match item.data {
             Some(SplitDwarf) |
-            Some(PassThrough) => Some(&mut common_args),
+            Some(PassThrough) |
+            Some(ProfileGenerate) |
+            Some(ProfileUse) |
+            Some(ClangProfileUse) => Some(&mut common_args),
             Some(PreprocessorArgument) |
+            Some(PreprocessorInput) |
             Some(NeedDepTarget) => Some(&mut preprocessor_args),
             Some(DoCompilation) |
             Some(Language) |
@@ -260,74 +339,212 @@ where
         };
     }
 
-    // We only support compilation.
-    if !compilation {
+    // We only support compilation. Generating a precompiled header doesn't
+    // require `-c`.
+    let generate_pch = emit_pch || language.map_or(false, |l| l.is_header());
+    if !compilation && !generate_pch {
         return CompilerArguments::NotCompilation;
     }
-    // Can't cache compilations with multiple inputs.
-    if multiple_input {
+    // We can't cache compilation without an input.
+    if input_args.is_empty() {
+        return CompilerArguments::CannotCache("no input file");
+    }
+    // With several inputs, each one is compiled separately, so arguments
+    // naming a single output file can't be honored.
+    if input_args.len() > 1 && (output_arg.is_some() || dep_file) {
         return CompilerArguments::CannotCache("multiple input files");
     }
-    let input = match input_arg {
-        Some(i) => i.to_owned(),
-        // We can't cache compilation without an input.
-        None => return CompilerArguments::CannotCache("no input file"),
-    };
-    if language == None {
-        language = Language::from_file_name(Path::new(&input));
+    // Precompiled headers and modules are inputs to the compilation that
+    // don't appear in the preprocessor output.
+    let mut pch_files = preprocessor_inputs.into_iter()
+        .filter(|f| f.is_file())
+        .collect::<Vec<_>>();
+    for include in include_files {
+        pch_files.extend(precompiled_headers(&include, cwd));
     }
-    let language = match language {
-        Some(l) => l,
-        None => return CompilerArguments::CannotCache("unknown source language"),
-    };
-    let mut outputs = HashMap::new();
-    let output = match output_arg {
-        // We can't cache compilation that doesn't go to a file
-        None => Path::new(&input).with_extension("o"),
-        Some(o) =>  PathBuf::from(o),
+    let multiple = input_args.len() > 1;
+    let mut parsed = vec!();
+    for input in input_args {
+        let language = match language.or_else(|| Language::from_file_name(Path::new(&input))) {
+            Some(l) => l,
+            None => return CompilerArguments::CannotCache("unknown source language"),
+        };
+        let mut outputs = HashMap::new();
+        let output = match output_arg {
+            // Like the compiler, default to an object file named after the
+            // input, in the current directory.
+            None => default_output(&input, language),
+            Some(ref o) => o.clone(),
+        };
+        if split_dwarf {
+            let dwo = output.with_extension("dwo");
+            outputs.insert("dwo", dwo);
+        }
+        let mut preprocessor_args = preprocessor_args.clone();
+        if need_explicit_dep_target {
+            preprocessor_args.push("-MT".into());
+            preprocessor_args.push(dep_target.clone().unwrap_or(output.clone().into_os_string()));
+        }
+        // The profile data used to optimize the output needs to be part of
+        // the hash.
+        let mut extra_hash_files = pch_files.clone();
+        extra_hash_files.extend(profile_use.as_ref().and_then(|p| p.find_file(&output, cwd)));
+        outputs.insert("obj", output);
+
+        parsed.push(ParsedArguments {
+            input: input.into(),
+            language: language,
+            depfile: None,
+            outputs: outputs,
+            preprocessor_args: preprocessor_args,
+            common_args: common_args.clone(),
+            extra_hash_files: extra_hash_files,
+            // Instrumented objects refer to their profile data by absolute
+            // path.
+            hash_cwd: profile_generate,
+            msvc_show_includes: false,
+        });
+    }
+
+    if multiple {
+        CompilerArguments::Multiple(parsed)
+    } else {
+        CompilerArguments::Ok(parsed.pop().unwrap())
+    }
+}
+
+/// Where a `-fprofile-use` style argument reads profile data from.
+enum ProfileData {
+    /// GCC's per-object `.gcda` files, optionally in the given directory.
+    Gcda(Option<PathBuf>),
+    /// A clang `.profdata` file, or a directory containing `default.profdata`.
+    Profdata(Option<PathBuf>),
+}
+
+impl ProfileData {
+    /// Find the existing profile data file used when compiling `output`.
+    ///
+    /// If there is none, the compiler proceeds without profile data, so
+    /// there's nothing more to hash.
+    fn find_file(&self, output: &Path, cwd: &Path) -> Option<PathBuf> {
+        let candidates = match *self {
+            ProfileData::Gcda(None) => vec![cwd.join(output).with_extension("gcda")],
+            ProfileData::Gcda(Some(ref dir)) => {
+                let dir = cwd.join(dir);
+                // Newer versions of GCC look for a file named after the
+                // absolute path of the object, with slashes replaced by `#`.
+                let mangled = cwd.join(output).with_extension("gcda")
+                    .to_string_lossy()
+                    .replace('/', "#");
+                vec![
+                    dir.clone(),
+                    dir.join(mangled),
+                    dir.join(output.with_extension("gcda")),
+                ]
+            }
+            ProfileData::Profdata(None) => vec![cwd.join("default.profdata")],
+            ProfileData::Profdata(Some(ref path)) => {
+                let path = cwd.join(path);
+                vec![path.join("default.profdata"), path]
+            }
+        };
+        candidates.into_iter().find(|f| f.is_file())
+    }
+}
+
+/// Get the path given to an argument such as `-fprofile-use=path`.
+fn profile_path(arg: &Argument) -> Option<PathBuf> {
+    let value = match arg.get_value() {
+        Some(value) => OsString::from(value),
+        None => return None,
     };
-    if split_dwarf {
-        let dwo = output.with_extension("dwo");
-        outputs.insert("dwo", dwo);
-    }
-    if need_explicit_dep_target {
-        preprocessor_args.push("-MT".into());
-        preprocessor_args.push(dep_target.unwrap_or(output.clone().into_os_string()));
-    }
-    outputs.insert("obj", output);
-
-    CompilerArguments::Ok(ParsedArguments {
-        input: input.into(),
-        language: language,
-        depfile: None,
-        outputs: outputs,
-        preprocessor_args: preprocessor_args,
-        common_args: common_args,
-        msvc_show_includes: false,
-    })
+    match value.split_prefix("=") {
+        Some(ref path) if !path.is_empty() => Some(path.into()),
+        _ => None,
+    }
+}
+
+/// Find the precompiled headers the compiler may use instead of `header`,
+/// whether it's given to `-include` or included by the source.
+fn precompiled_headers(header: &Path, cwd: &Path) -> Vec<PathBuf> {
+    let mut found = vec!();
+    for ext in &[".gch", ".pch"] {
+        let mut pch = cwd.join(header).into_os_string();
+        pch.push(ext);
+        let pch = PathBuf::from(pch);
+        if pch.is_file() {
+            found.push(pch);
+        } else if let Ok(entries) = fs::read_dir(&pch) {
+            // GCC uses the first valid precompiled header from a directory.
+            let mut files = entries.filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.is_file())
+                .collect::<Vec<_>>();
+            files.sort();
+            found.extend(files);
+        }
+    }
+    found
 }
 
+/// The object file the compiler writes for `input` when no `-o` is given.
+fn default_output(input: &OsString, language: Language) -> PathBuf {
+    // Precompiled headers are written next to the header.
+    if language.is_header() {
+        let mut output = input.clone();
+        output.push(".gch");
+        return output.into();
+    }
+    let input = Path::new(input);
+    match input.file_name() {
+        Some(name) => Path::new(name).with_extension("o"),
+        None => input.with_extension("o"),
+    }
+}
+
+/// The value to pass to `-x` for `language`.
+pub fn language_arg(language: Language) -> &'static str {
+    match language {
+        Language::C => "c",
+        Language::Cxx => "c++",
+        Language::ObjectiveC => "objective-c",
+        Language::ObjectiveCxx => "objective-c++",
+        Language::CHeader => "c-header",
+        Language::CxxHeader => "c++-header",
+        Language::ObjectiveCHeader => "objective-c-header",
+        Language::ObjectiveCxxHeader => "objective-c++-header",
+        Language::Cuda => "cuda",
+        Language::AssemblerWithCpp => "assembler-with-cpp",
+        Language::Assembler => "assembler",
+    }
+}
+
+/// Run the preprocessor, and return its output along with the files it
+/// read, as absolute paths.
 pub fn preprocess<T>(creator: &T,
                      executable: &Path,
                      parsed_args: &ParsedArguments,
                      cwd: &Path,
-                     env_vars: &[(OsString, OsString)])
-                     -> SFuture<process::Output>
+                     env_vars: &[(OsString, OsString)],
+                     pool: &CpuPool)
+                     -> SFuture<(process::Output, Vec<PathBuf>)>
     where T: CommandCreatorSync
 {
     trace!("preprocess");
-    let language = match parsed_args.language {
-        Language::C => "c",
-        Language::Cxx => "c++",
-        Language::ObjectiveC => "objective-c",
-        Language::ObjectiveCxx => "objective-c++",
-    };
+    // Have the preprocessor list the files it reads. Any dependency file the
+    // command asked for is written when it's compiled instead.
+    let temp_dir = ftry!(TempDir::new("sccache").chain_err(|| "Failed to create temp dir"));
+    let dep_file = temp_dir.path().join("deps.d");
+    let language = language_arg(parsed_args.language);
     let mut cmd = creator.clone().new_command_sync(executable);
     cmd.arg("-x").arg(language)
         .arg("-E")
         .arg("-P")
         .arg(&parsed_args.input)
-        .args(&parsed_args.preprocessor_args)
+        .args(&without_dep_args(&parsed_args.preprocessor_args))
+        .arg("-MD")
+        .arg("-MF")
+        .arg(&dep_file)
         .args(&parsed_args.common_args)
         .env_clear()
         .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
@@ -336,14 +553,75 @@ pub fn preprocess<T>(creator: &T,
     if log_enabled!(Trace) {
         trace!("preprocess: {:?}", cmd);
     }
-    run_input_output(cmd, None)
+    let pool = pool.clone();
+    let cwd = cwd.to_owned();
+    Box::new(run_input_output(cmd, None).and_then(move |output| {
+        pool.spawn_fn(move || -> Result<_> {
+            let mut deps = String::new();
+            File::open(&dep_file).and_then(|mut f| f.read_to_string(&mut deps))
+                .chain_err(|| "Failed to read the preprocessor's dependencies")?;
+            drop(temp_dir);
+            let read = parse_dependencies(&deps).into_iter().map(|f| cwd.join(f)).collect();
+            Ok((output, read))
+        })
+    }))
+}
+
+/// Remove the arguments that write a dependency file from `args`.
+fn without_dep_args(args: &[OsString]) -> Vec<OsString> {
+    let mut result = vec!();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "-MF" || arg == "-MT" || arg == "-MQ" {
+            it.next();
+        } else if arg != "-M" && arg != "-MM" && arg != "-MD" && arg != "-MMD" && arg != "-MP" {
+            result.push(arg.clone());
+        }
+    }
+    result
+}
+
+/// Get the prerequisites listed in the makefile rules in `deps`.
+fn parse_dependencies(deps: &str) -> Vec<PathBuf> {
+    let deps = deps.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut files = vec!();
+    let mut file = String::new();
+    let mut chars = deps.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                match chars.next() {
+                    Some(' ') => file.push(' '),
+                    Some(c) => {
+                        file.push('\\');
+                        file.push(c);
+                    }
+                    None => file.push('\\'),
+                }
+            }
+            c if c.is_whitespace() => {
+                if !file.is_empty() {
+                    files.push(file.split_off(0));
+                }
+            }
+            c => file.push(c),
+        }
+    }
+    if !file.is_empty() {
+        files.push(file);
+    }
+    files.into_iter()
+        .filter(|f| !f.ends_with(':'))
+        .map(PathBuf::from)
+        .collect()
 }
 
 pub fn compile<T>(creator: &T,
               executable: &Path,
               parsed_args: &ParsedArguments,
               cwd: &Path,
-              env_vars: &[(OsString, OsString)])
+              env_vars: &[(OsString, OsString)],
+              output: Option<OutputSink>)
               -> SFuture<(Cacheable, process::Output)>
     where T: CommandCreatorSync
 {
@@ -358,12 +636,7 @@ pub fn compile<T>(creator: &T,
 
     // Pass the language explicitly as we might have gotten it from the
     // command line.
-    let language = match parsed_args.language {
-        Language::C => "c",
-        Language::Cxx => "c++",
-        Language::ObjectiveC => "objective-c",
-        Language::ObjectiveCxx => "objective-c++",
-    };
+    let language = language_arg(parsed_args.language);
     let mut attempt = creator.clone().new_command_sync(executable);
     attempt.arg("-x").arg(language)
         .arg("-c")
@@ -374,73 +647,11 @@ pub fn compile<T>(creator: &T,
         .env_clear()
         .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
         .current_dir(&cwd);
-    Box::new(run_input_output(attempt, None).map(|output| {
+    Box::new(run_input_output_streaming(attempt, None, output).map(|output| {
         (Cacheable::Yes, output)
     }))
 }
 
-struct ExpandIncludeFile<'a> {
-    cwd: &'a Path,
-    stack: Vec<OsString>,
-}
-
-impl<'a> Iterator for ExpandIncludeFile<'a> {
-    type Item = OsString;
-
-    fn next(&mut self) -> Option<OsString> {
-        loop {
-            let arg = match self.stack.pop() {
-                Some(arg) => arg,
-                None => return None,
-            };
-            let file = match arg.split_prefix("@") {
-                Some(arg) => self.cwd.join(&arg),
-                None => return Some(arg),
-            };
-
-            // According to gcc [1], @file means:
-            //
-            //     Read command-line options from file. The options read are
-            //     inserted in place of the original @file option. If file does
-            //     not exist, or cannot be read, then the option will be
-            //     treated literally, and not removed.
-            //
-            //     Options in file are separated by whitespace. A
-            //     whitespace character may be included in an option by
-            //     surrounding the entire option in either single or double
-            //     quotes. Any character (including a backslash) may be
-            //     included by prefixing the character to be included with
-            //     a backslash. The file may itself contain additional
-            //     @file options; any such options will be processed
-            //     recursively.
-            //
-            // So here we interpret any I/O errors as "just return this
-            // argument". Currently we don't implement handling of arguments
-            // with quotes, so if those are encountered we just pass the option
-            // through literally anyway.
-            //
-            // At this time we interpret all `@` arguments above as non
-            // cacheable, so if we fail to interpret this we'll just call the
-            // compiler anyway.
-            //
-            // [1]: https://gcc.gnu.org/onlinedocs/gcc/Overall-Options.html#Overall-Options
-            let mut contents = String::new();
-            let res = File::open(&file).and_then(|mut f| {
-                f.read_to_string(&mut contents)
-            });
-            if let Err(e) = res {
-                debug!("failed to read @-file `{}`: {}", file.display(), e);
-                return Some(arg)
-            }
-            if contents.contains('"') || contents.contains('\'') {
-                return Some(arg)
-            }
-            let new_args = contents.split_whitespace().collect::<Vec<_>>();
-            self.stack.extend(new_args.iter().rev().map(|s| s.into()));
-        }
-    }
-}
-
 #[cfg(test)]
 mod test {
     use std::fs::File;
@@ -468,6 +679,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -494,6 +707,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -520,6 +735,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -548,6 +765,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -574,6 +793,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -600,6 +821,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -626,6 +849,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -652,6 +877,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -678,6 +905,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -709,6 +938,30 @@ mod test {
     fn test_parse_arguments_too_many_inputs() {
         assert_eq!(CompilerArguments::CannotCache("multiple input files"),
                    _parse_arguments(&stringvec!["-c", "foo.c", "-o", "foo.o", "bar.c"]));
+        assert_eq!(CompilerArguments::CannotCache("multiple input files"),
+                   _parse_arguments(&stringvec!["-c", "foo.c", "-MD", "-MF", "foo.d", "bar.c"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_multiple_inputs() {
+        let args = stringvec!["-c", "src/foo.c", "-fabc", "-I", "include", "bar.cpp", "-MD"];
+        let parsed = match _parse_arguments(&args) {
+            CompilerArguments::Multiple(parsed) => parsed,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(2, parsed.len());
+        assert_eq!(Some("src/foo.c"), parsed[0].input.to_str());
+        assert_eq!(Language::C, parsed[0].language);
+        assert_map_contains!(parsed[0].outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(1, parsed[0].outputs.len());
+        assert_eq!(ovec!["-Iinclude", "-MD", "-MT", "foo.o"], parsed[0].preprocessor_args);
+        assert_eq!(ovec!["-fabc"], parsed[0].common_args);
+        assert_eq!(Some("bar.cpp"), parsed[1].input.to_str());
+        assert_eq!(Language::Cxx, parsed[1].language);
+        assert_map_contains!(parsed[1].outputs, ("obj", PathBuf::from("bar.o")));
+        assert_eq!(1, parsed[1].outputs.len());
+        assert_eq!(ovec!["-Iinclude", "-MD", "-MT", "bar.o"], parsed[1].preprocessor_args);
+        assert_eq!(ovec!["-fabc"], parsed[1].common_args);
     }
 
     #[test]
@@ -719,8 +972,30 @@ mod test {
 
     #[test]
     fn test_parse_arguments_pgo() {
-        assert_eq!(CompilerArguments::CannotCache("-fprofile-use"),
-                   _parse_arguments(&stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"]));
+        let f = TestFixture::new();
+        let parse = |args: Vec<OsString>| {
+            match parse_arguments(&args, f.tempdir.path(), &ARGS[..]) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        };
+        // `-fprofile-use=` can name the profile data file itself.
+        f.touch("foo.prof").unwrap();
+        let a = parse(ovec!["-c", "foo.c", "-fprofile-use=foo.prof", "-o", "foo.o"]);
+        assert_eq!(vec![f.tempdir.path().join("foo.prof")], a.extra_hash_files);
+        assert!(!a.hash_cwd);
+        // Or a directory, with the file named after the object's absolute path.
+        let mangled = f.tempdir.path().join("bar.gcda").to_string_lossy().replace('/', "#");
+        f.touch(&format!("prof/{}", mangled)).unwrap();
+        let a = parse(ovec!["-c", "bar.c", "-fprofile-use=prof", "-o", "bar.o"]);
+        assert_eq!(vec![f.tempdir.path().join("prof").join(&mangled)], a.extra_hash_files);
+        // Objects built to generate profiles use the data from a previous
+        // run too.
+        let a = parse(ovec!["-c", "foo.c", "-fprofile-generate", "-fprofile-use=foo.prof",
+                            "-o", "foo.o"]);
+        assert_eq!(vec![f.tempdir.path().join("foo.prof")], a.extra_hash_files);
+        assert!(a.hash_cwd);
+        assert_eq!(ovec!["-fprofile-generate", "-fprofile-use=foo.prof"], a.common_args);
     }
 
     #[test]
@@ -729,6 +1004,160 @@ mod test {
                    _parse_arguments(&stringvec!["-c", "foo.c", "@foo", "-o", "foo.o"]));
     }
 
+    #[test]
+    fn test_parse_arguments_profile_use() {
+        let f = TestFixture::new();
+        let parse = |args: Vec<OsString>| {
+            match parse_arguments(&args, f.tempdir.path(), &ARGS[..]) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        };
+        // Without profile data, there is nothing extra to hash.
+        let a = parse(ovec!["-c", "foo.c", "-fprofile-use", "-o", "out/foo.o"]);
+        assert!(a.extra_hash_files.is_empty());
+        assert_eq!(ovec!["-fprofile-use"], a.common_args);
+
+        f.touch("out/foo.gcda").unwrap();
+        let a = parse(ovec!["-c", "foo.c", "-fprofile-use", "-o", "out/foo.o"]);
+        assert_eq!(vec![f.tempdir.path().join("out/foo.gcda")], a.extra_hash_files);
+        assert!(!a.hash_cwd);
+
+        f.touch("profiles/out/foo.gcda").unwrap();
+        let a = parse(ovec!["-c", "foo.c", "-fprofile-use=profiles", "-o", "out/foo.o"]);
+        assert_eq!(vec![f.tempdir.path().join("profiles").join("out/foo.gcda")],
+                   a.extra_hash_files);
+        assert_eq!(ovec!["-fprofile-use=profiles"], a.common_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_profile_generate() {
+        for arg in &["-fprofile-generate", "-fprofile-generate=dir", "-fprofile-arcs", "--coverage"] {
+            match _parse_arguments(&stringvec!["-c", "foo.c", arg, "-o", "foo.o"]) {
+                CompilerArguments::Ok(a) => {
+                    assert!(a.hash_cwd);
+                    assert_eq!(ovec![arg], a.common_args);
+                }
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_precompiled_header() {
+        let f = TestFixture::new();
+        f.touch("pch.h.gch/a").unwrap();
+        f.touch("pch.h.gch/b").unwrap();
+        let args = ovec!["-c", "foo.c", "-include", "pch.h", "-o", "foo.o"];
+        match parse_arguments(&args, f.tempdir.path(), &ARGS[..]) {
+            CompilerArguments::Ok(a) => {
+                assert_eq!(vec![f.tempdir.path().join("pch.h.gch/a"),
+                                f.tempdir.path().join("pch.h.gch/b")],
+                           a.extra_hash_files);
+                assert_eq!(ovec!["-include", "pch.h"], a.preprocessor_args);
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+        // Generating a precompiled header.
+        match _parse_arguments(&stringvec!["-x", "c-header", "pch.h"]) {
+            CompilerArguments::Ok(a) => {
+                assert_eq!(Language::CHeader, a.language);
+                assert_map_contains!(a.outputs, ("obj", PathBuf::from("pch.h.gch")));
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_preprocess_precompiled_header() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        // A plain `#include "pch.h"` uses `pch.h.gch` too.
+        f.touch("pch.h.gch").unwrap();
+        let args = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let a = match parse_arguments(&args, f.tempdir.path(), &ARGS[..]) {
+            CompilerArguments::Ok(a) => a,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        next_command_calls(&creator, |args| {
+            let i = args.iter().position(|a| a == "-MF").unwrap();
+            let mut deps = File::create(&args[i + 1])?;
+            deps.write_all(b"foo.o: foo.c pch.h other.h\n")?;
+            Ok(MockChild::new(exit_status(0), "preprocessed", ""))
+        });
+        let p = GCC.preprocess(&creator, "gcc".as_ref(), &a, f.tempdir.path(), &[], &pool)
+            .wait().unwrap();
+        assert_eq!(b"preprocessed", &p.output.stdout[..]);
+        assert_eq!(vec![f.tempdir.path().join("foo.c"),
+                        f.tempdir.path().join("pch.h"),
+                        f.tempdir.path().join("other.h")],
+                   p.read_files);
+        assert_eq!(vec![f.tempdir.path().join("pch.h.gch")], p.dependencies);
+    }
+
+    #[test]
+    fn test_parse_dependencies() {
+        let deps = "foo.o: foo.c /usr/include/stdio.h \\\n  with\\ space.h \\\r\n  C:\\foo\\bar.h\n";
+        assert_eq!(vec![PathBuf::from("foo.c"),
+                        PathBuf::from("/usr/include/stdio.h"),
+                        PathBuf::from("with space.h"),
+                        PathBuf::from("C:\\foo\\bar.h")],
+                   parse_dependencies(deps));
+    }
+
+    #[test]
+    fn test_parse_arguments_assembler() {
+        match _parse_arguments(&stringvec!["-c", "foo.S", "-DFOO", "-o", "foo.o"]) {
+            CompilerArguments::Ok(a) => {
+                assert_eq!(Language::AssemblerWithCpp, a.language);
+                assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+                assert_eq!(ovec!["-DFOO"], a.preprocessor_args);
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+        match _parse_arguments(&stringvec!["-c", "foo.s"]) {
+            CompilerArguments::Ok(a) => {
+                assert_eq!(Language::Assembler, a.language);
+                assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+        match _parse_arguments(&stringvec!["-x", "assembler-with-cpp", "-c", "foo.asm", "-o", "foo.o"]) {
+            CompilerArguments::Ok(a) => assert_eq!(Language::AssemblerWithCpp, a.language),
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+        match _parse_arguments(&stringvec!["-x", "assembler", "-c", "foo.asm", "-o", "foo.o"]) {
+            CompilerArguments::Ok(a) => assert_eq!(Language::Assembler, a.language),
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_cuda() {
+        match _parse_arguments(&stringvec!["-c", "foo.cu", "-o", "foo.o"]) {
+            CompilerArguments::Ok(a) => {
+                assert_eq!(Language::Cuda, a.language);
+                assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+        for lang in &["cuda", "cu"] {
+            match _parse_arguments(&stringvec!["-x", *lang, "-c", "foo.cpp", "-o", "foo.o"]) {
+                CompilerArguments::Ok(a) => assert_eq!(Language::Cuda, a.language),
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+        assert_eq!("cuda", language_arg(Language::Cuda));
+        // Only the clang and nvcc front ends accept CUDA.
+        let args = ovec!["-c", "foo.cu", "-o", "foo.o"];
+        assert_eq!(CompilerArguments::CannotCache("unknown source language"),
+                   GCC.parse_arguments(&args, ".".as_ref()));
+        let args = ovec!["-x", "cu", "-c", "foo.cpp", "-o", "foo.o"];
+        assert_eq!(CompilerArguments::CannotCache("unknown source language"),
+                   GCC.parse_arguments(&args, ".".as_ref()));
+    }
+
     #[test]
     fn at_signs() {
         let td = TempDir::new("sccache").unwrap();
@@ -744,6 +1173,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match _parse_arguments(&[arg]) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -759,6 +1190,33 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn at_signs_quoted_nested() {
+        let td = TempDir::new("sccache").unwrap();
+        File::create(td.path().join("foo")).unwrap().write_all(b"\
+            -c 'foo bar.c' @baz -o foo.o\
+        ").unwrap();
+        File::create(td.path().join("baz")).unwrap().write_all(b"\
+            -DFOO=\"a b\" -Ifoo\\ bar\
+        ").unwrap();
+        let arg = format!("@{}", td.path().join("foo").display());
+        let ParsedArguments {
+            input,
+            outputs,
+            preprocessor_args,
+            common_args,
+            ..
+        } = match _parse_arguments(&[arg]) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("foo bar.c"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(1, outputs.len());
+        assert_eq!(ovec!["-DFOO=a b", "-Ifoo bar"], preprocessor_args);
+        assert!(common_args.is_empty());
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
@@ -770,6 +1228,8 @@ mod test {
             outputs: vec![("obj", "foo.o".into())].into_iter().collect(),
             preprocessor_args: vec!(),
             common_args: vec!(),
+            extra_hash_files: vec!(),
+            hash_cwd: false,
             msvc_show_includes: false,
         };
         let compiler = &f.bins[0];
@@ -779,7 +1239,8 @@ mod test {
                                      &compiler,
                                      &parsed_args,
                                      f.tempdir.path(),
-                                     &[]).wait().unwrap();
+                                     &[],
+                                     None).wait().unwrap();
         assert_eq!(Cacheable::Yes, cacheable);
         // Ensure that we ran all processes.
         assert_eq!(0, creator.lock().unwrap().children.len());
@@ -19,6 +19,9 @@ mod clang;
 mod compiler;
 mod gcc;
 mod msvc;
+mod normalize;
+mod nvcc;
 mod rust;
 
 pub use compiler::compiler::*;
+pub use compiler::normalize::Normalizer;
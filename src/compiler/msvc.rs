@@ -18,7 +18,7 @@ use ::compiler::{
     write_temp_file,
 };
 use compiler::args::*;
-use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments, Preprocessed};
 use local_encoding::{Encoding, Encoder};
 use log::LogLevel::{Debug, Trace};
 use futures::future::Future;
@@ -28,8 +28,9 @@ use mock_command::{
     RunCommand,
 };
 use std::collections::{HashMap,HashSet};
+use std::env;
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{
     self,
     BufWriter,
@@ -37,26 +38,55 @@ use std::io::{
 };
 use std::path::{Path, PathBuf};
 use std::process::{self,Stdio};
-use util::{run_input_output, OsStrExt};
+use util::{run_input_output, run_input_output_streaming, OsStrExt, OutputSink};
 
 use errors::*;
 
 /// A struct on which to implement `CCompilerImpl`.
 ///
-/// Needs a little bit of state just to persist `includes_prefix`.
+/// Needs a little bit of state just to persist `includes_prefix` and
+/// `pdb_mode`.
 #[derive(Debug, PartialEq, Clone)]
 pub struct MSVC {
     /// The prefix used in the output of `-showIncludes`.
     pub includes_prefix: String,
+    /// How to handle compilations writing debug info to a PDB with `-Zi`.
+    pub pdb_mode: PdbMode,
+}
+
+/// How compilations with `-Zi` are handled.
+///
+/// With `-Zi`, the compiler writes debug info to a program database that
+/// may be shared between several compilations, which can't be cached.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PdbMode {
+    /// Only cache compilations that have their own PDB, given with `-Fd`.
+    Shared,
+    /// Replace `-Zi` with `-Z7`, embedding debug info in the object file.
+    Embed,
+    /// Give each object file its own PDB, next to the object file.
+    PerObject,
+}
+
+impl PdbMode {
+    /// Get the mode configured with `SCCACHE_MSVC_PDB`, which can be
+    /// `embed` or `per-object`.
+    pub fn from_environment() -> PdbMode {
+        match env::var("SCCACHE_MSVC_PDB").as_ref().map(|s| s.as_str()) {
+            Ok("embed") => PdbMode::Embed,
+            Ok("per-object") => PdbMode::PerObject,
+            _ => PdbMode::Shared,
+        }
+    }
 }
 
 impl CCompilerImpl for MSVC {
     fn kind(&self) -> CCompilerKind { CCompilerKind::MSVC }
     fn parse_arguments(&self,
                        arguments: &[OsString],
-                       _cwd: &Path) -> CompilerArguments<ParsedArguments>
+                       cwd: &Path) -> CompilerArguments<ParsedArguments>
     {
-        parse_arguments(arguments)
+        parse_arguments(arguments, cwd, self.pdb_mode)
     }
 
     fn preprocess<T>(&self,
@@ -64,10 +94,19 @@ impl CCompilerImpl for MSVC {
                      executable: &Path,
                      parsed_args: &ParsedArguments,
                      cwd: &Path,
-                     env_vars: &[(OsString, OsString)])
-                     -> SFuture<process::Output> where T: CommandCreatorSync
+                     env_vars: &[(OsString, OsString)],
+                     _pool: &CpuPool)
+                     -> SFuture<Preprocessed> where T: CommandCreatorSync
     {
-        preprocess(creator, executable, parsed_args, cwd, env_vars, &self.includes_prefix)
+        let result = preprocess(creator, executable, parsed_args, cwd, env_vars,
+                                &self.includes_prefix);
+        Box::new(result.map(|output| {
+            Preprocessed {
+                output: output,
+                read_files: vec!(),
+                dependencies: vec!(),
+            }
+        }))
     }
 
     fn compile<T>(&self,
@@ -75,11 +114,12 @@ impl CCompilerImpl for MSVC {
                   executable: &Path,
                   parsed_args: &ParsedArguments,
                   cwd: &Path,
-                  env_vars: &[(OsString, OsString)])
+                  env_vars: &[(OsString, OsString)],
+                  output: Option<OutputSink>)
                   -> SFuture<(Cacheable, process::Output)>
         where T: CommandCreatorSync
     {
-        compile(creator, executable, parsed_args, cwd, env_vars)
+        compile(creator, executable, parsed_args, cwd, env_vars, self.pdb_mode, output)
     }
 }
 
@@ -221,9 +261,11 @@ static ARGS: [(ArgInfo, MSVCArgAttribute); 20] = [
     take_arg!("@", Path, Concatenated, TooHard),
 ];
 
-pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArguments> {
+pub fn parse_arguments(arguments: &[OsString],
+                       cwd: &Path,
+                       pdb_mode: PdbMode) -> CompilerArguments<ParsedArguments> {
     let mut output_arg = None;
-    let mut input_arg = None;
+    let mut input_args = vec!();
     let mut common_args = vec!();
     let mut compilation = false;
     let mut debug_info = false;
@@ -231,14 +273,15 @@ pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArgume
     let mut depfile = None;
     let mut show_includes = false;
 
-    // First convert all `/foo` arguments to `-foo` to accept both styles
-    let it = arguments.iter().map(|i| {
+    // Expand `@` response files, and convert all `/foo` arguments to `-foo`
+    // to accept both styles.
+    let it = ExpandResponseFiles::new(arguments, cwd, ResponseFileQuoting::Msvc).map(|i| {
         if let Some(arg) = i.split_prefix("/") {
             let mut dash = OsString::from("-");
             dash.push(&arg);
             dash
         } else {
-            i.clone()
+            i
         }
     });
 
@@ -267,13 +310,7 @@ pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArgume
             Some(PreprocessorArgument) => {}
             None => {
                 match item.arg {
-                    Argument::Raw(ref val) => {
-                        if input_arg.is_some() {
-                            // Can't cache compilations with multiple inputs.
-                            return CompilerArguments::CannotCache("multiple input files");
-                        }
-                        input_arg = Some(val.clone());
-                    }
+                    Argument::Raw(ref val) => input_args.push(val.clone()),
                     Argument::UnknownFlag(ref flag) => common_args.push(flag.clone()),
                     _ => unreachable!(),
                 }
@@ -290,47 +327,112 @@ pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArgume
     if !compilation {
         return CompilerArguments::NotCompilation;
     }
-    let (input, language) = match input_arg {
-        Some(i) => {
-            match Language::from_file_name(Path::new(&i)) {
-                Some(l) => (i.to_owned(), l),
-                None => return CompilerArguments::CannotCache("unknown source language"),
+    // We can't cache compilation without an input.
+    if input_args.is_empty() {
+        return CompilerArguments::CannotCache("no input file");
+    }
+    // `-Fo` may name a directory in which to put the object files.
+    let output_dir = match output_arg {
+        Some(ref o) if is_dir_arg(o) => Some(PathBuf::from(o)),
+        _ => None,
+    };
+    let multiple = input_args.len() > 1;
+    if multiple {
+        // Each input is compiled separately, so arguments naming a single
+        // output file can't be honored.
+        if (output_arg.is_some() && output_dir.is_none()) || depfile.is_some() {
+            return CompilerArguments::CannotCache("multiple input files");
+        }
+        // All the compilations would write to the same pdb.
+        if debug_info && pdb_mode == PdbMode::Shared {
+            return CompilerArguments::CannotCache("shared pdb");
+        }
+    }
+    let mut parsed = vec!();
+    for input in input_args {
+        let language = match Language::from_file_name(Path::new(&input)) {
+            Some(l @ Language::C) |
+            Some(l @ Language::Cxx) |
+            Some(l @ Language::ObjectiveC) |
+            Some(l @ Language::ObjectiveCxx) => l,
+            _ => return CompilerArguments::CannotCache("unknown source language"),
+        };
+        let mut outputs = HashMap::new();
+        match (output_dir.as_ref(), output_arg.as_ref()) {
+            (Some(dir), _) => {
+                outputs.insert("obj", dir.join(default_output(&input)));
+            }
+            (None, Some(o)) => {
+                outputs.insert("obj", PathBuf::from(o));
+            }
+            // If output file name is not given, use default naming rule
+            (None, None) => {
+                outputs.insert("obj", default_output(&input));
             }
         }
-        // We can't cache compilation without an input.
-        None => return CompilerArguments::CannotCache("no input file"),
-    };
-    let mut outputs = HashMap::new();
-    match output_arg {
-        // If output file name is not given, use default naming rule
-        None => {
-            outputs.insert("obj", Path::new(&input).with_extension("obj"));
-        },
-        Some(o) => {
-            outputs.insert("obj", PathBuf::from(o));
-        },
-    }
-    // -Fd is not taken into account unless -Zi is given
-    if debug_info {
-        match pdb {
-            Some(p) => outputs.insert("pdb", p),
-            None => {
-                // -Zi without -Fd defaults to vcxxx.pdb (where xxx depends on the
-                // MSVC version), and that's used for all compilations with the same
-                // working directory. We can't cache such a pdb.
-                return CompilerArguments::CannotCache("shared pdb");
+        let mut common_args = common_args.clone();
+        // -Fd is not taken into account unless -Zi is given
+        if debug_info {
+            match pdb_mode {
+                PdbMode::Shared => {
+                    match pdb {
+                        Some(ref p) => outputs.insert("pdb", p.clone()),
+                        None => {
+                            // -Zi without -Fd defaults to vcxxx.pdb (where xxx depends on the
+                            // MSVC version), and that's used for all compilations with the same
+                            // working directory. We can't cache such a pdb.
+                            return CompilerArguments::CannotCache("shared pdb");
+                        }
+                    };
+                }
+                PdbMode::Embed => {
+                    common_args = common_args.into_iter()
+                        .filter(|a| !a.starts_with("-Fd"))
+                        .map(|a| if a.as_os_str() == "-Zi" { "-Z7".into() } else { a })
+                        .collect();
+                }
+                PdbMode::PerObject => {
+                    let pdb = outputs["obj"].with_extension("pdb");
+                    common_args.retain(|a| !a.starts_with("-Fd"));
+                    let mut fd = OsString::from("-Fd");
+                    fd.push(&pdb);
+                    common_args.push(fd);
+                    outputs.insert("pdb", pdb);
+                }
             }
-        };
+        }
+        parsed.push(ParsedArguments {
+            input: input.into(),
+            language: language,
+            depfile: depfile.clone(),
+            outputs: outputs,
+            preprocessor_args: vec!(),
+            common_args: common_args,
+            extra_hash_files: vec!(),
+            hash_cwd: false,
+            msvc_show_includes: show_includes,
+        });
+    }
+    if multiple {
+        CompilerArguments::Multiple(parsed)
+    } else {
+        CompilerArguments::Ok(parsed.pop().unwrap())
+    }
+}
+
+/// Whether `arg`, the value of `-Fo`, names a directory rather than a file.
+fn is_dir_arg(arg: &OsString) -> bool {
+    let arg = arg.to_string_lossy();
+    arg.ends_with('/') || arg.ends_with('\\')
+}
+
+/// The object file the compiler writes for `input` when no `-Fo` is given.
+fn default_output(input: &OsString) -> PathBuf {
+    let input = Path::new(input);
+    match input.file_name() {
+        Some(name) => Path::new(name).with_extension("obj"),
+        None => input.with_extension("obj"),
     }
-    CompilerArguments::Ok(ParsedArguments {
-        input: input.into(),
-        language: language,
-        depfile: depfile.map(|d| d.into()),
-        outputs: outputs,
-        preprocessor_args: vec!(),
-        common_args: common_args,
-        msvc_show_includes: show_includes,
-    })
 }
 
 #[cfg(windows)]
@@ -454,7 +556,9 @@ fn compile<T>(creator: &T,
               executable: &Path,
               parsed_args: &ParsedArguments,
               cwd: &Path,
-              env_vars: &[(OsString, OsString)])
+              env_vars: &[(OsString, OsString)],
+              pdb_mode: PdbMode,
+              output: Option<OutputSink>)
               -> SFuture<(Cacheable, process::Output)>
     where T: CommandCreatorSync
 {
@@ -466,6 +570,15 @@ fn compile<T>(creator: &T,
         }
     };
 
+    // A per-object PDB is only used by this compilation, but the compiler
+    // would add to any stale one from a previous build.
+    if let (PdbMode::PerObject, Some(pdb)) = (pdb_mode, parsed_args.outputs.get("pdb")) {
+        let pdb = cwd.join(pdb);
+        if pdb.exists() {
+            ftry!(fs::remove_file(&pdb).chain_err(|| format!("failed to remove {:?}", pdb)));
+        }
+    }
+
     // See if this compilation will produce a PDB.
     let cacheable = parsed_args.outputs.get("pdb")
         .map_or(Cacheable::Yes, |pdb| {
@@ -490,7 +603,7 @@ fn compile<T>(creator: &T,
         .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
         .current_dir(cwd);
 
-    Box::new(run_input_output(cmd, None).map(move |output| {
+    Box::new(run_input_output_streaming(cmd, None, output).map(move |output| {
         (cacheable, output)
     }))
 }
@@ -506,6 +619,10 @@ mod test {
     use super::*;
     use test::utils::*;
 
+    fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArguments> {
+        super::parse_arguments(arguments, ".".as_ref(), PdbMode::Shared)
+    }
+
     #[test]
     fn test_detect_showincludes_prefix() {
         drop(env_logger::init());
@@ -534,6 +651,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -560,6 +679,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -586,6 +707,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -612,6 +735,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -638,6 +763,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -664,6 +791,8 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
+            hash_cwd: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -681,6 +810,31 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_expand_response_file() {
+        let f = TestFixture::new();
+        File::create(f.tempdir.path().join("args.rsp")).unwrap()
+            .write_all(b"/c \"foo bar.c\"\r\n/I\"C:\\Program Files\\inc\\\\\" @more.rsp").unwrap();
+        File::create(f.tempdir.path().join("more.rsp")).unwrap()
+            .write_all(b"/Fofoo.obj /DX=\\\"y\\\"").unwrap();
+        let args = ovec!["-nologo", "@args.rsp"];
+        let ParsedArguments {
+            input,
+            outputs,
+            preprocessor_args,
+            common_args,
+            ..
+        } = match super::parse_arguments(&args, f.tempdir.path(), PdbMode::Shared) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("foo bar.c"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.obj")));
+        assert_eq!(1, outputs.len());
+        assert!(preprocessor_args.is_empty());
+        assert_eq!(common_args, ovec!["-nologo", "-IC:\\Program Files\\inc\\", "-DX=\"y\""]);
+    }
+
     #[test]
     fn test_parse_arguments_empty_args() {
         assert_eq!(CompilerArguments::NotCompilation,
@@ -699,6 +853,31 @@ mod test {
                    parse_arguments(&ovec!["-c", "foo.c", "-Fofoo.obj", "bar.c"]));
     }
 
+    #[test]
+    fn test_parse_arguments_multiple_inputs() {
+        let parsed = match parse_arguments(&ovec!["-c", "foo.c", "-foo", "src/bar.cpp", "-Foobj/"]) {
+            CompilerArguments::Multiple(parsed) => parsed,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(2, parsed.len());
+        assert_eq!(Some("foo.c"), parsed[0].input.to_str());
+        assert_eq!(Language::C, parsed[0].language);
+        assert_map_contains!(parsed[0].outputs, ("obj", PathBuf::from("obj/foo.obj")));
+        assert_eq!(1, parsed[0].outputs.len());
+        assert_eq!(ovec!["-foo"], parsed[0].common_args);
+        assert_eq!(Some("src/bar.cpp"), parsed[1].input.to_str());
+        assert_eq!(Language::Cxx, parsed[1].language);
+        assert_map_contains!(parsed[1].outputs, ("obj", PathBuf::from("obj/bar.obj")));
+        assert_eq!(1, parsed[1].outputs.len());
+        assert_eq!(ovec!["-foo"], parsed[1].common_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_multiple_inputs_shared_pdb() {
+        assert_eq!(CompilerArguments::CannotCache("shared pdb"),
+                   parse_arguments(&ovec!["-c", "foo.c", "bar.c", "-Zi", "-Fdfoo.pdb"]));
+    }
+
     #[test]
     fn test_parse_arguments_unsupported() {
         assert_eq!(CompilerArguments::CannotCache("-FA"),
@@ -723,6 +902,44 @@ mod test {
                    parse_arguments(&ovec!["-c", "foo.c", "-Zi", "-Fofoo.obj"]));
     }
 
+    #[test]
+    fn test_parse_arguments_pdb_embed() {
+        let args = ovec!["-c", "foo.c", "bar.c", "-Zi", "-Fdshared.pdb", "-Foobj/", "-O2"];
+        match super::parse_arguments(&args, ".".as_ref(), PdbMode::Embed) {
+            CompilerArguments::Multiple(parsed) => {
+                for a in parsed {
+                    assert_eq!(ovec!["-Z7", "-O2"], a.common_args);
+                    assert!(!a.outputs.contains_key("pdb"));
+                }
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_pdb_per_object() {
+        let args = ovec!["-c", "foo.c", "bar.c", "-Zi", "-Foobj/"];
+        let parsed = match super::parse_arguments(&args, ".".as_ref(), PdbMode::PerObject) {
+            CompilerArguments::Multiple(parsed) => parsed,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(2, parsed.len());
+        assert_map_contains!(parsed[0].outputs,
+                             ("obj", PathBuf::from("obj/foo.obj")),
+                             ("pdb", PathBuf::from("obj/foo.pdb")));
+        assert_eq!(ovec!["-Zi", "-Fdobj/foo.pdb"], parsed[0].common_args);
+        assert_map_contains!(parsed[1].outputs,
+                             ("obj", PathBuf::from("obj/bar.obj")),
+                             ("pdb", PathBuf::from("obj/bar.pdb")));
+        assert_eq!(ovec!["-Zi", "-Fdobj/bar.pdb"], parsed[1].common_args);
+        // The PDB mode is reflected in the arguments, so in the hash key.
+        match super::parse_arguments(&ovec!["-c", "foo.c", "-Zi", "-Fdfoo.pdb"], ".".as_ref(),
+                                     PdbMode::Shared) {
+            CompilerArguments::Ok(a) => assert_neq!(parsed[0].common_args, a.common_args),
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
@@ -734,6 +951,8 @@ mod test {
             outputs: vec![("obj", "foo.obj".into())].into_iter().collect(),
             preprocessor_args: vec!(),
             common_args: vec!(),
+            extra_hash_files: vec!(),
+            hash_cwd: false,
             msvc_show_includes: false,
         };
         let compiler = &f.bins[0];
@@ -743,7 +962,9 @@ mod test {
                                      &compiler,
                                      &parsed_args,
                                      &f.tempdir.path(),
-                                     &[]).wait().unwrap();
+                                     &[],
+                                     PdbMode::Shared,
+                                     None).wait().unwrap();
         assert_eq!(Cacheable::Yes, cacheable);
         // Ensure that we ran all processes.
         assert_eq!(0, creator.lock().unwrap().children.len());
@@ -762,6 +983,8 @@ mod test {
                           ("pdb", pdb.into())].into_iter().collect(),
             preprocessor_args: vec!(),
             common_args: vec!(),
+            extra_hash_files: vec!(),
+            hash_cwd: false,
             msvc_show_includes: false,
         };
         let compiler = &f.bins[0];
@@ -771,9 +994,66 @@ mod test {
                                      &compiler,
                                      &parsed_args,
                                      f.tempdir.path(),
-                                     &[]).wait().unwrap();
+                                     &[],
+                                     PdbMode::Shared,
+                                     None).wait().unwrap();
         assert_eq!(Cacheable::No, cacheable);
         // Ensure that we ran all processes.
         assert_eq!(0, creator.lock().unwrap().children.len());
     }
+
+    #[test]
+    fn test_compile_pdb_per_object() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        // A PDB left over from a previous build.
+        let pdb = f.touch("foo.pdb").unwrap();
+        let parsed_args = match super::parse_arguments(&ovec!["-c", "foo.c", "-Zi", "-Fofoo.obj"],
+                                                       f.tempdir.path(),
+                                                       PdbMode::PerObject) {
+            CompilerArguments::Ok(a) => a,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let compiler = &f.bins[0];
+        next_command_calls(&creator, |args| {
+            assert_eq!(ovec!["-c", "foo.c", "-Fofoo.obj", "-Zi", "-Fdfoo.pdb"], args);
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let (cacheable, _) = compile(&creator,
+                                     &compiler,
+                                     &parsed_args,
+                                     f.tempdir.path(),
+                                     &[],
+                                     PdbMode::PerObject,
+                                     None).wait().unwrap();
+        assert_eq!(Cacheable::Yes, cacheable);
+        assert!(!pdb.exists());
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test]
+    fn test_compile_pdb_embed() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let parsed_args = match super::parse_arguments(&ovec!["-c", "foo.c", "-Zi", "-Fofoo.obj"],
+                                                       f.tempdir.path(),
+                                                       PdbMode::Embed) {
+            CompilerArguments::Ok(a) => a,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let compiler = &f.bins[0];
+        next_command_calls(&creator, |args| {
+            assert_eq!(ovec!["-c", "foo.c", "-Fofoo.obj", "-Z7"], args);
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let (cacheable, _) = compile(&creator,
+                                     &compiler,
+                                     &parsed_args,
+                                     f.tempdir.path(),
+                                     &[],
+                                     PdbMode::Embed,
+                                     None).wait().unwrap();
+        assert_eq!(Cacheable::Yes, cacheable);
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
 }
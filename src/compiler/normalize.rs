@@ -0,0 +1,450 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Normalizing compiler outputs before they're stored in the cache.
+//!
+//! Some outputs embed details of the machine that produced them, like the
+//! modification times of `ar` archive members, or the directory the compile
+//! ran in, which debug info records as `DW_AT_comp_dir`. Normalizing them
+//! means that outputs restored from the cache don't depend on which machine
+//! wrote the cache entry.
+
+use std::cmp;
+use std::path::Path;
+use util::Digest;
+
+use errors::*;
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_HEADER_LEN: usize = 60;
+const AR_HEADER_END: &[u8] = b"`\n";
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// The type of sections that take no space in the file, like `.bss`.
+const SHT_NOBITS: u64 = 8;
+/// The flag of sections whose contents are compressed.
+const SHF_COMPRESSED: u64 = 0x800;
+
+/// Ways to normalize compiler outputs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Normalizer {
+    /// Whether to zero the modification times and owners of `ar` archive
+    /// members.
+    ar_timestamps: bool,
+    /// The directory that absolute paths in debug info are made relative
+    /// to, without a trailing slash.
+    base_dir: Option<Vec<u8>>,
+}
+
+/// An ELF file being read.
+struct Elf<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    little_endian: bool,
+}
+
+impl Normalizer {
+    /// Create a normalizer that zeroes `ar` member timestamps if
+    /// `ar_timestamps` is set, and rewrites paths in debug info under the
+    /// absolute directory `base_dir` to be relative to it.
+    pub fn new(ar_timestamps: bool, base_dir: Option<&Path>) -> Result<Normalizer> {
+        let base_dir = match base_dir {
+            Some(dir) => {
+                let s = match dir.to_str() {
+                    Some(s) if dir.is_absolute() => s.trim_right_matches('/'),
+                    _ => bail!("base directory must be an absolute UTF-8 path: {:?}", dir),
+                };
+                if s.is_empty() {
+                    bail!("base directory must not be the root directory");
+                }
+                Some(s.as_bytes().to_vec())
+            }
+            None => None,
+        };
+        Ok(Normalizer {
+            ar_timestamps: ar_timestamps,
+            base_dir: base_dir,
+        })
+    }
+
+    /// Mix the settings of this normalizer into the hash key `key`, since
+    /// outputs stored with different settings aren't interchangeable.
+    pub fn hash_key(&self, key: &str) -> String {
+        let mut m = Digest::new();
+        m.update(key.as_bytes());
+        m.update(if self.ar_timestamps { b"ar_timestamps=1" } else { b"ar_timestamps=0" });
+        if let Some(ref dir) = self.base_dir {
+            m.update(b"base_dir=");
+            m.update(dir);
+        }
+        m.finish()
+    }
+
+    /// Normalize the output in `data`, in place. Outputs that aren't `ar`
+    /// archives or ELF files are left alone.
+    ///
+    /// Returns whether anything changed.
+    pub fn normalize(&self, data: &mut [u8]) -> bool {
+        if data.starts_with(AR_MAGIC) {
+            self.normalize_ar(data)
+        } else if data.starts_with(ELF_MAGIC) {
+            self.normalize_elf(data)
+        } else {
+            false
+        }
+    }
+
+    /// Normalize the `ar` archive in `data`, and the ELF files in it.
+    fn normalize_ar(&self, data: &mut [u8]) -> bool {
+        let members = match ar_members(data) {
+            Some(members) => members,
+            None => return false,
+        };
+        // The linker on macOS complains if the timestamp of the BSD symbol
+        // table isn't newer than the archive, so leave those archives be.
+        let has_bsd_symbols = members.iter().any(|&(header, start, _)| {
+            data[header..].starts_with(b"__.SYMDEF") ||
+                (data[header..].starts_with(b"#1/") && data[start..].starts_with(b"__.SYMDEF"))
+        });
+        let mut changed = false;
+        for &(header, start, end) in &members {
+            if self.ar_timestamps && !has_bsd_symbols {
+                // The modification time, owner and group fields.
+                for &(from, to) in &[(16, 28), (28, 34), (34, 40)] {
+                    changed |= zero_field(&mut data[header + from..header + to]);
+                }
+            }
+            changed |= self.normalize_elf(&mut data[start..end]);
+        }
+        changed
+    }
+
+    /// Make paths under the base directory in the debug info sections of
+    /// the ELF file in `data` relative to it.
+    ///
+    /// Paths are rewritten in place, so that no offsets change: the base
+    /// directory is replaced by a `.` followed by as many slashes as it
+    /// takes to fill its length.
+    fn normalize_elf(&self, data: &mut [u8]) -> bool {
+        let base_dir = match self.base_dir {
+            Some(ref dir) => dir,
+            None => return false,
+        };
+        let sections = match Elf::new(data).map(|elf| elf.debug_sections()) {
+            Some(Ok(sections)) => sections,
+            _ => return false,
+        };
+        let mut changed = false;
+        for (start, end) in sections {
+            changed |= relativize_paths(&mut data[start..end], base_dir);
+        }
+        changed
+    }
+}
+
+/// Get the header offset, and the start and end of the data, of each member
+/// of the `ar` archive in `data`, or `None` if it's malformed.
+fn ar_members(data: &[u8]) -> Option<Vec<(usize, usize, usize)>> {
+    let mut members = vec!();
+    let mut pos = AR_MAGIC.len();
+    while pos < data.len() {
+        let start = pos + AR_HEADER_LEN;
+        if start > data.len() || &data[pos + 58..start] != AR_HEADER_END {
+            return None;
+        }
+        let end = match parse_decimal(&data[pos + 48..pos + 58]) {
+            Some(size) if size <= data.len() - start => start + size,
+            _ => return None,
+        };
+        members.push((pos, start, end));
+        // Members are padded to an even offset.
+        pos = end + (end & 1);
+    }
+    Some(members)
+}
+
+/// Parse the space-padded decimal number in the `ar` header field `field`.
+fn parse_decimal(field: &[u8]) -> Option<usize> {
+    let digits = match field.iter().position(|&b| b == b' ') {
+        Some(len) => &field[..len],
+        None => field,
+    };
+    if digits.is_empty() || !digits.iter().all(|&b| b >= b'0' && b <= b'9') ||
+        !field[digits.len()..].iter().all(|&b| b == b' ') {
+        return None;
+    }
+    ::std::str::from_utf8(digits).ok().and_then(|s| s.parse().ok())
+}
+
+/// Set the `ar` header field `field` to zero, if it holds a number.
+///
+/// Returns whether it changed.
+fn zero_field(field: &mut [u8]) -> bool {
+    match parse_decimal(field) {
+        Some(0) | None => return false,
+        Some(_) => {}
+    }
+    field[0] = b'0';
+    for b in &mut field[1..] {
+        *b = b' ';
+    }
+    true
+}
+
+/// Make the absolute paths under `base_dir` in `data` relative to it, in
+/// place.
+///
+/// Returns whether anything changed.
+fn relativize_paths(data: &mut [u8], base_dir: &[u8]) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i + base_dir.len() < data.len() {
+        let starts_path = i == 0 || data[i - 1] <= b' ' || data[i - 1] == 0x7f;
+        let end = i + base_dir.len();
+        if starts_path && &data[i..end] == base_dir && (data[end] == b'/' || data[end] == 0) {
+            data[i] = b'.';
+            for b in &mut data[i + 1..end] {
+                *b = b'/';
+            }
+            changed = true;
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    changed
+}
+
+impl<'a> Elf<'a> {
+    /// Start reading the ELF file in `data`, if it has a header.
+    fn new(data: &'a [u8]) -> Option<Elf<'a>> {
+        if data.len() < 64 || !data.starts_with(ELF_MAGIC) {
+            return None;
+        }
+        let is_64 = match data[4] {
+            1 => false,
+            2 => true,
+            _ => return None,
+        };
+        let little_endian = match data[5] {
+            1 => true,
+            2 => false,
+            _ => return None,
+        };
+        Some(Elf {
+            data: data,
+            is_64: is_64,
+            little_endian: little_endian,
+        })
+    }
+
+    /// Read the `size` byte integer at `offset`.
+    fn read(&self, offset: usize, size: usize) -> Result<u64> {
+        if offset > self.data.len() || size > self.data.len() - offset {
+            bail!("truncated ELF file");
+        }
+        let bytes = &self.data[offset..offset + size];
+        let mut value = 0;
+        for i in 0..size {
+            let b = if self.little_endian { bytes[size - 1 - i] } else { bytes[i] };
+            value = (value << 8) | b as u64;
+        }
+        Ok(value)
+    }
+
+    /// Read the `size` byte integer at `offset`, as an offset into the file.
+    fn read_offset(&self, offset: usize, size: usize) -> Result<usize> {
+        let value = self.read(offset, size)?;
+        if value > self.data.len() as u64 {
+            bail!("ELF offset out of bounds");
+        }
+        Ok(value as usize)
+    }
+
+    /// Get the start and end of the uncompressed `.debug_*` sections.
+    fn debug_sections(&self) -> Result<Vec<(usize, usize)>> {
+        // The offsets of the fields of the ELF header that locate the
+        // section headers, then of the section header fields, and the size
+        // of the address-sized ones.
+        let (shoff, shentsize, shnum, shstrndx) = if self.is_64 {
+            (0x28, 0x3a, 0x3c, 0x3e)
+        } else {
+            (0x20, 0x2e, 0x30, 0x32)
+        };
+        let (sh_flags, sh_offset, sh_size, word) = if self.is_64 {
+            (0x08, 0x18, 0x20, 8)
+        } else {
+            (0x08, 0x10, 0x14, 4)
+        };
+        let shoff = self.read_offset(shoff, word)?;
+        let shentsize = self.read(shentsize, 2)? as usize;
+        let shnum = self.read(shnum, 2)? as usize;
+        let shstrndx = self.read(shstrndx, 2)? as usize;
+        if shstrndx >= shnum {
+            bail!("ELF section name table out of bounds");
+        }
+        let mut sections = vec!();
+        for i in 0..shnum {
+            let header = shoff + i * shentsize;
+            let name = self.read(header, 4)? as usize;
+            let kind = self.read(header + 4, 4)?;
+            let flags = self.read(header + sh_flags, word)?;
+            let start = self.read_offset(header + sh_offset, word)?;
+            let len = self.read(header + sh_size, word)?;
+            let len = if kind == SHT_NOBITS { 0 } else { len };
+            let end = match (start as u64).checked_add(len) {
+                Some(end) if end <= self.data.len() as u64 => end as usize,
+                _ => bail!("ELF section out of bounds"),
+            };
+            sections.push((name, flags, start, end));
+        }
+        let (_, _, names, names_end) = sections[shstrndx];
+        Ok(sections.iter().filter(|&&(name, flags, _, _)| {
+            let name = &self.data[cmp::min(names + name, names_end)..names_end];
+            flags & SHF_COMPRESSED == 0 && name.starts_with(b".debug_")
+        }).map(|&(_, _, start, end)| (start, end)).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ar_member(name: &str, mtime: &str, owner: &str, data: &[u8]) -> Vec<u8> {
+        let mut member = format!("{:16}{:12}{:6}{:6}{:8}{:10}`\n",
+                                 name, mtime, owner, owner, "100644", data.len())
+            .into_bytes();
+        member.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            member.push(b'\n');
+        }
+        member
+    }
+
+    #[test]
+    fn test_normalize_ar() {
+        let normalizer = Normalizer::new(true, None).unwrap();
+        let mut ar = AR_MAGIC.to_vec();
+        ar.extend(ar_member("//", "", "", b"a_long_file_name.o/\n"));
+        ar.extend(ar_member("foo.o/", "1500000000", "1000", b"foo"));
+        ar.extend(ar_member("/0", "1500000001", "1000", b"bar!"));
+        let mut expected = AR_MAGIC.to_vec();
+        expected.extend(ar_member("//", "", "", b"a_long_file_name.o/\n"));
+        expected.extend(ar_member("foo.o/", "0", "0", b"foo"));
+        expected.extend(ar_member("/0", "0", "0", b"bar!"));
+        assert!(normalizer.normalize(&mut ar));
+        assert_eq!(String::from_utf8_lossy(&expected), String::from_utf8_lossy(&ar));
+        // Normalizing again doesn't change anything.
+        assert!(!normalizer.normalize(&mut ar));
+        // Truncated archives are left alone.
+        let mut truncated = AR_MAGIC.to_vec();
+        truncated.extend(&ar_member("foo.o/", "1500000000", "1000", b"foo")[..50]);
+        let before = truncated.clone();
+        assert!(!normalizer.normalize(&mut truncated));
+        assert_eq!(before, truncated);
+    }
+
+    #[test]
+    fn test_hash_key() {
+        let keys = vec![Normalizer::new(false, None).unwrap().hash_key("abcd"),
+                        Normalizer::new(true, None).unwrap().hash_key("abcd"),
+                        Normalizer::new(false, Some(Path::new("/a"))).unwrap().hash_key("abcd"),
+                        Normalizer::new(false, Some(Path::new("/b"))).unwrap().hash_key("abcd"),
+                        Normalizer::new(true, Some(Path::new("/a"))).unwrap().hash_key("abcd"),
+                        Normalizer::new(false, None).unwrap().hash_key("efgh")];
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        // A trailing slash on the base directory doesn't matter.
+        assert_eq!(keys[2],
+                   Normalizer::new(false, Some(Path::new("/a/"))).unwrap().hash_key("abcd"));
+    }
+
+    #[test]
+    fn test_relativize_paths() {
+        let mut data = b"\0/base/dir\0/base/dir/foo.c\0/other/base/dir/x.c\0 /base/dirt\0/base/dir".to_vec();
+        assert!(relativize_paths(&mut data, b"/base/dir"));
+        assert_eq!(&b"\0.////////\0.////////foo.c\0/other/base/dir/x.c\0 /base/dirt\0/base/dir"[..],
+                   &data[..]);
+    }
+
+    /// Make a 64-bit little-endian ELF file with the sections in `sections`.
+    fn elf64(sections: &[(&str, u64, &[u8])]) -> Vec<u8> {
+        fn put(data: &mut Vec<u8>, offset: usize, size: usize, value: u64) {
+            for i in 0..size {
+                data[offset + i] = (value >> (i * 8)) as u8;
+            }
+        }
+        let mut data = vec![0; 64];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4] = 2;
+        data[5] = 1;
+        let mut names = b"\0.shstrtab\0".to_vec();
+        let mut headers = vec![(0, 0, 0, 0), (1, 0, 0, 0)];
+        for &(name, flags, contents) in sections {
+            headers.push((names.len(), flags, data.len(), contents.len()));
+            names.extend(name.as_bytes());
+            names.push(0);
+            data.extend(contents);
+        }
+        headers[1].2 = data.len();
+        headers[1].3 = names.len();
+        data.extend(names);
+        let shoff = data.len();
+        put(&mut data, 0x28, 8, shoff as u64);
+        put(&mut data, 0x3a, 2, 64);
+        put(&mut data, 0x3c, 2, headers.len() as u64);
+        put(&mut data, 0x3e, 2, 1);
+        for (i, &(name, flags, offset, size)) in headers.iter().enumerate() {
+            let header = shoff + i * 64;
+            data.extend(&[0; 64][..]);
+            put(&mut data, header, 4, name as u64);
+            put(&mut data, header + 4, 4, 1);
+            put(&mut data, header + 0x08, 8, flags);
+            put(&mut data, header + 0x18, 8, offset as u64);
+            put(&mut data, header + 0x20, 8, size as u64);
+        }
+        data
+    }
+
+    #[test]
+    fn test_normalize_elf() {
+        let normalizer = Normalizer::new(false, Some(Path::new("/base/dir/"))).unwrap();
+        let mut elf = elf64(&[(".debug_str", 0, b"GNU C\0/base/dir\0foo.c\0"),
+                              (".rodata", 0, b"/base/dir/foo.c\0"),
+                              (".debug_line", 0, b"\x01/base/dir/include\0"),
+                              (".debug_info", SHF_COMPRESSED, b"/base/dir\0")]);
+        let expected = elf64(&[(".debug_str", 0, b"GNU C\0.////////\0foo.c\0"),
+                               (".rodata", 0, b"/base/dir/foo.c\0"),
+                               (".debug_line", 0, b"\x01./////////include\0"),
+                               (".debug_info", SHF_COMPRESSED, b"/base/dir\0")]);
+        assert!(normalizer.normalize(&mut elf));
+        assert_eq!(expected, elf);
+        // The same goes for ELF files in archives.
+        let mut ar = AR_MAGIC.to_vec();
+        ar.extend(ar_member("foo.o/", "1500000000", "1000",
+                            &elf64(&[(".debug_str", 0, b"/base/dir\0")])));
+        let mut expected = AR_MAGIC.to_vec();
+        expected.extend(ar_member("foo.o/", "1500000000", "1000",
+                                  &elf64(&[(".debug_str", 0, b".////////\0")])));
+        assert!(normalizer.normalize(&mut ar));
+        assert_eq!(expected, ar);
+        // Other files are left alone.
+        let mut other = b"/base/dir/foo.c\0".to_vec();
+        assert!(!normalizer.normalize(&mut other));
+        assert!(Normalizer::new(false, Some(Path::new("base"))).is_err());
+        assert!(Normalizer::new(false, Some(Path::new("/"))).is_err());
+    }
+}
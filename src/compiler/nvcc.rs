@@ -0,0 +1,317 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ::compiler::{
+    gcc,
+    Cacheable,
+    CompilerArguments,
+};
+use compiler::args::*;
+use compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments, Preprocessed};
+use compiler::gcc::GCCArgAttribute::*;
+use futures::future::Future;
+use futures_cpupool::CpuPool;
+use log::LogLevel::Trace;
+use mock_command::{
+    CommandCreatorSync,
+    RunCommand,
+};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process;
+use util::{run_input_output, run_input_output_streaming, OutputSink};
+use which::which_in;
+
+use errors::*;
+
+/// A unit struct on which to implement `CCompilerImpl`.
+#[derive(Clone, Debug)]
+pub struct Nvcc;
+
+impl CCompilerImpl for Nvcc {
+    fn kind(&self) -> CCompilerKind { CCompilerKind::Nvcc }
+    fn parse_arguments(&self,
+                       arguments: &[OsString],
+                       cwd: &Path) -> CompilerArguments<ParsedArguments>
+    {
+        let supported = |args: &ParsedArguments| language_arg(args.language).is_some();
+        match gcc::parse_arguments(arguments, cwd, (&gcc::ARGS[..], &ARGS[..])) {
+            CompilerArguments::Ok(ref args) if !supported(args) => {
+                CompilerArguments::CannotCache("unknown source language")
+            }
+            CompilerArguments::Multiple(ref args) if !args.iter().all(supported) => {
+                CompilerArguments::CannotCache("unknown source language")
+            }
+            a => a,
+        }
+    }
+
+    fn preprocess<T>(&self,
+                     creator: &T,
+                     executable: &Path,
+                     parsed_args: &ParsedArguments,
+                     cwd: &Path,
+                     env_vars: &[(OsString, OsString)],
+                     _pool: &CpuPool)
+                     -> SFuture<Preprocessed> where T: CommandCreatorSync
+    {
+        trace!("preprocess");
+        let language = language_arg(parsed_args.language).expect("checked in parse_arguments");
+        let mut cmd = creator.clone().new_command_sync(executable);
+        cmd.arg("-x").arg(language)
+            .arg("-E")
+            .arg(&parsed_args.input)
+            .args(&parsed_args.preprocessor_args)
+            .args(&parsed_args.common_args)
+            .env_clear()
+            .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+            .current_dir(cwd);
+
+        if log_enabled!(Trace) {
+            trace!("preprocess: {:?}", cmd);
+        }
+        Box::new(run_input_output(cmd, None).map(|output| {
+            Preprocessed {
+                output: output,
+                read_files: vec!(),
+                dependencies: vec!(),
+            }
+        }))
+    }
+
+    fn scan_dependencies<T>(&self,
+                            _creator: &T,
+                            _executable: &Path,
+                            parsed_args: &ParsedArguments,
+                            cwd: &Path,
+                            env_vars: &[(OsString, OsString)])
+                            -> SFuture<Vec<PathBuf>> where T: CommandCreatorSync
+    {
+        // nvcc hands the host code to a host compiler, whose output also
+        // ends up in the object file. If it can't be found, `compile` says
+        // the result can't be cached.
+        let host_compilers = find_host_compilers(parsed_args, cwd, env_vars);
+        trace!("nvcc host compilers: {:?}", host_compilers);
+        f_ok(host_compilers)
+    }
+
+    fn compile<T>(&self,
+                  creator: &T,
+                  executable: &Path,
+                  parsed_args: &ParsedArguments,
+                  cwd: &Path,
+                  env_vars: &[(OsString, OsString)],
+                  output: Option<OutputSink>)
+                  -> SFuture<(Cacheable, process::Output)>
+        where T: CommandCreatorSync
+    {
+        trace!("compile");
+
+        let out_file = match parsed_args.outputs.get("obj") {
+            Some(obj) => obj,
+            None => {
+                return f_err("Missing object file output")
+            }
+        };
+
+        let language = language_arg(parsed_args.language).expect("checked in parse_arguments");
+        let mut attempt = creator.clone().new_command_sync(executable);
+        attempt.arg("-x").arg(language)
+            .arg("-c")
+            .arg(&parsed_args.input)
+            .arg("-o").arg(&out_file)
+            .args(&parsed_args.preprocessor_args)
+            .args(&parsed_args.common_args)
+            .env_clear()
+            .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+            .current_dir(&cwd);
+        // Without a host compiler to hash, there's no telling what nvcc
+        // compiled the host code with.
+        let cacheable = if find_host_compilers(parsed_args, cwd, env_vars).is_empty() {
+            debug!("Couldn't find the nvcc host compiler, not caching");
+            Cacheable::No
+        } else {
+            Cacheable::Yes
+        };
+        Box::new(run_input_output_streaming(attempt, None, output).map(move |output| {
+            (cacheable, output)
+        }))
+    }
+}
+
+/// The host compilers nvcc uses when `-ccbin` isn't given.
+#[cfg(windows)]
+const DEFAULT_HOST_COMPILERS: &[&str] = &["cl.exe"];
+#[cfg(not(windows))]
+const DEFAULT_HOST_COMPILERS: &[&str] = &["gcc", "g++"];
+
+/// The value to pass to `-x` for `language`, if nvcc supports it.
+fn language_arg(language: Language) -> Option<&'static str> {
+    match language {
+        Language::C => Some("c"),
+        Language::Cxx => Some("c++"),
+        Language::Cuda => Some("cu"),
+        _ => None,
+    }
+}
+
+/// Find the host compilers that nvcc may run for `parsed_args`, in `cwd`
+/// with `env_vars`.
+fn find_host_compilers(parsed_args: &ParsedArguments,
+                       cwd: &Path,
+                       env_vars: &[(OsString, OsString)])
+                       -> Vec<PathBuf> {
+    let path = env_vars.iter()
+        .find(|&&(ref k, _)| k == "PATH")
+        .map(|&(_, ref v)| v.clone());
+    let candidates = match host_compiler_arg(&parsed_args.common_args) {
+        Some(bin) => {
+            let dir = cwd.join(&bin);
+            if dir.is_dir() {
+                DEFAULT_HOST_COMPILERS.iter().map(|c| dir.join(c)).collect()
+            } else {
+                vec![bin]
+            }
+        }
+        None => DEFAULT_HOST_COMPILERS.iter().map(PathBuf::from).collect(),
+    };
+    candidates.into_iter()
+        .filter_map(|c| which_in(c, path.as_ref(), cwd).ok())
+        .collect()
+}
+
+/// Get the host compiler given with `-ccbin` in `args`.
+fn host_compiler_arg(args: &[OsString]) -> Option<PathBuf> {
+    args.windows(2)
+        .rev()
+        .find(|w| &w[0] == "-ccbin" || &w[0] == "--compiler-bindir")
+        .map(|w| PathBuf::from(&w[1]))
+}
+
+/// nvcc arguments that take a separate value, in addition to those it
+/// shares with gcc.
+static ARGS: [(ArgInfo, gcc::GCCArgAttribute); 14] = [
+    take_arg!("--compiler-bindir", Path, Separated, PassThrough),
+    take_arg!("--compiler-options", String, Separated, PassThrough),
+    take_arg!("--generate-code", String, Separated, PassThrough),
+    take_arg!("--gpu-architecture", String, Separated, PassThrough),
+    take_arg!("--gpu-code", String, Separated, PassThrough),
+    take_arg!("--linker-options", String, Separated, PassThrough),
+    take_arg!("--ptxas-options", String, Separated, PassThrough),
+    take_arg!("--relocatable-device-code", String, Separated, PassThrough),
+    take_arg!("-Xcompiler", String, Separated, PassThrough),
+    take_arg!("-Xptxas", String, Separated, PassThrough),
+    take_arg!("-ccbin", Path, Separated, PassThrough),
+    take_arg!("-code", String, Separated, PassThrough),
+    take_arg!("-gencode", String, Separated, PassThrough),
+    take_arg!("-rdc", String, Separated, PassThrough),
+];
+
+#[cfg(test)]
+mod test {
+    use compiler::*;
+    use futures::Future;
+    use mock_command::*;
+    use std::path::PathBuf;
+    use super::*;
+    use test::utils::*;
+
+    fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments> {
+        let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        Nvcc.parse_arguments(&arguments, ".".as_ref())
+    }
+
+    macro_rules! parses {
+        ( $( $s:expr ),* ) => {
+            match _parse_arguments(&[ $( $s.to_string(), )* ]) {
+                CompilerArguments::Ok(a) => a,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_simple() {
+        let a = parses!("-c", "foo.cu", "-o", "foo.o");
+        assert_eq!(Some("foo.cu"), a.input.to_str());
+        assert_eq!(Language::Cuda, a.language);
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(1, a.outputs.len());
+        assert!(a.preprocessor_args.is_empty());
+        assert!(a.common_args.is_empty());
+    }
+
+    #[test]
+    fn test_parse_arguments_values() {
+        let a = parses!("-x", "cu", "-c", "kernels.cpp", "-arch", "sm_50",
+                        "-gencode", "arch=compute_60,code=sm_60",
+                        "--compiler-options", "-fPIC,-Wall", "-Xcompiler", "-O3",
+                        "-ccbin", "/usr/bin/g++-6", "-I", "include", "-o", "kernels.o");
+        assert_eq!(Some("kernels.cpp"), a.input.to_str());
+        assert_eq!(Language::Cuda, a.language);
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("kernels.o")));
+        assert_eq!(ovec!["-Iinclude"], a.preprocessor_args);
+        assert_eq!(ovec!["-arch", "sm_50", "-gencode", "arch=compute_60,code=sm_60",
+                         "--compiler-options", "-fPIC,-Wall", "-Xcompiler", "-O3",
+                         "-ccbin", "/usr/bin/g++-6"],
+                   a.common_args);
+    }
+
+    #[test]
+    fn test_parse_arguments_unsupported_language() {
+        assert_eq!(CompilerArguments::CannotCache("unknown source language"),
+                   _parse_arguments(&stringvec!["-c", "foo.m", "-o", "foo.o"]));
+    }
+
+    #[test]
+    fn test_preprocess() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let a = parses!("-c", "foo.cu", "-gencode", "arch=compute_60,code=sm_60", "-o", "foo.o");
+        next_command_calls(&creator, |args| {
+            assert_eq!(ovec!["-x", "cu", "-E", "foo.cu", "-gencode", "arch=compute_60,code=sm_60"],
+                       args);
+            Ok(MockChild::new(exit_status(0), "preprocessed", ""))
+        });
+        let p = Nvcc.preprocess(&creator, "nvcc".as_ref(), &a, f.tempdir.path(), &[],
+                                &CpuPool::new(1))
+            .wait().unwrap();
+        assert_eq!(b"preprocessed", &p.output.stdout[..]);
+        assert!(p.dependencies.is_empty());
+    }
+
+    #[test]
+    fn test_scan_dependencies_host_compiler() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        f.mk_bin("host-g++").unwrap();
+        let a = parses!("-c", "foo.cu", "-ccbin", "host-g++", "-o", "foo.o");
+        let path = vec![(OsString::from("PATH"), f.tempdir.path().as_os_str().to_owned())];
+        let deps = Nvcc.scan_dependencies(&creator, "nvcc".as_ref(), &a, f.tempdir.path(), &path)
+            .wait().unwrap();
+        assert_eq!(1, deps.len());
+        assert_eq!(Some("host-g++".as_ref()), deps[0].file_name());
+        // Without a host compiler, there's no way to know what nvcc will
+        // do, so the compile isn't cached.
+        let a = parses!("-c", "foo.cu", "-ccbin", "missing", "-o", "foo.o");
+        let deps = Nvcc.scan_dependencies(&creator, "nvcc".as_ref(), &a, f.tempdir.path(), &path)
+            .wait().unwrap();
+        assert!(deps.is_empty());
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
+        let (cacheable, _) = Nvcc.compile(&creator, "nvcc".as_ref(), &a, f.tempdir.path(), &path,
+                                          None)
+            .wait().unwrap();
+        assert_eq!(Cacheable::No, cacheable);
+    }
+}
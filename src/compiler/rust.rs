@@ -15,10 +15,11 @@
 use compiler::{Cacheable, Compiler, CompilerArguments, CompilerHasher, CompilerKind, Compilation,
                HashResult};
 use compiler::args::*;
-use futures::{Future, future};
+use futures::Future;
 use futures_cpupool::CpuPool;
 use log::LogLevel::Trace;
 use mock_command::{CommandCreatorSync, RunCommand};
+use sandbox::{SandboxCompiler, SandboxInputs};
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::env::consts::DLL_EXTENSION;
@@ -32,7 +33,8 @@ use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
 use std::time::Instant;
 use tempdir::TempDir;
-use util::{fmt_duration_as_secs, run_input_output, Digest};
+use util::{fmt_duration_as_secs, hash_all, run_input_output, run_input_output_streaming, Digest};
+use util::OutputSink;
 use util::{HashToDigest, OsStrExt};
 
 use errors::*;
@@ -91,6 +93,9 @@ pub struct RustCompilation {
     outputs: HashMap<String, PathBuf>,
     /// The crate name being compiled.
     crate_name: String,
+    /// The files whose contents went into the hash key: the source files
+    /// from dep-info, the extern crates and the static libraries.
+    inputs: Vec<PathBuf>,
 }
 
 lazy_static! {
@@ -104,21 +109,8 @@ lazy_static! {
 /// Version number for cache key.
 const CACHE_VERSION: &[u8] = b"2";
 
-/// Calculate the SHA-1 digest of each file in `files` on background threads
-/// in `pool`.
-fn hash_all(files: Vec<String>, pool: &CpuPool) -> SFuture<Vec<String>>
-{
-    let start = Instant::now();
-    let count = files.len();
-    let pool = pool.clone();
-    Box::new(future::join_all(files.into_iter().map(move |f| Digest::file(f, &pool)))
-             .map(move |hashes| {
-                 trace!("Hashed {} files in {}", count, fmt_duration_as_secs(&start.elapsed()));
-                 hashes
-             }))
-}
-
-/// Calculate SHA-1 digests for all source files listed in rustc's dep-info output.
+/// Calculate SHA-1 digests for all source files listed in rustc's dep-info output,
+/// returning the files along with their digests.
 fn hash_source_files<T>(creator: &T,
                         crate_name: &str,
                         executable: &Path,
@@ -126,7 +118,7 @@ fn hash_source_files<T>(creator: &T,
                         cwd: &Path,
                         env_vars: &[(OsString, OsString)],
                         pool: &CpuPool)
-                        -> SFuture<Vec<String>>
+                        -> SFuture<(Vec<String>, Vec<String>)>
     where T: CommandCreatorSync,
 {
     let start = Instant::now();
@@ -159,7 +151,7 @@ fn hash_source_files<T>(creator: &T,
                    files.len(), fmt_duration_as_secs(&start.elapsed()));
             // Just to make sure we capture temp_dir.
             drop(temp_dir);
-            hash_all(files, &pool)
+            hash_all(files.clone(), &pool).map(move |hashes| (files, hashes))
         }))
     }))
 }
@@ -576,13 +568,14 @@ impl<T> CompilerHasher<T> for RustHasher
         // Hash the contents of the externs listed on the commandline.
         let cwp = Path::new(cwd);
         trace!("[{}]: hashing {} externs", crate_name, externs.len());
+        let externs = externs.iter().map(|e| cwp.join(e)).collect::<Vec<_>>();
         let extern_hashes = hash_all(externs.iter()
-                                     .map(|e| cwp.join(e).to_string_lossy().into_owned())
+                                     .map(|e| e.to_string_lossy().into_owned())
                                      .collect(),
                                      &pool);
         // Hash the contents of the staticlibs listed on the commandline.
         trace!("[{}]: hashing {} staticlibs", crate_name, staticlibs.len());
-        let staticlib_hashes = hash_all(staticlibs.into_iter()
+        let staticlib_hashes = hash_all(staticlibs.iter()
                                         .map(|s| s.to_string_lossy().into_owned())
                                         .collect(),
                                         &pool);
@@ -590,7 +583,7 @@ impl<T> CompilerHasher<T> for RustHasher
         let cwd = cwd.to_owned();
         let env_vars = env_vars.to_vec();
         let hashes = source_hashes.join3(extern_hashes, staticlib_hashes);
-        Box::new(hashes.and_then(move |(source_hashes, extern_hashes, staticlib_hashes)|
+        Box::new(hashes.and_then(move |((source_files, source_hashes), extern_hashes, staticlib_hashes)|
                                         -> SFuture<_> {
             // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
             let mut m = Digest::new();
@@ -644,6 +637,11 @@ impl<T> CompilerHasher<T> for RustHasher
                     val.hash(&mut HashToDigest { digest: &mut m });
                 }
             }
+            let inputs = source_files.into_iter()
+                .map(PathBuf::from)
+                .chain(externs)
+                .chain(staticlibs)
+                .collect();
             // Turn arguments into a simple Vec<String> for compilation.
             let arguments = arguments.into_iter()
                 .flat_map(|(arg, val)| Some(arg).into_iter().chain(val))
@@ -668,6 +666,7 @@ impl<T> CompilerHasher<T> for RustHasher
                         arguments: arguments,
                         outputs: outputs,
                         crate_name: crate_name,
+                        inputs: inputs,
                     }),
                 }
             }))
@@ -678,6 +677,10 @@ impl<T> CompilerHasher<T> for RustHasher
         Cow::Borrowed(&self.parsed_args.crate_name)
     }
 
+    fn language(&self) -> &'static str {
+        "rust"
+    }
+
     fn box_clone(&self) -> Box<CompilerHasher<T>> {
         Box::new((*self).clone())
     }
@@ -689,7 +692,8 @@ impl<T> Compilation<T> for RustCompilation
     fn compile(self: Box<Self>,
                creator: &T,
                cwd: &Path,
-               env_vars: &[(OsString, OsString)])
+               env_vars: &[(OsString, OsString)],
+               output: Option<OutputSink>)
                -> SFuture<(Cacheable, process::Output)>
     {
         let me = *self;
@@ -701,7 +705,7 @@ impl<T> Compilation<T> for RustCompilation
             .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
             .current_dir(cwd);
         trace!("compile: {:?}", cmd);
-        Box::new(run_input_output(cmd, None).map(|output| {
+        Box::new(run_input_output_streaming(cmd, None, output).map(|output| {
             (Cacheable::Yes, output)
         }))
     }
@@ -709,6 +713,19 @@ impl<T> Compilation<T> for RustCompilation
     fn outputs<'a>(&'a self) -> Box<Iterator<Item=(&'a str, &'a Path)> + 'a> {
         Box::new(self.outputs.iter().map(|(k, v)| (k.as_str(), &**v)))
     }
+
+    // Rust compiles don't run on build workers: a job only carries one
+    // input, but rustc reads every source file of the crate along with the
+    // rlibs of its dependencies, and procedural macros and plugins that
+    // run on the host. Jobs would need to send all of those, and the
+    // toolchain would need the whole sysroot, so `dist_job` is left as is.
+
+    fn sandbox_inputs(&self, _cwd: &Path) -> Option<SandboxInputs> {
+        Some(SandboxInputs {
+            compiler: SandboxCompiler::Rust(self.executable.clone()),
+            paths: self.inputs.clone(),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -1022,6 +1039,13 @@ c:/foo/bar.rs:
         let mut out = res.compilation.outputs().map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
         out.sort();
         assert_eq!(out, vec!["foo.a", "foo.rlib"]);
+        let inputs = res.compilation.sandbox_inputs(f.tempdir.path()).unwrap();
+        assert_eq!(SandboxCompiler::Rust(PathBuf::from("rustc")), inputs.compiler);
+        assert_eq!(vec![f.tempdir.path().join("bar.rs"),
+                        f.tempdir.path().join("foo.rs"),
+                        f.tempdir.path().join("bar.rlib"),
+                        f.tempdir.path().join("libbaz.a")],
+                   inputs.paths);
     }
 
     fn hash_key<'a, F>(args: &[OsString], env_vars: &[(OsString, OsString)], pre_func: F)
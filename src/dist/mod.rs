@@ -0,0 +1,530 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Distributed compilation.
+//!
+//! On a cache miss, the server can hand a compile to a build worker
+//! instead of running it locally. A scheduler keeps track of the workers
+//! and picks one for each compile. The server sends that worker the
+//! compiler's toolchain, if the worker doesn't have it yet, along with
+//! the inputs of the compile, and gets the outputs back. If anything goes
+//! wrong, the server compiles locally instead.
+//!
+//! The scheduler and workers are run by the `sccache-dist` binary. They
+//! all talk with length-prefixed bincode messages over TCP, like the
+//! client and server do. Each request starts with a token shared by
+//! everything in the cluster, and connections without it are dropped.
+
+use bincode;
+use byteorder::{ByteOrder, BigEndian};
+use futures_cpupool::CpuPool;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tar::Archive;
+use tempfile::NamedTempFile;
+
+use errors::*;
+
+pub mod pkg;
+mod scheduler;
+mod worker;
+
+use self::pkg::{Toolchain, ToolchainCache};
+pub use self::scheduler::Scheduler;
+pub use self::worker::Worker;
+
+/// How often workers report their status to the scheduler, in seconds.
+const HEARTBEAT_INTERVAL_SECS: u64 = 5;
+
+/// How long to wait for the scheduler, or for a worker to answer anything
+/// but a job, in seconds.
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// How long to wait for a worker to run a job, in seconds.
+const JOB_TIMEOUT_SECS: u64 = 600;
+
+/// The largest message, in bytes, which is mostly for toolchain archives.
+const MAX_MESSAGE_SIZE: usize = 512 * 1024 * 1024;
+
+/// The largest token, in bytes. Tokens are read before anything else, so
+/// this is all that someone without the token can make us allocate.
+const MAX_TOKEN_SIZE: usize = 1024;
+
+/// How many jobs the server runs on workers at once.
+const MAX_REMOTE_JOBS: usize = 32;
+
+/// The status of a worker, as it reports it to the scheduler.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkerStatus {
+    /// The address the worker accepts jobs on. If the IP address is
+    /// unspecified, the scheduler uses the one the status came from.
+    pub addr: SocketAddr,
+    /// How many jobs the worker runs at once.
+    pub slots: usize,
+    /// How many jobs the worker is running.
+    pub running: usize,
+}
+
+/// A request to the scheduler.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SchedulerRequest {
+    /// A worker reporting its status. Workers that stop doing so are
+    /// forgotten about.
+    Heartbeat(WorkerStatus),
+    /// Ask for a worker to run a job on.
+    AllocJob,
+}
+
+/// A response from the scheduler.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SchedulerResponse {
+    /// The heartbeat was recorded.
+    HeartbeatOk,
+    /// The worker to run a job on, if any has a free slot.
+    JobAlloc(Option<SocketAddr>),
+}
+
+/// A request to a worker.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WorkerRequest {
+    /// Ask whether the worker has the toolchain with this id.
+    HasToolchain(String),
+    /// Send the worker a toolchain, by id and archive.
+    SubmitToolchain(String, Vec<u8>),
+    /// Run a job.
+    RunJob(Job),
+}
+
+/// A response from a worker.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum WorkerResponse {
+    /// Whether the worker has the toolchain.
+    HasToolchain(bool),
+    /// The toolchain was stored.
+    ToolchainSubmitted,
+    /// The job ran.
+    JobComplete(JobResult),
+    /// The request failed, for this reason.
+    Error(String),
+}
+
+/// A compile to run on a worker.
+///
+/// All paths are relative, to the toolchain for `executable` and to the
+/// directory the job runs in otherwise.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Job {
+    /// The id of the toolchain to run the compile with.
+    pub toolchain: String,
+    /// The compiler to run.
+    pub executable: PathBuf,
+    /// The compiler's arguments.
+    pub arguments: Vec<OsString>,
+    /// The compiler's environment.
+    pub env_vars: Vec<(OsString, OsString)>,
+    /// Files to create before running the compiler, with their contents.
+    pub inputs: Vec<(PathBuf, Vec<u8>)>,
+    /// Files to send back after running the compiler.
+    pub outputs: Vec<PathBuf>,
+}
+
+/// The result of running a `Job`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobResult {
+    /// The compiler's exit code, if it wasn't killed by a signal.
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The outputs the compiler produced, with their contents.
+    pub outputs: Vec<(PathBuf, Vec<u8>)>,
+}
+
+/// Write `message` to `stream`, prefixed with its length.
+fn write_message<W: Write, T: Serialize>(stream: &mut W, message: &T) -> Result<()> {
+    let bytes = bincode::serialize(message, bincode::Infinite)?;
+    if bytes.len() > MAX_MESSAGE_SIZE {
+        bail!("message of {} bytes is too large to send", bytes.len());
+    }
+    let mut len = [0; 4];
+    BigEndian::write_u32(&mut len, bytes.len() as u32);
+    stream.write_all(&len)?;
+    stream.write_all(&bytes)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Get the token that the scheduler, workers and servers share, from
+/// `SCCACHE_DIST_TOKEN`.
+pub fn get_token() -> Result<String> {
+    match env::var("SCCACHE_DIST_TOKEN") {
+        Ok(ref token) if !token.is_empty() => Ok(token.clone()),
+        _ => bail!("SCCACHE_DIST_TOKEN must be set to the token of the build cluster"),
+    }
+}
+
+/// Read a request sent by `request` from `stream`, checking that it came
+/// with `token`.
+fn read_request<R: Read, T: DeserializeOwned>(stream: &mut R, token: &str) -> Result<T> {
+    let sent: String = read_message(stream, MAX_TOKEN_SIZE)?;
+    if !tokens_match(sent.as_bytes(), token.as_bytes()) {
+        bail!("request had an invalid token");
+    }
+    read_message(stream, MAX_MESSAGE_SIZE)
+}
+
+/// Compare tokens in time that doesn't depend on where they differ.
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Read a message written by `write_message` from `stream`, if it's no
+/// larger than `max` bytes.
+fn read_message<R: Read, T: DeserializeOwned>(stream: &mut R, max: usize) -> Result<T> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = BigEndian::read_u32(&len) as usize;
+    if len > max {
+        bail!("message of {} bytes is larger than the limit of {}", len, max);
+    }
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data)?;
+    Ok(bincode::deserialize(&data)?)
+}
+
+/// Send `request` with `token` to `addr` on a new connection, and read the
+/// response, waiting at most `timeout` for each.
+fn request<Req, Resp>(addr: &SocketAddr,
+                      token: &str,
+                      request: &Req,
+                      timeout: Duration)
+                      -> Result<Resp>
+    where Req: Serialize,
+          Resp: DeserializeOwned,
+{
+    let mut stream = TcpStream::connect_timeout(addr, timeout)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write_message(&mut stream, &token)?;
+    write_message(&mut stream, request)?;
+    read_message(&mut stream, MAX_MESSAGE_SIZE)
+}
+
+/// Check that `path` is relative and stays below the directory it's
+/// relative to, since it came from elsewhere.
+fn check_relative(path: &Path) -> Result<()> {
+    let ok = path.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if !ok || path.as_os_str().is_empty() {
+        bail!("invalid path: {:?}", path);
+    }
+    Ok(())
+}
+
+/// Unpack the toolchain `archive` into `dir`.
+fn unpack_toolchain(archive: &[u8], dir: &Path) -> Result<()> {
+    Archive::new(archive).unpack(dir).chain_err(|| "failed to unpack toolchain archive")
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> process::ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    process::ExitStatus::from_raw(code << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> process::ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    process::ExitStatus::from_raw(code as u32)
+}
+
+/// A compile, as the server wants it run on a worker.
+#[derive(Debug)]
+pub struct CompileJob {
+    /// The local C compiler, which gets packaged up for the worker.
+    pub executable: PathBuf,
+    /// The compiler's arguments.
+    pub arguments: Vec<OsString>,
+    /// The compiler's environment.
+    pub env_vars: Vec<(OsString, OsString)>,
+    /// Files to create on the worker, with their contents, relative to the
+    /// directory the compile runs in.
+    pub inputs: Vec<(PathBuf, Vec<u8>)>,
+    /// The outputs of the compile, as paths on the worker relative to the
+    /// directory the compile runs in, and the local paths to write them
+    /// to.
+    pub outputs: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Packaged toolchains, by compiler path and modification time.
+type Toolchains = HashMap<(PathBuf, SystemTime), Arc<Toolchain>>;
+
+/// Runs compiles on workers, for the server.
+pub struct Client {
+    /// The address of the scheduler.
+    scheduler: SocketAddr,
+    /// The token of the build cluster.
+    token: Arc<String>,
+    /// Toolchains used so far.
+    toolchains: Arc<Mutex<Toolchains>>,
+    /// Where toolchains are packaged.
+    cache: Arc<ToolchainCache>,
+    /// The pool to talk to the scheduler and workers on.
+    pool: CpuPool,
+}
+
+impl Client {
+    /// Create a client using the scheduler at `scheduler`, with the
+    /// cluster's `token`, which keeps the toolchains it packages in
+    /// `toolchain_dir`.
+    pub fn new(scheduler: SocketAddr, token: String, toolchain_dir: &Path) -> Result<Client> {
+        Ok(Client {
+            scheduler: scheduler,
+            token: Arc::new(token),
+            toolchains: Arc::new(Mutex::new(HashMap::new())),
+            cache: Arc::new(ToolchainCache::new(toolchain_dir)?),
+            pool: CpuPool::new(MAX_REMOTE_JOBS),
+        })
+    }
+
+    /// Run `job` on a worker, writing its outputs to their local paths.
+    ///
+    /// Compiles that fail on the worker are errors, rather than failed
+    /// compiles, since the worker's environment could be to blame.
+    pub fn compile(&self, job: CompileJob) -> SFuture<process::Output> {
+        let scheduler = self.scheduler;
+        let token = self.token.clone();
+        let toolchains = self.toolchains.clone();
+        let cache = self.cache.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            run_compile(&scheduler, &token, &toolchains, &cache, job)
+        }))
+    }
+}
+
+/// Get the packaged toolchain for the C compiler `executable`, packaging
+/// it if needed.
+fn get_toolchain(toolchains: &Mutex<Toolchains>,
+                 cache: &ToolchainCache,
+                 executable: &Path)
+                 -> Result<Arc<Toolchain>> {
+    let mtime = fs::metadata(executable).and_then(|m| m.modified())
+        .chain_err(|| format!("failed to stat compiler {:?}", executable))?;
+    let key = (executable.to_owned(), mtime);
+    let mut toolchains = toolchains.lock().unwrap();
+    if let Some(toolchain) = toolchains.get(&key) {
+        return Ok(toolchain.clone());
+    }
+    // Toolchains are cached on disk by the digest of the compiler, which
+    // also identifies it in hash keys.
+    let digest = pkg::digest_file(executable)?;
+    let toolchain = Arc::new(cache.get(&digest, || pkg::package_c_compiler(executable))?);
+    debug!("Using toolchain {} for {:?}", toolchain.id, executable);
+    toolchains.insert(key, toolchain.clone());
+    Ok(toolchain)
+}
+
+fn run_compile(scheduler: &SocketAddr,
+               token: &str,
+               toolchains: &Mutex<Toolchains>,
+               cache: &ToolchainCache,
+               job: CompileJob)
+               -> Result<process::Output> {
+    let request_timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    let toolchain = get_toolchain(toolchains, cache, &job.executable)?;
+    let worker = match request(scheduler, token, &SchedulerRequest::AllocJob, request_timeout)
+        .chain_err(|| "failed to get a worker from the scheduler")? {
+        SchedulerResponse::JobAlloc(Some(worker)) => worker,
+        SchedulerResponse::JobAlloc(None) => bail!("no build workers are available"),
+        r => bail!("unexpected response from scheduler: {:?}", r),
+    };
+    debug!("Running compile on worker {}", worker);
+
+    let has_toolchain = WorkerRequest::HasToolchain(toolchain.id.clone());
+    match request(&worker, token, &has_toolchain, request_timeout)? {
+        WorkerResponse::HasToolchain(true) => {}
+        WorkerResponse::HasToolchain(false) => {
+            debug!("Sending toolchain {} to worker {}", toolchain.id, worker);
+            let mut archive = vec!();
+            File::open(&toolchain.archive).and_then(|mut f| f.read_to_end(&mut archive))
+                .chain_err(|| format!("failed to read {:?}", toolchain.archive))?;
+            let submit = WorkerRequest::SubmitToolchain(toolchain.id.clone(), archive);
+            match request(&worker, token, &submit, request_timeout)? {
+                WorkerResponse::ToolchainSubmitted => {}
+                WorkerResponse::Error(e) => bail!("worker failed to store toolchain: {}", e),
+                r => bail!("unexpected response from worker: {:?}", r),
+            }
+        }
+        WorkerResponse::Error(e) => bail!("worker failed to look up toolchain: {}", e),
+        r => bail!("unexpected response from worker: {:?}", r),
+    }
+
+    let CompileJob { arguments, env_vars, inputs, outputs, .. } = job;
+    let remote = WorkerRequest::RunJob(Job {
+        toolchain: toolchain.id.clone(),
+        executable: toolchain.executable.clone(),
+        arguments: arguments,
+        env_vars: env_vars,
+        inputs: inputs,
+        outputs: outputs.iter().map(|&(ref remote, _)| remote.clone()).collect(),
+    });
+    let job_timeout = Duration::from_secs(JOB_TIMEOUT_SECS);
+    let result = match request(&worker, token, &remote, job_timeout)? {
+        WorkerResponse::JobComplete(result) => result,
+        WorkerResponse::Error(e) => bail!("worker failed to run job: {}", e),
+        r => bail!("unexpected response from worker: {:?}", r),
+    };
+    match result.code {
+        Some(0) => {}
+        Some(code) => bail!("compile failed on worker with exit code {}", code),
+        None => bail!("compile was killed by a signal on worker"),
+    }
+
+    for (remote, data) in result.outputs {
+        let path = match outputs.iter().find(|&&(ref r, _)| *r == remote) {
+            Some(&(_, ref path)) => path,
+            None => bail!("worker sent unexpected output {:?}", remote),
+        };
+        let dir = match path.parent() {
+            Some(d) => d,
+            None => bail!("Output file without a parent directory!"),
+        };
+        // Write to a tempfile and then move it into place, as for cache
+        // hits.
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        tmp.write_all(&data)?;
+        tmp.persist(path)?;
+    }
+    Ok(process::Output {
+        status: exit_status(0),
+        stdout: result.stdout,
+        stderr: result.stderr,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use futures::Future;
+    use std::net::TcpListener;
+    use std::thread;
+    use tempdir::TempDir;
+    use test::utils::*;
+
+    #[test]
+    fn test_check_relative() {
+        assert!(check_relative(Path::new("foo/bar.o")).is_ok());
+        assert!(check_relative(Path::new("./foo")).is_ok());
+        assert!(check_relative(Path::new("")).is_err());
+        assert!(check_relative(Path::new("/etc/passwd")).is_err());
+        assert!(check_relative(Path::new("foo/../../bar")).is_err());
+    }
+
+    #[test]
+    fn test_read_message_limit() {
+        let mut buf = vec!();
+        write_message(&mut buf, &"hello".to_owned()).unwrap();
+        let read: String = read_message(&mut &buf[..], buf.len() - 4).unwrap();
+        assert_eq!("hello", read);
+        assert!(read_message::<_, String>(&mut &buf[..], buf.len() - 5).is_err());
+        // Huge lengths are rejected before anything is allocated for them.
+        let huge = [0xff, 0xff, 0xff, 0xff];
+        assert!(read_message::<_, String>(&mut &huge[..], MAX_MESSAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match(b"secret", b"secret"));
+        assert!(!tokens_match(b"secret", b"secreT"));
+        assert!(!tokens_match(b"secret", b"secret2"));
+        assert!(!tokens_match(b"", b"secret"));
+    }
+
+    #[test]
+    fn test_unpack_toolchain() {
+        let f = TestFixture::new();
+        let exe = mk_bin(f.tempdir.path(), "compiler").unwrap();
+        let mut packager = pkg::ToolchainPackager::new();
+        let executable = packager.add_file(&exe).unwrap();
+        let mut archive = vec!();
+        packager.write(&mut archive).unwrap();
+        let td = TempDir::new("sccache").unwrap();
+        unpack_toolchain(&archive, td.path()).unwrap();
+        let unpacked = td.path().join(&executable);
+        assert_eq!(fs::metadata(&exe).unwrap().permissions(),
+                   fs::metadata(&unpacked).unwrap().permissions());
+    }
+
+    #[test]
+    fn test_remote_compile() {
+        // Workers need bubblewrap to run jobs.
+        if ::sandbox::find_bwrap().is_err() {
+            return;
+        }
+        let f = TestFixture::new();
+        // A "compiler" that copies its input to its output. It only sees
+        // its toolchain on the worker, so it can't be a script.
+        let compiler = PathBuf::from("/bin/cp");
+
+        let token = "secret".to_owned();
+        let scheduler = Scheduler::bind(&"127.0.0.1:0".parse().unwrap(), token.clone()).unwrap();
+        let scheduler_addr = scheduler.local_addr().unwrap();
+        thread::spawn(move || scheduler.run());
+        // Nothing can run without workers.
+        let toolchain_dir = f.tempdir.path().join("toolchains");
+        let client = Client::new(scheduler_addr, token.clone(), &toolchain_dir).unwrap();
+        let out = f.tempdir.path().join("out.o");
+        let job = || CompileJob {
+            executable: compiler.clone(),
+            arguments: vec!["in.c".into(), "out.o".into()],
+            env_vars: vec![],
+            inputs: vec![(PathBuf::from("in.c"), b"source".to_vec())],
+            outputs: vec![(PathBuf::from("out.o"), out.clone())],
+        };
+        assert!(client.compile(job()).wait().is_err());
+
+        let work_dir = TempDir::new("sccache-worker").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // Workers need the token to report to the scheduler.
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let wrong = Worker::new(other, scheduler_addr, "wrong".to_owned(), 1, work_dir.path());
+        assert!(wrong.is_err());
+        let worker = Worker::new(listener, scheduler_addr, token, 1, work_dir.path()).unwrap();
+        thread::spawn(move || worker.run());
+        let output = client.compile(job()).wait().unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        let mut contents = vec!();
+        File::open(&out).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(b"source", &contents[..]);
+        // The toolchain was sent once, and is reused.
+        assert!(client.compile(job()).wait().is_ok());
+        assert_eq!(1, fs::read_dir(work_dir.path().join("toolchains")).unwrap().count());
+        // Servers need the token too.
+        let client = Client::new(scheduler_addr, "wrong".to_owned(), &toolchain_dir).unwrap();
+        assert!(client.compile(job()).wait().is_err());
+    }
+}
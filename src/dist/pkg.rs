@@ -0,0 +1,441 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Packaging compilers into toolchain archives.
+//!
+//! A toolchain archive is a tarball holding a compiler along with the
+//! helper programs and shared libraries it runs, each at its path on this
+//! machine, relative to the root of the archive. The archive is written
+//! the same way every time, so its digest identifies the toolchain.
+
+use serde_json;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use tar::{Builder, EntryType, Header};
+use tempfile::NamedTempFile;
+use util::Digest;
+use which::which_in;
+
+use errors::*;
+
+/// The files of a toolchain, to be written to an archive.
+#[derive(Debug, Default)]
+pub struct ToolchainPackager {
+    /// Local files, by their path in the archive.
+    files: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl ToolchainPackager {
+    pub fn new() -> ToolchainPackager {
+        ToolchainPackager::default()
+    }
+
+    /// Add the file at the absolute `path`, returning its path in the
+    /// archive. Symlinks are followed.
+    pub fn add_file(&mut self, path: &Path) -> Result<PathBuf> {
+        let archive_path = archive_path(path)?;
+        if !path.is_file() {
+            bail!("not a file: {:?}", path);
+        }
+        self.files.insert(archive_path.clone(), path.to_owned());
+        Ok(archive_path)
+    }
+
+    /// Add the executable or shared library at `path`, along with the
+    /// shared libraries it loads, returning its path in the archive.
+    pub fn add_executable_and_deps(&mut self, path: &Path) -> Result<PathBuf> {
+        let archive_path = self.add_file(path)?;
+        for lib in shared_libraries(path) {
+            self.add_file(&lib)?;
+        }
+        Ok(archive_path)
+    }
+
+    /// Add all the files below the directory `dir`, along with the shared
+    /// libraries that any shared libraries among them load.
+    pub fn add_dir_all(&mut self, dir: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir).chain_err(|| format!("failed to list {:?}", dir))? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.add_dir_all(&path)?;
+            } else if is_shared_library(&path) {
+                self.add_executable_and_deps(&path)?;
+            } else if path.is_file() {
+                self.add_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The local paths of the files added so far.
+    pub fn files<'a>(&'a self) -> Box<Iterator<Item=&'a Path> + 'a> {
+        Box::new(self.files.values().map(|p| &**p))
+    }
+
+    /// Write the archive to `to`.
+    ///
+    /// Entries are written in order of their paths, and only their
+    /// contents and permissions are kept, so that the same files always
+    /// make the same archive.
+    pub fn write<W: Write>(&self, to: W) -> Result<()> {
+        let mut builder = Builder::new(to);
+        for (archive_path, path) in &self.files {
+            let mut f = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+            let metadata = f.metadata()?;
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(metadata.len());
+            header.set_mode(file_mode(&metadata));
+            header.set_mtime(0);
+            header.set_uid(0);
+            header.set_gid(0);
+            builder.append_data(&mut header, archive_path, &mut f)
+                .chain_err(|| format!("failed to add {:?} to toolchain archive", path))?;
+        }
+        builder.into_inner()?.flush()?;
+        Ok(())
+    }
+}
+
+/// The path of the absolute `path` in a toolchain archive.
+fn archive_path(path: &Path) -> Result<PathBuf> {
+    if !path.is_absolute() {
+        bail!("toolchain files must have absolute paths: {:?}", path);
+    }
+    let relative = path.components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c),
+            _ => None,
+        })
+        .collect::<PathBuf>();
+    Ok(relative)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(windows)]
+fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    0o755
+}
+
+/// Whether `path` looks like a shared library.
+fn is_shared_library(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.ends_with(".so") || n.contains(".so.") || n.ends_with(".dylib") || n.ends_with(".dll"))
+        .unwrap_or(false)
+}
+
+/// The shared libraries that the executable or shared library at `path`
+/// loads, as `ldd` finds them.
+///
+/// Files that aren't dynamically linked, or that `ldd` fails on, load
+/// none.
+#[cfg(target_os = "linux")]
+fn shared_libraries(path: &Path) -> Vec<PathBuf> {
+    let output = Command::new("ldd")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(ref output) if output.status.success() => {
+            parse_ldd_output(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => {
+            trace!("ldd failed on {:?}, assuming it loads no libraries", path);
+            vec!()
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared_libraries(_path: &Path) -> Vec<PathBuf> {
+    vec!()
+}
+
+/// Get the paths of the libraries in `ldd`'s `output`.
+///
+/// Lines look like `libm.so.6 => /lib/libm.so.6 (0x...)`, or
+/// `/lib64/ld-linux-x86-64.so.2 (0x...)` for the dynamic loader. Libraries
+/// that aren't files, like the vDSO, have no path.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_ldd_output(output: &str) -> Vec<PathBuf> {
+    output.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let path = match line.find("=>") {
+                Some(i) => &line[i + 2..],
+                None => line,
+            };
+            match path.split_whitespace().next() {
+                Some(p) if p.starts_with('/') => Some(PathBuf::from(p)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Run `executable` with `args`, and get the first line it prints.
+fn run_for_output(executable: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new(executable)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .chain_err(|| format!("failed to run {:?}", executable))?;
+    if !output.status.success() {
+        bail!("{:?} {:?} failed", executable, args);
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .chain_err(|| format!("{:?} printed invalid UTF-8", executable))?;
+    Ok(stdout.lines().next().unwrap_or("").trim().to_owned())
+}
+
+/// Package the gcc or clang `executable`, with the programs it runs to
+/// compile C and C++.
+pub fn package_c_compiler(executable: &Path) -> Result<(ToolchainPackager, PathBuf)> {
+    let mut packager = ToolchainPackager::new();
+    let archive_executable = packager.add_executable_and_deps(executable)?;
+    let cwd = env::current_dir()?;
+    for prog in &["cc1", "cc1plus", "as"] {
+        let arg = format!("-print-prog-name={}", prog);
+        let found = run_for_output(executable, &[&arg])
+            .and_then(|name| find_prog(&name, env::var_os("PATH"), &cwd));
+        match found {
+            Ok(path) => {
+                packager.add_executable_and_deps(&path)?;
+            }
+            Err(e) => debug!("Couldn't find {} for {:?}: {}", prog, executable, e),
+        }
+    }
+    Ok((packager, archive_executable))
+}
+
+/// Find the program that the compiler runs for `name`, as printed by
+/// `-print-prog-name`.
+///
+/// gcc prints the name it was given if it runs the program from `paths`,
+/// as it usually does for `as`, and clang doesn't run most of them anyway.
+fn find_prog<T: AsRef<OsStr>>(name: &str, paths: Option<T>, cwd: &Path) -> Result<PathBuf> {
+    if Path::new(name).is_absolute() {
+        return Ok(PathBuf::from(name));
+    }
+    match which_in(name, paths, cwd) {
+        Ok(path) => Ok(path),
+        Err(e) => bail!("{:?} isn't in PATH: {}", name, e),
+    }
+}
+
+/// Package the rustc `executable`, with its sysroot.
+pub fn package_rustc(executable: &Path) -> Result<(ToolchainPackager, PathBuf)> {
+    let mut packager = ToolchainPackager::new();
+    let archive_executable = packager.add_executable_and_deps(executable)?;
+    let sysroot = run_for_output(executable, &["--print=sysroot"])?;
+    packager.add_dir_all(&Path::new(&sysroot).join("lib"))?;
+    Ok((packager, archive_executable))
+}
+
+/// Calculate the digest of the file at `path`.
+pub fn digest_file(path: &Path) -> Result<String> {
+    let mut f = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+    let mut digest = Digest::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let count = f.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        digest.update(&buffer[..count]);
+    }
+    Ok(digest.finish())
+}
+
+/// A writer that also calculates the digest of what's written.
+struct DigestWriter<W> {
+    inner: W,
+    digest: Digest,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.digest.update(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A packaged toolchain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Toolchain {
+    /// The digest of the archive, which identifies the toolchain.
+    pub id: String,
+    /// The path of the compiler in the archive.
+    pub executable: PathBuf,
+    /// The path of the archive.
+    #[serde(skip)]
+    pub archive: PathBuf,
+}
+
+/// Toolchain archives on disk, named by their digests, along with which
+/// compiler each one is for.
+pub struct ToolchainCache {
+    dir: PathBuf,
+}
+
+impl ToolchainCache {
+    /// Keep toolchains in `dir`, creating it if needed.
+    pub fn new(dir: &Path) -> Result<ToolchainCache> {
+        fs::create_dir_all(dir).chain_err(|| format!("failed to create {:?}", dir))?;
+        Ok(ToolchainCache { dir: dir.to_owned() })
+    }
+
+    /// Get the toolchain for the compiler identified by `key`, such as a
+    /// digest of the compiler, running `package` to make it if it isn't
+    /// cached yet.
+    pub fn get<F>(&self, key: &str, package: F) -> Result<Toolchain>
+        where F: FnOnce() -> Result<(ToolchainPackager, PathBuf)>,
+    {
+        let index = self.dir.join(format!("{}.json", key));
+        if let Ok(f) = File::open(&index) {
+            match serde_json::from_reader::<_, Toolchain>(f) {
+                Ok(mut toolchain) => {
+                    toolchain.archive = self.archive_path(&toolchain.id);
+                    if toolchain.archive.is_file() {
+                        return Ok(toolchain);
+                    }
+                }
+                Err(e) => warn!("Failed to read toolchain index {:?}: {}", index, e),
+            }
+        }
+
+        let (packager, executable) = package()?;
+        let mut tmp = DigestWriter {
+            inner: NamedTempFile::new_in(&self.dir)?,
+            digest: Digest::new(),
+        };
+        packager.write(&mut tmp)?;
+        let DigestWriter { inner: tmp, digest } = tmp;
+        let toolchain = Toolchain {
+            id: digest.finish(),
+            executable: executable,
+            archive: PathBuf::new(),
+        };
+        let archive = self.archive_path(&toolchain.id);
+        tmp.persist(&archive)?;
+        let mut tmp = NamedTempFile::new_in(&self.dir)?;
+        serde_json::to_writer(&mut tmp, &toolchain)?;
+        tmp.persist(&index)?;
+        info!("Packaged toolchain {} with {} files", toolchain.id, packager.files.len());
+        Ok(Toolchain { archive: archive, ..toolchain })
+    }
+
+    fn archive_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.tar", id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use filetime::{set_file_times, FileTime};
+    use std::cell::Cell;
+    use tempdir::TempDir;
+    use test::utils::mk_bin;
+
+    #[test]
+    fn test_parse_ldd_output() {
+        let output = "\tlinux-vdso.so.1 (0x00007ffc0a5e4000)
+\tlibm.so.6 => /lib/x86_64-linux-gnu/libm.so.6 (0x00007f2f5b2a0000)
+\tlibfoo.so => not found
+\t/lib64/ld-linux-x86-64.so.2 (0x00007f2f5b9c6000)
+";
+        assert_eq!(vec![PathBuf::from("/lib/x86_64-linux-gnu/libm.so.6"),
+                        PathBuf::from("/lib64/ld-linux-x86-64.so.2")],
+                   parse_ldd_output(output));
+    }
+
+    #[test]
+    fn test_find_prog() {
+        let td = TempDir::new("sccache").unwrap();
+        let bin = td.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        let as_path = mk_bin(&bin, "as").unwrap();
+        assert_eq!(PathBuf::from("/usr/libexec/gcc/cc1"),
+                   find_prog("/usr/libexec/gcc/cc1", Some(&bin), td.path()).unwrap());
+        assert_eq!(as_path, find_prog("as", Some(&bin), td.path()).unwrap());
+        assert!(find_prog("ld", Some(&bin), td.path()).is_err());
+    }
+
+    #[test]
+    fn test_packager_deterministic() {
+        let td = TempDir::new("sccache").unwrap();
+        let dir = td.path().join("toolchain");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        File::create(dir.join("compiler")).unwrap().write_all(b"compiler").unwrap();
+        File::create(dir.join("lib/helper")).unwrap().write_all(b"helper").unwrap();
+        let package = || {
+            let mut packager = ToolchainPackager::new();
+            packager.add_dir_all(&dir).unwrap();
+            let mut archive = vec!();
+            packager.write(&mut archive).unwrap();
+            archive
+        };
+        let first = package();
+        // Modification times don't matter.
+        let mtime = FileTime::from_seconds_since_1970(1000, 0);
+        set_file_times(dir.join("compiler"), mtime, mtime).unwrap();
+        assert_eq!(first, package());
+        // Contents do.
+        File::create(dir.join("lib/helper")).unwrap().write_all(b"other").unwrap();
+        assert!(first != package());
+    }
+
+    #[test]
+    fn test_toolchain_cache() {
+        let td = TempDir::new("sccache").unwrap();
+        let compiler = td.path().join("compiler");
+        File::create(&compiler).unwrap().write_all(b"compiler").unwrap();
+        let cache = ToolchainCache::new(&td.path().join("toolchains")).unwrap();
+        let packaged = Cell::new(0);
+        let package = || -> Result<_> {
+            packaged.set(packaged.get() + 1);
+            let mut packager = ToolchainPackager::new();
+            let executable = packager.add_file(&compiler)?;
+            Ok((packager, executable))
+        };
+        let toolchain = cache.get("key", &package).unwrap();
+        assert_eq!(archive_path(&compiler).unwrap(), toolchain.executable);
+        assert_eq!(cache.archive_path(&toolchain.id), toolchain.archive);
+        assert_eq!(toolchain.id, digest_file(&toolchain.archive).unwrap());
+        // The toolchain is only packaged once.
+        assert_eq!(toolchain, cache.get("key", &package).unwrap());
+        assert_eq!(toolchain, ToolchainCache::new(&td.path().join("toolchains")).unwrap()
+                   .get("key", &package).unwrap());
+        assert_eq!(1, packaged.get());
+    }
+}
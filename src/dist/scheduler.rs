@@ -0,0 +1,182 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The scheduler, which hands out workers to run jobs on.
+
+use dist::{read_request, write_message, SchedulerRequest, SchedulerResponse, WorkerStatus,
+           HEARTBEAT_INTERVAL_SECS, REQUEST_TIMEOUT_SECS};
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errors::*;
+
+/// What the scheduler knows about a worker.
+struct WorkerState {
+    status: WorkerStatus,
+    /// When the worker last reported its status.
+    last_seen: Instant,
+    /// How many jobs have been handed to the worker since then.
+    assigned: usize,
+}
+
+/// The workers the scheduler knows about.
+#[derive(Default)]
+struct Workers {
+    workers: HashMap<SocketAddr, WorkerState>,
+}
+
+impl Workers {
+    /// Record the status a worker reported at `now`.
+    fn heartbeat(&mut self, status: WorkerStatus, now: Instant) {
+        trace!("Heartbeat from worker {:?}", status);
+        self.workers.insert(status.addr, WorkerState {
+            status: status,
+            last_seen: now,
+            assigned: 0,
+        });
+    }
+
+    /// Pick the worker with the most free slots at `now`, if any have
+    /// one, forgetting about workers that have stopped reporting.
+    fn alloc(&mut self, now: Instant) -> Option<SocketAddr> {
+        // Give workers a few chances to report before giving up on them.
+        let timeout = Duration::from_secs(HEARTBEAT_INTERVAL_SECS * 3);
+        self.workers.retain(|addr, state| {
+            let alive = now.duration_since(state.last_seen) < timeout;
+            if !alive {
+                info!("Worker {} stopped reporting, forgetting about it", addr);
+            }
+            alive
+        });
+        let best = self.workers.iter_mut()
+            .map(|(addr, state)| {
+                let busy = state.status.running + state.assigned;
+                (state.status.slots.saturating_sub(busy), addr, state)
+            })
+            .filter(|&(free, _, _)| free > 0)
+            .max_by_key(|&(free, _, _)| free);
+        best.map(|(_, addr, state)| {
+            state.assigned += 1;
+            *addr
+        })
+    }
+}
+
+/// A scheduler, waiting for connections from workers and servers.
+pub struct Scheduler {
+    listener: TcpListener,
+    /// The token of the build cluster.
+    token: Arc<String>,
+    workers: Arc<Mutex<Workers>>,
+}
+
+impl Scheduler {
+    /// Create a scheduler listening on `addr`, which only answers requests
+    /// that come with `token`.
+    pub fn bind(addr: &SocketAddr, token: String) -> Result<Scheduler> {
+        let listener = TcpListener::bind(addr)
+            .chain_err(|| format!("failed to listen on {}", addr))?;
+        Ok(Scheduler {
+            listener: listener,
+            token: Arc::new(token),
+            workers: Arc::new(Mutex::new(Workers::default())),
+        })
+    }
+
+    /// The address the scheduler is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Handle connections until an error happens.
+    pub fn run(self) -> Result<()> {
+        info!("Scheduler listening on {}", self.listener.local_addr()?);
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let token = self.token.clone();
+            let workers = self.workers.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &token, &workers) {
+                    warn!("Scheduler request failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection(mut stream: TcpStream,
+                     token: &str,
+                     workers: &Mutex<Workers>)
+                     -> Result<()> {
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let response = match read_request(&mut stream, token)? {
+        SchedulerRequest::Heartbeat(mut status) => {
+            if status.addr.ip().is_unspecified() {
+                status.addr.set_ip(stream.peer_addr()?.ip());
+            }
+            workers.lock().unwrap().heartbeat(status, Instant::now());
+            SchedulerResponse::HeartbeatOk
+        }
+        SchedulerRequest::AllocJob => {
+            let worker = workers.lock().unwrap().alloc(Instant::now());
+            debug!("Allocated worker {:?}", worker);
+            SchedulerResponse::JobAlloc(worker)
+        }
+    };
+    write_message(&mut stream, &response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn status(addr: &str, slots: usize, running: usize) -> WorkerStatus {
+        WorkerStatus {
+            addr: addr.parse().unwrap(),
+            slots: slots,
+            running: running,
+        }
+    }
+
+    #[test]
+    fn test_alloc() {
+        let mut workers = Workers::default();
+        let now = Instant::now();
+        assert_eq!(None, workers.alloc(now));
+        let a: SocketAddr = "10.0.0.1:10501".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:10501".parse().unwrap();
+        workers.heartbeat(status("10.0.0.1:10501", 2, 0), now);
+        workers.heartbeat(status("10.0.0.2:10501", 4, 3), now);
+        // Jobs go to the worker with the most free slots, until all are
+        // full.
+        assert_eq!(Some(a), workers.alloc(now));
+        let allocated = vec![workers.alloc(now), workers.alloc(now)];
+        assert!(allocated.contains(&Some(a)));
+        assert!(allocated.contains(&Some(b)));
+        assert_eq!(None, workers.alloc(now));
+        // A heartbeat reports what's actually running.
+        workers.heartbeat(status("10.0.0.2:10501", 4, 0), now);
+        assert_eq!(Some(b), workers.alloc(now));
+        // Workers that stop reporting are forgotten.
+        let later = now + Duration::from_secs(HEARTBEAT_INTERVAL_SECS * 3);
+        assert_eq!(None, workers.alloc(later));
+    }
+}
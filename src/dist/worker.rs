@@ -0,0 +1,332 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A build worker, which runs jobs sent by servers.
+
+use dist::{check_relative, read_request, request, unpack_toolchain, write_message, Job,
+           JobResult, SchedulerRequest, SchedulerResponse, WorkerRequest, WorkerResponse,
+           WorkerStatus, HEARTBEAT_INTERVAL_SECS, REQUEST_TIMEOUT_SECS};
+use sandbox::find_bwrap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempdir::TempDir;
+use util::Digest;
+
+use errors::*;
+
+/// Where jobs run, in their sandboxes.
+const JOB_DIR: &str = "/build";
+
+/// The job slots of a worker.
+struct Slots {
+    slots: usize,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    /// Wait for a free slot and take it.
+    fn acquire(&self) {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.slots {
+            running = self.freed.wait(running).unwrap();
+        }
+        *running += 1;
+    }
+
+    /// Give back a slot taken with `acquire`.
+    fn release(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+
+    fn running(&self) -> usize {
+        *self.running.lock().unwrap()
+    }
+}
+
+/// The state shared by a worker's connections.
+struct Shared {
+    /// The address to give the scheduler.
+    addr: SocketAddr,
+    scheduler: SocketAddr,
+    /// The token of the build cluster.
+    token: String,
+    slots: Slots,
+    /// The `bwrap` that jobs are run with.
+    bwrap: PathBuf,
+    /// The directory toolchains are unpacked in.
+    toolchains: PathBuf,
+    /// The directory jobs are run in.
+    jobs: PathBuf,
+    /// Held while a toolchain is being unpacked.
+    unpacking: Mutex<()>,
+}
+
+/// A worker, waiting for jobs.
+pub struct Worker {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl Worker {
+    /// Create a worker that accepts jobs on `listener` and runs up to
+    /// `slots` of them at once, in directories below `dir`. Requests to
+    /// and from the worker come with `token`.
+    ///
+    /// The worker reports to the scheduler at `scheduler` right away, so
+    /// this fails if the scheduler can't be reached. Jobs are run in
+    /// sandboxes made by bubblewrap, so this also fails if `bwrap` isn't in
+    /// `PATH`.
+    pub fn new(listener: TcpListener,
+               scheduler: SocketAddr,
+               token: String,
+               slots: usize,
+               dir: &Path)
+               -> Result<Worker> {
+        let toolchains = dir.join("toolchains");
+        let jobs = dir.join("jobs");
+        fs::create_dir_all(&toolchains)?;
+        fs::create_dir_all(&jobs)?;
+        let bwrap = find_bwrap().chain_err(|| "build workers need bubblewrap to run jobs")?;
+        let shared = Arc::new(Shared {
+            addr: listener.local_addr()?,
+            scheduler: scheduler,
+            token: token,
+            slots: Slots {
+                slots: if slots == 0 { 1 } else { slots },
+                running: Mutex::new(0),
+                freed: Condvar::new(),
+            },
+            bwrap: bwrap,
+            toolchains: toolchains,
+            jobs: jobs,
+            unpacking: Mutex::new(()),
+        });
+        heartbeat(&shared).chain_err(|| format!("failed to reach scheduler at {}", scheduler))?;
+        Ok(Worker {
+            listener: listener,
+            shared: shared,
+        })
+    }
+
+    /// Handle connections until an error happens.
+    pub fn run(self) -> Result<()> {
+        info!("Worker listening on {}", self.shared.addr);
+        let shared = self.shared.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(HEARTBEAT_INTERVAL_SECS));
+                if let Err(e) = heartbeat(&shared) {
+                    warn!("Failed to report to scheduler: {}", e);
+                }
+            }
+        });
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let shared = self.shared.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &shared) {
+                    warn!("Worker request failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Report the worker's status to the scheduler.
+fn heartbeat(shared: &Shared) -> Result<()> {
+    let status = WorkerStatus {
+        addr: shared.addr,
+        slots: shared.slots.slots,
+        running: shared.slots.running(),
+    };
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    let heartbeat = SchedulerRequest::Heartbeat(status);
+    match request(&shared.scheduler, &shared.token, &heartbeat, timeout)? {
+        SchedulerResponse::HeartbeatOk => Ok(()),
+        r => bail!("unexpected response from scheduler: {:?}", r),
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, shared: &Shared) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+    let response = match read_request(&mut stream, &shared.token)? {
+        WorkerRequest::HasToolchain(id) => {
+            check_toolchain_id(&id).map(|_| {
+                WorkerResponse::HasToolchain(shared.toolchains.join(&id).is_dir())
+            })
+        }
+        WorkerRequest::SubmitToolchain(id, archive) => {
+            store_toolchain(shared, &id, &archive).map(|_| WorkerResponse::ToolchainSubmitted)
+        }
+        WorkerRequest::RunJob(job) => {
+            shared.slots.acquire();
+            let result = run_job(shared, job);
+            shared.slots.release();
+            // Let the scheduler know about the free slot now, rather than
+            // at the next heartbeat.
+            if let Err(e) = heartbeat(shared) {
+                warn!("Failed to report to scheduler: {}", e);
+            }
+            result.map(WorkerResponse::JobComplete)
+        }
+    };
+    let response = response.unwrap_or_else(|e| WorkerResponse::Error(e.to_string()));
+    write_message(&mut stream, &response)
+}
+
+/// Toolchain ids are hex digests, and name directories.
+fn check_toolchain_id(id: &str) -> Result<()> {
+    if id.is_empty() || !id.chars().all(|c| c.is_digit(16)) {
+        bail!("invalid toolchain id: {:?}", id);
+    }
+    Ok(())
+}
+
+/// Unpack toolchain `archive` as `id`, unless it already has been.
+fn store_toolchain(shared: &Shared, id: &str, archive: &[u8]) -> Result<()> {
+    check_toolchain_id(id)?;
+    let mut digest = Digest::new();
+    digest.update(archive);
+    if digest.finish() != id {
+        bail!("toolchain archive doesn't match its id {}", id);
+    }
+    let _unpacking = shared.unpacking.lock().unwrap();
+    let dir = shared.toolchains.join(id);
+    if dir.is_dir() {
+        return Ok(());
+    }
+    // Unpack to a temporary directory first, so that a partly unpacked
+    // toolchain is never used.
+    let tmp = TempDir::new_in(&shared.toolchains, "unpack")?;
+    unpack_toolchain(archive, tmp.path())?;
+    fs::rename(tmp.into_path(), &dir)?;
+    info!("Stored toolchain {}", id);
+    Ok(())
+}
+
+/// Run `job` in a directory of its own.
+///
+/// The compiler runs in a sandbox whose root holds only the toolchain, so
+/// that the libraries and programs packaged with it are the ones it runs,
+/// at the paths they had where it was packaged. The job's directory is the
+/// only one it can write to.
+fn run_job(shared: &Shared, job: Job) -> Result<JobResult> {
+    check_toolchain_id(&job.toolchain)?;
+    let toolchain = shared.toolchains.join(&job.toolchain);
+    if !toolchain.is_dir() {
+        bail!("unknown toolchain {}", job.toolchain);
+    }
+    check_relative(&job.executable)?;
+    let dir = TempDir::new_in(&shared.jobs, "job")?;
+    for &(ref path, ref data) in &job.inputs {
+        check_relative(path)?;
+        let path = dir.path().join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(&path)?.write_all(data)?;
+    }
+    let executable = Path::new("/").join(&job.executable);
+    let mut path = OsString::from(executable.parent().unwrap_or(Path::new("/")));
+    path.push(":/usr/local/bin:/usr/bin:/bin");
+    debug!("Running job: {:?} {:?}", executable, job.arguments);
+    let output = Command::new(&shared.bwrap)
+        .args(job_bwrap_args(&toolchain, dir.path())?)
+        .arg(&executable)
+        .args(&job.arguments)
+        .env_clear()
+        .envs(job.env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+        .env("PATH", path)
+        .current_dir(dir.path())
+        .stdin(Stdio::null())
+        .output()
+        .chain_err(|| format!("failed to run {:?}", executable))?;
+    let mut outputs = vec!();
+    for path in job.outputs {
+        check_relative(&path)?;
+        // A failed compile may not produce all its outputs.
+        if let Ok(mut f) = File::open(dir.path().join(&path)) {
+            let mut data = vec!();
+            f.read_to_end(&mut data)?;
+            outputs.push((path, data));
+        }
+    }
+    Ok(JobResult {
+        code: output.status.code(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+        outputs: outputs,
+    })
+}
+
+/// Get the arguments for `bwrap` to run a job in `job_dir`, with the files
+/// in `toolchain` at the root.
+fn job_bwrap_args(toolchain: &Path, job_dir: &Path) -> Result<Vec<OsString>> {
+    let mut entries = fs::read_dir(toolchain)
+        .chain_err(|| format!("failed to list {:?}", toolchain))?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    entries.sort();
+    let mut args = ["--unshare-all", "--die-with-parent"].iter()
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+    for name in entries {
+        args.push("--ro-bind".into());
+        args.push(toolchain.join(&name).into());
+        args.push(Path::new("/").join(&name).into());
+    }
+    for arg in &["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp",
+                 "--bind"] {
+        args.push(arg.into());
+    }
+    args.push(job_dir.into());
+    for arg in &[JOB_DIR, "--chdir", JOB_DIR, "--"] {
+        args.push(arg.into());
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_job_bwrap_args() {
+        let td = TempDir::new("sccache").unwrap();
+        let toolchain = td.path().join("toolchain");
+        fs::create_dir_all(toolchain.join("usr/bin")).unwrap();
+        fs::create_dir_all(toolchain.join("lib64")).unwrap();
+        let job_dir = td.path().join("job");
+        let args = job_bwrap_args(&toolchain, &job_dir).unwrap();
+        let expected = vec![OsString::from("--unshare-all"), "--die-with-parent".into(),
+                            "--ro-bind".into(), toolchain.join("lib64").into(), "/lib64".into(),
+                            "--ro-bind".into(), toolchain.join("usr").into(), "/usr".into(),
+                            "--dev".into(), "/dev".into(), "--proc".into(), "/proc".into(),
+                            "--tmpfs".into(), "/tmp".into(),
+                            "--bind".into(), job_dir.into(), JOB_DIR.into(),
+                            "--chdir".into(), JOB_DIR.into(), "--".into()];
+        assert_eq!(expected, args);
+    }
+}
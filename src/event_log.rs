@@ -0,0 +1,138 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A log of compiles handled by the server, with one JSON object per line.
+
+use serde_json;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use util::duration_as_secs_f64;
+
+use errors::*;
+
+/// A record of a single compile.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct CompileEvent {
+    /// When the compile finished, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The working directory of the compile.
+    pub cwd: String,
+    /// The kind of compiler, as `CompilerKind::as_str` names it.
+    pub compiler: String,
+    /// The source language, if the arguments were parsed.
+    pub language: Option<String>,
+    /// The output being produced, if the arguments were parsed.
+    pub output: Option<String>,
+    /// The hash key of the compile, if hashing succeeded.
+    pub hash_key: Option<String>,
+    /// One of `hit`, `miss`, `not_cacheable`, `compile_failed` or `error`.
+    pub result: String,
+    /// Why the compile missed the cache, for `miss` results.
+    pub miss_type: Option<String>,
+    /// Why the compile couldn't be cached, if it was known from the
+    /// arguments.
+    pub not_cacheable_reason: Option<String>,
+    /// The error, for `error` results.
+    pub error: Option<String>,
+    /// The time taken to handle the compile, in seconds.
+    pub duration: f64,
+    /// The time taken to look up the compile in the cache, in seconds.
+    pub cache_read_duration: Option<f64>,
+    /// The time taken to compile, in seconds. For hits, this is the time
+    /// that the compile which wrote the cache entry took, if known, unless
+    /// the hit was verified.
+    pub compile_duration: Option<f64>,
+    /// For hits that were verified by compiling again, the outputs that
+    /// didn't match the cache entry.
+    pub verify_mismatches: Option<Vec<String>>,
+}
+
+impl CompileEvent {
+    /// Create an event for a compile in `cwd` by a `compiler` compiler
+    /// that turned out as `result`, and took `duration`.
+    pub fn new(cwd: &Path, compiler: &str, result: &str, duration: Duration) -> CompileEvent {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        CompileEvent {
+            time: time,
+            cwd: cwd.to_string_lossy().into_owned(),
+            compiler: compiler.to_owned(),
+            result: result.to_owned(),
+            duration: duration_as_secs_f64(&duration),
+            ..Default::default()
+        }
+    }
+}
+
+/// A file that compile events are appended to.
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    /// Open the event log at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<EventLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .chain_err(|| format!("failed to open event log {}", path.display()))?;
+        Ok(EventLog { file: file })
+    }
+
+    /// Append `event` to the log. Failures are logged and otherwise
+    /// ignored, since they shouldn't fail the compile.
+    pub fn log(&mut self, event: &CompileEvent) {
+        let res = serde_json::to_vec(event)
+            .map_err(Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                // Write the line at once so that it doesn't get interleaved
+                // with other writers.
+                self.file.write_all(&line).map_err(Error::from)
+            });
+        if let Err(e) = res {
+            warn!("Failed to write to event log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_event_log() {
+        let td = TempDir::new("sccache").unwrap();
+        let path = td.path().join("events.jsonl");
+        let mut event = CompileEvent::new(Path::new("/src"), "gcc", "hit",
+                                          Duration::from_millis(1500));
+        event.hash_key = Some("abcd".to_owned());
+        {
+            let mut log = EventLog::open(&path).unwrap();
+            log.log(&event);
+        }
+        // Opening it again appends.
+        EventLog::open(&path).unwrap().log(&event);
+        let lines = BufReader::new(File::open(&path).unwrap()).lines()
+            .map(|l| serde_json::from_str::<CompileEvent>(&l.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(2, lines.len());
+        assert_eq!(event, lines[0]);
+        assert_eq!(1.5, lines[1].duration);
+        assert_eq!(Some("abcd".to_owned()), lines[1].hash_key);
+    }
+}
@@ -1,7 +1,10 @@
 extern crate jobserver;
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io;
 use std::process::Command;
+use std::rc::Rc;
 use std::sync::Arc;
 
 use futures::prelude::*;
@@ -69,3 +72,91 @@ impl Client {
                    .and_then(|t| t.chain_err(|| "failed to acquire jobserver token")))
     }
 }
+
+/// A limit on how many jobs may run at once, for work that is all driven
+/// by a single event loop.
+///
+/// Unlike a `Client`, this doesn't involve the system jobserver, so it's
+/// cheap enough to create one per request.
+#[derive(Clone)]
+pub struct JobLimit {
+    inner: Rc<RefCell<JobLimitInner>>,
+}
+
+struct JobLimitInner {
+    /// The number of tokens not currently held.
+    available: usize,
+    /// Requests waiting for a token, in order.
+    waiting: VecDeque<oneshot::Sender<JobToken>>,
+}
+
+/// Permission to run a job under a `JobLimit`, given back when dropped.
+pub struct JobToken {
+    limit: JobLimit,
+}
+
+impl JobLimit {
+    /// Create a limit of `jobs` jobs at once. At least one job is always
+    /// allowed.
+    pub fn new(jobs: usize) -> JobLimit {
+        JobLimit {
+            inner: Rc::new(RefCell::new(JobLimitInner {
+                available: if jobs == 0 { 1 } else { jobs },
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Returns a future that resolves to a token once fewer jobs than the
+    /// limit are running.
+    pub fn acquire(&self) -> SFuture<JobToken> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.available > 0 {
+            inner.available -= 1;
+            return f_ok(JobToken { limit: self.clone() });
+        }
+        let (tx, rx) = oneshot::channel();
+        inner.waiting.push_back(tx);
+        Box::new(rx.chain_err(|| "job limit went away"))
+    }
+}
+
+impl Drop for JobToken {
+    fn drop(&mut self) {
+        let next = self.limit.inner.borrow_mut().waiting.pop_front();
+        match next {
+            // If that request was dropped, the token comes back here and is
+            // passed on to the next one.
+            Some(tx) => drop(tx.send(JobToken { limit: self.limit.clone() })),
+            None => self.limit.inner.borrow_mut().available += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_job_limit() {
+        let limit = JobLimit::new(2);
+        let first = limit.acquire().wait().unwrap();
+        let second = limit.acquire().wait().unwrap();
+        // These have to wait for the first two.
+        let third = limit.acquire();
+        let dropped = limit.acquire();
+        let fourth = limit.acquire();
+        assert_eq!(0, limit.inner.borrow().available);
+        assert_eq!(3, limit.inner.borrow().waiting.len());
+        drop(first);
+        let third = third.wait().unwrap();
+        // A request that went away doesn't keep the token it was given.
+        drop(dropped);
+        drop(second);
+        let fourth = fourth.wait().unwrap();
+        assert!(limit.inner.borrow().waiting.is_empty());
+        drop(third);
+        drop(fourth);
+        assert_eq!(2, limit.inner.borrow().available);
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server stats in the Prometheus text exposition format.
+
+#[cfg(feature = "hyper")]
+use futures::{future, Stream};
+#[cfg(feature = "hyper")]
+use hyper;
+#[cfg(feature = "hyper")]
+use hyper::header::{ContentLength, ContentType};
+#[cfg(feature = "hyper")]
+use hyper::server::{Http, Request, Response};
+#[cfg(feature = "hyper")]
+use hyper::StatusCode;
+use server::{BreakdownStats, ServerInfo};
+#[cfg(feature = "hyper")]
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+#[cfg(feature = "hyper")]
+use std::rc::Rc;
+use std::time::Duration;
+#[cfg(feature = "hyper")]
+use tokio_core::net::TcpListener;
+#[cfg(feature = "hyper")]
+use tokio_core::reactor::Handle;
+#[cfg(feature = "hyper")]
+use tokio_service::Service;
+use util::duration_as_secs_f64;
+
+/// The upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5,
+                                  1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// A histogram of durations.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    /// The count of durations at or below each of `BUCKETS`.
+    buckets: Vec<u64>,
+    /// The count of all durations.
+    count: u64,
+    /// The sum of all durations.
+    sum: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: vec![0; BUCKETS.len()],
+            count: 0,
+            sum: Duration::new(0, 0),
+        }
+    }
+}
+
+impl Histogram {
+    /// Record `duration` in this histogram.
+    pub fn observe(&mut self, duration: Duration) {
+        let secs = duration_as_secs_f64(&duration);
+        for (bucket, &bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += duration;
+    }
+
+    /// Write this histogram as the metric `name` to `out`.
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        drop(writeln!(out, "# HELP {} {}", name, help));
+        drop(writeln!(out, "# TYPE {} histogram", name));
+        for (count, bound) in self.buckets.iter().zip(BUCKETS) {
+            drop(writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count));
+        }
+        drop(writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count));
+        drop(writeln!(out, "{}_sum {}", name, duration_as_secs_f64(&self.sum)));
+        drop(writeln!(out, "{}_count {}", name, self.count));
+    }
+}
+
+/// Latency histograms for the work the server does.
+#[derive(Clone, Debug, Default)]
+pub struct Latencies {
+    /// Cache lookups, both hits and misses.
+    pub cache_read: Histogram,
+    /// Cache writes.
+    pub cache_write: Histogram,
+    /// Compiles done for cache misses.
+    pub compile: Histogram,
+}
+
+/// Escape `value` for use as a label value.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_value<T: ::std::fmt::Display>(out: &mut String, name: &str, kind: &str, help: &str,
+                                        value: T) {
+    drop(writeln!(out, "# HELP {} {}", name, help));
+    drop(writeln!(out, "# TYPE {} {}", name, kind));
+    drop(writeln!(out, "{} {}", name, value));
+}
+
+fn render_breakdown(out: &mut String, label: &str, breakdown: &HashMap<String, BreakdownStats>) {
+    let mut rows = breakdown.iter().collect::<Vec<_>>();
+    rows.sort_by(|&(a, _), &(b, _)| a.cmp(b));
+    for &(name, field) in &[("cache_hits", "hits"), ("cache_misses", "misses")] {
+        let metric = format!("sccache_{}_{}_total", label, name);
+        drop(writeln!(out, "# HELP {} Cache {} by {}.", metric, field, label));
+        drop(writeln!(out, "# TYPE {} counter", metric));
+        for &(key, stats) in &rows {
+            let value = if field == "hits" { stats.cache_hits } else { stats.cache_misses };
+            drop(writeln!(out, "{}{{{}=\"{}\"}} {}", metric, label, escape_label(key), value));
+        }
+    }
+}
+
+/// Render `info` and `latencies` in the Prometheus text format.
+pub fn render(info: &ServerInfo, latencies: &Latencies) -> String {
+    let mut out = String::new();
+    let stats = &info.stats;
+    for &(name, help, value) in &[
+        ("compile_requests", "Client compile requests.", stats.compile_requests),
+        ("requests_executed", "Compile requests executed.", stats.requests_executed),
+        ("requests_unsupported_compiler", "Requests using an unsupported compiler.",
+         stats.requests_unsupported_compiler),
+        ("requests_not_compile", "Requests that were not compilation.", stats.requests_not_compile),
+        ("requests_not_cacheable", "Requests that were not cacheable.", stats.requests_not_cacheable),
+        ("cache_hits", "Cache hits.", stats.cache_hits),
+        ("cache_misses", "Cache misses.", stats.cache_misses),
+        ("cache_timeouts", "Cache lookups that timed out.", stats.cache_timeouts),
+        ("cache_read_errors", "Errors reading cache entries.", stats.cache_read_errors),
+        ("cache_errors", "Errors handling compile requests.", stats.cache_errors),
+        ("cache_writes", "Successful cache writes.", stats.cache_writes),
+        ("cache_write_errors", "Errors writing cache entries.", stats.cache_write_errors),
+        ("forced_recaches", "Compilations that ignored the cache.", stats.forced_recaches),
+        ("cache_verifications", "Cache hits compiled again to check them.",
+         stats.cache_verifications),
+        ("cache_verify_mismatches", "Cache hits that didn't match when compiled again.",
+         stats.cache_verify_mismatches),
+        ("cache_verify_errors", "Cache hits that couldn't be compiled again to check them.",
+         stats.cache_verify_errors),
+        ("non_cacheable_compilations", "Successful compilations that couldn't be cached.",
+         stats.non_cacheable_compilations),
+        ("compile_fails", "Compilation failures.", stats.compile_fails),
+    ] {
+        render_value(&mut out, &format!("sccache_{}_total", name), "counter", help, value);
+    }
+    render_value(&mut out, "sccache_time_saved_seconds_total", "counter",
+                 "Estimated time saved by cache hits.", duration_as_secs_f64(&stats.time_saved));
+
+    let mut reasons = stats.not_cached.iter().collect::<Vec<_>>();
+    reasons.sort();
+    out.push_str("# HELP sccache_not_cached_total Requests that were not cacheable, by reason.\n");
+    out.push_str("# TYPE sccache_not_cached_total counter\n");
+    for (reason, count) in reasons {
+        drop(writeln!(out, "sccache_not_cached_total{{reason=\"{}\"}} {}", escape_label(reason), count));
+    }
+    render_breakdown(&mut out, "compiler", &stats.by_compiler);
+    render_breakdown(&mut out, "language", &stats.by_language);
+
+    if let Some(size) = info.cache_size {
+        render_value(&mut out, "sccache_cache_size_bytes", "gauge", "Current cache size.", size);
+    }
+    if let Some(size) = info.max_cache_size {
+        render_value(&mut out, "sccache_max_cache_size_bytes", "gauge", "Maximum cache size.", size);
+    }
+
+    latencies.cache_read.render(&mut out, "sccache_cache_read_duration_seconds",
+                                "Time taken by cache lookups.");
+    latencies.cache_write.render(&mut out, "sccache_cache_write_duration_seconds",
+                                 "Time taken by cache writes.");
+    latencies.compile.render(&mut out, "sccache_compile_duration_seconds",
+                             "Time taken by compiles for cache misses.");
+    out
+}
+
+/// A hyper service answering requests for `/metrics`.
+#[cfg(feature = "hyper")]
+struct MetricsService {
+    info: Rc<Fn() -> ServerInfo>,
+    latencies: Rc<RefCell<Latencies>>,
+}
+
+#[cfg(feature = "hyper")]
+impl Service for MetricsService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = future::FutureResult<Response, hyper::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        trace!("metrics request: {}", req.path());
+        if req.path() != "/metrics" {
+            return future::ok(Response::new().with_status(StatusCode::NotFound));
+        }
+        let body = render(&(self.info)(), &self.latencies.borrow());
+        future::ok(Response::new()
+                   .with_header(ContentType::plaintext())
+                   .with_header(ContentLength(body.len() as u64))
+                   .with_body(body))
+    }
+}
+
+/// Serve metrics over HTTP to connections on `listener`, getting the
+/// current stats from `info`.
+#[cfg(feature = "hyper")]
+pub fn serve(listener: TcpListener,
+             handle: &Handle,
+             info: Rc<Fn() -> ServerInfo>,
+             latencies: Rc<RefCell<Latencies>>) {
+    let http = Http::new();
+    let h = handle.clone();
+    handle.spawn(listener.incoming().for_each(move |(socket, addr)| {
+        http.bind_connection(&h, socket, addr, MetricsService {
+            info: info.clone(),
+            latencies: latencies.clone(),
+        });
+        Ok(())
+    }).map_err(|e| warn!("Metrics listener failed: {}", e)));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use server::ServerStats;
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        h.observe(Duration::from_millis(3));
+        h.observe(Duration::from_millis(200));
+        h.observe(Duration::from_secs(1000));
+        let mut out = String::new();
+        h.render(&mut out, "test_seconds", "A test.");
+        assert!(out.contains("test_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.25\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"120\"} 2\n"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_seconds_sum 1000.203\n"));
+        assert!(out.contains("test_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn test_render() {
+        let mut stats = ServerStats::default();
+        stats.cache_hits = 3;
+        stats.not_cached.insert("-x \"foo\"".to_owned(), 2);
+        stats.by_compiler.insert("gcc".to_owned(), BreakdownStats {
+            cache_misses: 4,
+            ..Default::default()
+        });
+        let info = ServerInfo {
+            stats: stats.clone(),
+            lifetime_stats: stats,
+            cache_location: "here".to_owned(),
+            cache_size: Some(1024),
+            max_cache_size: None,
+            version: "1.0".to_owned(),
+        };
+        let out = render(&info, &Latencies::default());
+        assert!(out.contains("# TYPE sccache_cache_hits_total counter\nsccache_cache_hits_total 3\n"));
+        assert!(out.contains("sccache_not_cached_total{reason=\"-x \\\"foo\\\"\"} 2\n"));
+        assert!(out.contains("sccache_compiler_cache_misses_total{compiler=\"gcc\"} 4\n"));
+        assert!(out.contains("sccache_cache_size_bytes 1024\n"));
+        assert!(!out.contains("sccache_max_cache_size_bytes"));
+        assert!(out.contains("sccache_compile_duration_seconds_count 0\n"));
+    }
+}
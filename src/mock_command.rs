@@ -54,7 +54,7 @@ use std::boxed::Box;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{
     Command,
     ExitStatus,
@@ -139,6 +139,9 @@ pub trait CommandCreator {
     /// Create a new object that implements `RunCommand` that can be used
     /// to create a new process.
     fn new_command<S: AsRef<OsStr>>(&mut self, program: S) -> Self::Cmd;
+    /// Create a creator whose commands are run by `wrapper`, from one that's
+    /// shared.
+    fn wrapped(creator: &Arc<Mutex<Self>>, wrapper: CommandWrapper) -> Self where Self: Sized;
 }
 
 /// A trait for simplifying the normal case while still allowing the mock case requiring mutability.
@@ -148,6 +151,17 @@ pub trait CommandCreatorSync: Clone + 'static {
     fn new(handle: &Handle, client: &Client) -> Self;
 
     fn new_command_sync<S: AsRef<OsStr>>(&mut self, program: S) -> Self::Cmd;
+
+    /// Get a creator whose commands are run by `wrapper`.
+    fn wrapped(&self, wrapper: CommandWrapper) -> Self;
+}
+
+/// A program that runs other commands, such as a sandbox, along with the
+/// arguments that go before the command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandWrapper {
+    pub program: PathBuf,
+    pub args: Vec<OsString>,
 }
 
 pub struct Child {
@@ -294,6 +308,7 @@ impl fmt::Debug for AsyncCommand {
 pub struct ProcessCommandCreator {
     handle: Handle,
     jobserver: Client,
+    wrapper: Option<Arc<CommandWrapper>>,
 }
 
 /// Trivial implementation of `CommandCreator` for `ProcessCommandCreator`.
@@ -304,11 +319,27 @@ impl CommandCreator for ProcessCommandCreator {
         ProcessCommandCreator {
             handle: handle.clone(),
             jobserver: client.clone(),
+            wrapper: None,
         }
     }
 
     fn new_command<S: AsRef<OsStr>>(&mut self, program: S) -> AsyncCommand {
-        AsyncCommand::new(program, self.handle.clone(), self.jobserver.clone())
+        match self.wrapper {
+            Some(ref wrapper) => {
+                let mut cmd = AsyncCommand::new(&wrapper.program,
+                                                self.handle.clone(),
+                                                self.jobserver.clone());
+                cmd.args(&wrapper.args).arg(program);
+                cmd
+            }
+            None => AsyncCommand::new(program, self.handle.clone(), self.jobserver.clone()),
+        }
+    }
+
+    fn wrapped(creator: &Arc<Mutex<ProcessCommandCreator>>,
+               wrapper: CommandWrapper)
+               -> ProcessCommandCreator {
+        CommandCreatorSync::wrapped(&*creator.lock().unwrap(), wrapper)
     }
 }
 
@@ -324,6 +355,13 @@ impl CommandCreatorSync for ProcessCommandCreator {
         // This doesn't actually use any mutable state.
         self.new_command(program)
     }
+
+    fn wrapped(&self, wrapper: CommandWrapper) -> ProcessCommandCreator {
+        ProcessCommandCreator {
+            wrapper: Some(Arc::new(wrapper)),
+            .. self.clone()
+        }
+    }
 }
 
 #[cfg(unix)]
@@ -486,6 +524,9 @@ impl RunCommand for MockCommand {
 pub struct MockCommandCreator {
     /// Data to be used as the return value of `MockCommand::spawn`.
     pub children : Vec<ChildOrCall>,
+    /// For a wrapped creator, the creator whose children it uses, and the
+    /// wrapper whose program and arguments go before its commands'.
+    wrapping: Option<(Arc<Mutex<MockCommandCreator>>, CommandWrapper)>,
 }
 
 impl MockCommandCreator {
@@ -511,10 +552,16 @@ impl CommandCreator for MockCommandCreator {
     fn new(_handle: &Handle, _client: &Client) -> MockCommandCreator {
         MockCommandCreator {
             children: Vec::new(),
+            wrapping: None,
         }
     }
 
-    fn new_command<S: AsRef<OsStr>>(&mut self, _program: S) -> MockCommand {
+    fn new_command<S: AsRef<OsStr>>(&mut self, program: S) -> MockCommand {
+        if let Some((ref creator, ref wrapper)) = self.wrapping {
+            let mut cmd = creator.lock().unwrap().new_command(&wrapper.program);
+            cmd.args(&wrapper.args).arg(program);
+            return cmd;
+        }
         assert!(self.children.len() > 0, "Too many calls to MockCommandCreator::new_command, or not enough to MockCommandCreator::new_command_spawns!");
         //TODO: assert value of program
         MockCommand {
@@ -522,6 +569,15 @@ impl CommandCreator for MockCommandCreator {
             args: vec![],
         }
     }
+
+    fn wrapped(creator: &Arc<Mutex<MockCommandCreator>>,
+               wrapper: CommandWrapper)
+               -> MockCommandCreator {
+        MockCommandCreator {
+            children: Vec::new(),
+            wrapping: Some((creator.clone(), wrapper)),
+        }
+    }
 }
 
 /// To simplify life for using a `CommandCreator` across multiple threads.
@@ -535,6 +591,10 @@ impl<T: CommandCreator + 'static> CommandCreatorSync for Arc<Mutex<T>> {
     fn new_command_sync<S: AsRef<OsStr>>(&mut self, program: S) -> T::Cmd {
         self.lock().unwrap().new_command(program)
     }
+
+    fn wrapped(&self, wrapper: CommandWrapper) -> Arc<Mutex<T>> {
+        Arc::new(Mutex::new(T::wrapped(self, wrapper)))
+    }
 }
 
 #[cfg(test)]
@@ -644,6 +704,45 @@ mod test {
         assert_eq!("error", e.description());
     }
 
+    #[test]
+    fn test_mock_command_wrapped() {
+        let core = Core::new().unwrap();
+        let client = Client::new_num(1);
+        let creator = Arc::new(Mutex::new(MockCommandCreator::new(&core.handle(), &client)));
+        let mut wrapped = creator.wrapped(CommandWrapper {
+            program: "bwrap".into(),
+            args: vec!["--".into()],
+        });
+        // Wrapped commands use the children of the creator they came from.
+        next_command_calls(&creator, |args| {
+            assert_eq!(&[OsString::from("--"), "foo".into(), "-c".into()], args);
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let status = wrapped.new_command_sync("foo").arg("-c")
+            .spawn().wait().unwrap()
+            .wait().wait().unwrap();
+        assert_eq!(exit_status(0), status);
+        assert!(creator.lock().unwrap().children.is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_process_command_wrapped() {
+        let mut core = Core::new().unwrap();
+        let client = Client::new_num(1);
+        let creator = <ProcessCommandCreator as CommandCreatorSync>::new(&core.handle(), &client);
+        let mut wrapped = creator.wrapped(CommandWrapper {
+            program: "echo".into(),
+            args: vec!["wrapped".into()],
+        });
+        let output = wrapped.new_command_sync("foo").arg("bar")
+            .stdout(Stdio::piped())
+            .spawn()
+            .and_then(|child| child.wait_with_output().map_err(|e| e.into()));
+        let output = core.run(output).unwrap();
+        assert_eq!(&b"wrapped foo bar\n"[..], output.stdout.as_slice());
+    }
+
     #[test]
     fn test_mock_command_sync() {
         let core = Core::new().unwrap();
@@ -1,5 +1,9 @@
 use std::ffi::OsString;
-use server::ServerInfo;
+use server::{ServerInfo, ServerStatus};
+
+/// The version of the protocol between the client and the server. Change
+/// it whenever `Request` or `Response` change in an incompatible way.
+pub const PROTOCOL_VERSION: u32 = 1;
 
 /// A client request.
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,6 +16,11 @@ pub enum Request {
     Shutdown,
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    /// Get the server's version. New variants have to come after this one,
+    /// so that servers of any version can answer it.
+    GetVersion,
+    /// Check the health of the server and its cache storage.
+    Status,
 }
 
 /// A server response.
@@ -23,8 +32,34 @@ pub enum Response {
     Stats(ServerInfo),
     /// Response for `Request::Shutdown`, containing server statistics.
     ShuttingDown(ServerInfo),
+    /// Output from a compile started by `Request::Compile`, sent while it
+    /// runs. Any number of these may come before `CompileFinished`.
+    CompileOutput(CompileOutput),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::GetVersion`.
+    Version(Version),
+    /// Response for `Request::Status`.
+    Status(ServerStatus),
+}
+
+/// The version of a client or server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Version {
+    /// The protocol version, `PROTOCOL_VERSION`.
+    pub protocol: u32,
+    /// The version of sccache.
+    pub version: String,
+}
+
+impl Version {
+    /// The version of this build.
+    pub fn current() -> Version {
+        Version {
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
 }
 
 /// Possible responses from the server for a `Compile` request.
@@ -36,6 +71,15 @@ pub enum CompileResponse {
     UnhandledCompile,
 }
 
+/// A chunk of output from a running compile.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum CompileOutput {
+    /// Part of the compiler's stdout.
+    Stdout(Vec<u8>),
+    /// Part of the compiler's stderr.
+    Stderr(Vec<u8>),
+}
+
 /// Information about a finished compile, either from cache or executed locally.
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct CompileFinished {
@@ -43,10 +87,13 @@ pub struct CompileFinished {
     pub retcode: Option<i32>,
     /// The signal that terminated the compile process, if available.
     pub signal: Option<i32>,
-    /// The compiler's stdout.
+    /// The compiler's stdout, apart from any sent in `CompileOutput`s.
     pub stdout: Vec<u8>,
-    /// The compiler's stderr.
+    /// The compiler's stderr, apart from any sent in `CompileOutput`s.
     pub stderr: Vec<u8>,
+    /// An error in the server that kept it from finishing the compile, as
+    /// opposed to the compiler failing, if there was one.
+    pub server_error: Option<String>,
 }
 
 /// The contents of a compile request from a client.
@@ -60,4 +107,8 @@ pub struct Compile {
     pub args: Vec<OsString>,
     /// The environment variables present when the compiler was executed, as (var, val).
     pub env_vars: Vec<(OsString, OsString)>,
+    /// How many jobserver tokens the client holds for this request, besides
+    /// the job slot the build gave it. The compiles of a command with several
+    /// inputs run at most that many plus one at a time.
+    pub extra_tokens: usize,
 }
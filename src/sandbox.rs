@@ -0,0 +1,317 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running compiles in a sandbox that only shows them the files that went
+//! into their hash keys.
+//!
+//! A compile that reads a file the hash key doesn't cover, like a header
+//! missing from the preprocessor output, gets cached under a key that
+//! doesn't change when that file does. In a sandbox, such a compile fails
+//! instead. Sandboxes are made by bubblewrap (`bwrap`), which uses user
+//! namespaces, so no special privileges are needed.
+
+use dist::pkg;
+use mock_command::CommandWrapper;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tempdir::TempDir;
+use which::which_in;
+
+use errors::*;
+
+/// Files outside of any toolchain that programs may need, if they exist.
+const SYSTEM_FILES: &[&str] = &["/etc/ld.so.cache"];
+
+/// The compiler of a sandboxed compile.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SandboxCompiler {
+    /// gcc or clang.
+    C(PathBuf),
+    Rust(PathBuf),
+}
+
+/// What a sandboxed compile may read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SandboxInputs {
+    /// The compiler, along with the programs and libraries it runs.
+    pub compiler: SandboxCompiler,
+    /// The absolute paths of other files or directories that the compile
+    /// reads. Ones that don't exist are skipped.
+    pub paths: Vec<PathBuf>,
+}
+
+/// The files of each compiler packaged so far, by path and modification
+/// time.
+type Toolchains = HashMap<(PathBuf, SystemTime), Arc<Vec<PathBuf>>>;
+
+/// Makes sandboxes for compiles.
+pub struct Sandbox {
+    bwrap: PathBuf,
+    toolchains: Mutex<Toolchains>,
+}
+
+/// A sandbox prepared for a compile.
+pub struct SandboxedCompile {
+    wrapper: CommandWrapper,
+    /// Where each output is written in the sandbox, and where it goes.
+    outputs: Vec<(PathBuf, PathBuf)>,
+    /// The directories standing in for the output directories.
+    _dirs: Vec<TempDir>,
+}
+
+/// How a path is made visible in a sandbox.
+#[derive(Debug, PartialEq)]
+enum Mount {
+    /// An empty directory.
+    Dir,
+    /// The file or directory at the same path outside, read-only.
+    ReadOnly,
+    /// The directory at this path outside, writable.
+    Writable(PathBuf),
+}
+
+impl Sandbox {
+    /// Make sandboxes with the `bwrap` found in `PATH`.
+    pub fn new() -> Result<Sandbox> {
+        Ok(Sandbox {
+            bwrap: find_bwrap()?,
+            toolchains: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Prepare a sandbox for a compile in `cwd` that reads `inputs`, and
+    /// writes `outputs`, which are absolute paths.
+    ///
+    /// The compile writes its outputs to empty directories standing in for
+    /// the real ones, so that it can't read what's already there.
+    /// `SandboxedCompile::finish` moves them into place.
+    pub fn prepare(&self,
+                   cwd: &Path,
+                   inputs: &SandboxInputs,
+                   outputs: &[PathBuf])
+                   -> Result<SandboxedCompile> {
+        let mut mounts = BTreeMap::new();
+        for path in SYSTEM_FILES.iter().map(Path::new) {
+            mounts.insert(path.to_owned(), Mount::ReadOnly);
+        }
+        for path in self.toolchain_files(&inputs.compiler)?.iter().chain(&inputs.paths) {
+            mounts.insert(path.clone(), Mount::ReadOnly);
+        }
+        let mut dirs = HashMap::new();
+        let mut moves = vec!();
+        for output in outputs {
+            let (dir, name) = match (output.parent(), output.file_name()) {
+                (Some(dir), Some(name)) => (dir, name),
+                _ => bail!("invalid output path: {:?}", output),
+            };
+            // Directories the compile reads anyway are written to as they
+            // are.
+            let readable = match mounts.get(dir) {
+                Some(&Mount::ReadOnly) => true,
+                Some(&Mount::Writable(ref from)) => from == dir,
+                _ => false,
+            };
+            if readable {
+                mounts.insert(dir.to_owned(), Mount::Writable(dir.to_owned()));
+                continue;
+            }
+            if !dirs.contains_key(dir) {
+                let tmp = TempDir::new_in(dir, ".sccache-sandbox")
+                    .chain_err(|| format!("failed to create a directory in {:?}", dir))?;
+                mounts.insert(dir.to_owned(), Mount::Writable(tmp.path().to_owned()));
+                dirs.insert(dir.to_owned(), tmp);
+            }
+            moves.push((dirs[dir].path().join(name), output.clone()));
+        }
+        mounts.entry(cwd.to_owned()).or_insert(Mount::Dir);
+        Ok(SandboxedCompile {
+            wrapper: CommandWrapper {
+                program: self.bwrap.clone(),
+                args: bwrap_args(cwd, &mounts),
+            },
+            outputs: moves,
+            _dirs: dirs.into_iter().map(|(_, tmp)| tmp).collect(),
+        })
+    }
+
+    /// Get the files of `compiler`, packaging it if it hasn't been yet.
+    fn toolchain_files(&self, compiler: &SandboxCompiler) -> Result<Arc<Vec<PathBuf>>> {
+        let executable = match *compiler {
+            SandboxCompiler::C(ref exe) | SandboxCompiler::Rust(ref exe) => exe,
+        };
+        let mtime = fs::metadata(executable).and_then(|m| m.modified())
+            .chain_err(|| format!("failed to stat compiler {:?}", executable))?;
+        let key = (executable.clone(), mtime);
+        if let Some(files) = self.toolchains.lock().unwrap().get(&key) {
+            return Ok(files.clone());
+        }
+        let (packager, _) = match *compiler {
+            SandboxCompiler::C(ref exe) => pkg::package_c_compiler(exe)?,
+            SandboxCompiler::Rust(ref exe) => pkg::package_rustc(exe)?,
+        };
+        let files = Arc::new(packager.files().map(Path::to_owned).collect::<Vec<_>>());
+        debug!("Sandboxed compiles with {:?} see {} toolchain files", executable, files.len());
+        self.toolchains.lock().unwrap().insert(key, files.clone());
+        Ok(files)
+    }
+}
+
+impl SandboxedCompile {
+    /// What to run the compiler with.
+    pub fn wrapper(&self) -> &CommandWrapper {
+        &self.wrapper
+    }
+
+    /// Move the outputs the compile wrote into place.
+    pub fn finish(self) -> Result<()> {
+        for &(ref from, ref to) in &self.outputs {
+            // A failed compile may not write all its outputs.
+            if from.exists() {
+                fs::rename(from, to)
+                    .chain_err(|| format!("failed to move sandboxed output to {:?}", to))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find `bwrap` in `PATH`.
+pub fn find_bwrap() -> Result<PathBuf> {
+    if !cfg!(target_os = "linux") {
+        bail!("sandboxed compiles are only supported on Linux");
+    }
+    let cwd = env::current_dir()?;
+    match which_in("bwrap", env::var_os("PATH"), &cwd) {
+        Ok(bwrap) => Ok(bwrap),
+        Err(_) => bail!("sandboxed compiles need bubblewrap (`bwrap`) in PATH"),
+    }
+}
+
+/// Get the arguments for `bwrap` to run a command in `cwd`, with `mounts`
+/// visible.
+///
+/// Mounts are made in order of their paths, so that directories are
+/// mounted before the paths inside them.
+fn bwrap_args(cwd: &Path, mounts: &BTreeMap<PathBuf, Mount>) -> Vec<OsString> {
+    let mut args = ["--unshare-all", "--die-with-parent",
+                    "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"].iter()
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+    for (path, mount) in mounts {
+        match *mount {
+            Mount::Dir => {
+                args.push("--dir".into());
+            }
+            Mount::ReadOnly => {
+                args.push("--ro-bind-try".into());
+                args.push(path.into());
+            }
+            Mount::Writable(ref from) => {
+                args.push("--bind".into());
+                args.push(from.into());
+            }
+        }
+        args.push(path.into());
+    }
+    args.push("--chdir".into());
+    args.push(cwd.into());
+    args.push("--".into());
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+    use test::utils::*;
+
+    #[test]
+    fn test_bwrap_args() {
+        let mut mounts = BTreeMap::new();
+        mounts.insert(PathBuf::from("/src/foo.c"), Mount::ReadOnly);
+        mounts.insert(PathBuf::from("/src"), Mount::Writable(PathBuf::from("/src/.tmp")));
+        mounts.insert(PathBuf::from("/build"), Mount::Dir);
+        mounts.insert(PathBuf::from("/usr/bin/gcc"), Mount::ReadOnly);
+        let args = bwrap_args(Path::new("/build"), &mounts);
+        let expected = ["--unshare-all", "--die-with-parent",
+                        "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp",
+                        "--dir", "/build",
+                        "--bind", "/src/.tmp", "/src",
+                        "--ro-bind-try", "/src/foo.c", "/src/foo.c",
+                        "--ro-bind-try", "/usr/bin/gcc", "/usr/bin/gcc",
+                        "--chdir", "/build", "--"];
+        assert_eq!(expected.iter().map(OsString::from).collect::<Vec<_>>(), args);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_prepare() {
+        let f = TestFixture::new();
+        let root = f.tempdir.path();
+        let gcc = f.mk_bin("bin/gcc").unwrap();
+        // Pretend the compiler was packaged already.
+        let mtime = fs::metadata(&gcc).unwrap().modified().unwrap();
+        let mut toolchains = HashMap::new();
+        toolchains.insert((gcc.clone(), mtime), Arc::new(vec![gcc.clone()]));
+        let sandbox = Sandbox {
+            bwrap: PathBuf::from("/usr/bin/bwrap"),
+            toolchains: Mutex::new(toolchains),
+        };
+        let inputs = SandboxInputs {
+            compiler: SandboxCompiler::C(gcc.clone()),
+            paths: vec![root.join("src/foo.c"), root.join("deps")],
+        };
+        let cwd = root.join("build");
+        // One output goes to a directory the compile reads anyway, and one
+        // to a directory it can't see.
+        let outputs = [root.join("deps/foo.d"), root.join("out/foo.o")];
+        fs::create_dir_all(root.join("out")).unwrap();
+        let compile = sandbox.prepare(&cwd, &inputs, &outputs).unwrap();
+        let wrapper = compile.wrapper().clone();
+        assert_eq!(PathBuf::from("/usr/bin/bwrap"), wrapper.program);
+        let out_tmp = fs::read_dir(root.join("out")).unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.file_name().unwrap().to_string_lossy().starts_with(".sccache-sandbox"))
+            .unwrap();
+        let mut expected = ["--unshare-all", "--die-with-parent",
+                            "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"].iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        let mut mounts = vec![
+            (root.join("bin/gcc"), vec![OsString::from("--ro-bind-try"), root.join("bin/gcc").into()]),
+            (root.join("build"), vec!["--dir".into()]),
+            (root.join("deps"), vec!["--bind".into(), root.join("deps").into()]),
+            (root.join("out"), vec!["--bind".into(), out_tmp.clone().into()]),
+            (root.join("src/foo.c"), vec!["--ro-bind-try".into(), root.join("src/foo.c").into()]),
+            (PathBuf::from("/etc/ld.so.cache"), vec!["--ro-bind-try".into(), "/etc/ld.so.cache".into()]),
+        ];
+        mounts.sort();
+        for (path, args) in mounts {
+            expected.extend(args);
+            expected.push(path.into());
+        }
+        expected.extend(vec![OsString::from("--chdir"), cwd.into(), "--".into()]);
+        assert_eq!(expected, wrapper.args);
+        // Outputs written in the sandbox are moved into place.
+        File::create(out_tmp.join("foo.o")).unwrap();
+        compile.finish().unwrap();
+        assert!(root.join("out/foo.o").is_file());
+        assert!(!out_tmp.exists());
+    }
+}
@@ -12,47 +12,63 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use app_dirs::{AppDataType, app_dir};
 use cache::{
+    APP_INFO,
+    Cache,
+    CacheWrite,
     Storage,
     storage_from_environment,
 };
+use commands::DEFAULT_PORT;
 use compiler::{
     CacheControl,
+    CacheWriteInfo,
     Compiler,
     CompilerArguments,
     CompilerHasher,
+    CompilerKind,
     CompileResult,
+    InFlight,
     MissType,
+    Normalizer,
     get_compiler_info,
 };
+use dist;
+use event_log::{CompileEvent, EventLog};
 use filetime::FileTime;
 use futures::future;
 use futures::sync::mpsc;
 use futures::task::{self, Task};
 use futures::{Stream, Sink, Async, AsyncSink, Poll, StartSend, Future};
 use futures_cpupool::CpuPool;
-use jobserver::Client;
+use jobserver::{Client, JobLimit};
+use metrics::Latencies;
 use mock_command::{
     CommandCreatorSync,
     ProcessCommandCreator,
 };
+use num_cpus;
 use number_prefix::{binary_prefix, Prefixed, Standalone};
-use protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
-use std::cell::RefCell;
+use protocol::{Compile, CompileFinished, CompileOutput, CompileResponse, Request, Response, Version};
+use sandbox::Sandbox;
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::fs::metadata;
-use std::io::{self, Write};
-use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
-use std::path::PathBuf;
+use std::fmt;
+use std::fs::{self, metadata, File};
+use std::io::{self, BufReader, Write};
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::process::{Output, ExitStatus};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::u64;
 use tokio_core::net::TcpListener;
-use tokio_core::reactor::{Handle, Core, Timeout};
+use tokio_core::reactor::{Handle, Core, Interval, Timeout};
 use tokio_io::codec::length_delimited::Framed;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_proto::BindServer;
@@ -60,13 +76,28 @@ use tokio_proto::streaming::pipeline::{Frame, ServerProto, Transport};
 use tokio_proto::streaming::{Body, Message};
 use tokio_serde_bincode::{ReadBincode, WriteBincode};
 use tokio_service::Service;
-use util::fmt_duration_as_secs;
+#[cfg(unix)]
+use tokio_uds::UnixListener;
+use serde_json;
+use tempfile::NamedTempFile;
+use util::{duration_as_secs_f64, fmt_duration_as_secs, Digest};
 
 use errors::*;
 
 /// If the server is idle for this many seconds, shut down.
 const DEFAULT_IDLE_TIMEOUT: u64 = 600;
 
+/// How often the server saves its stats to disk, in seconds.
+const STATS_SNAPSHOT_INTERVAL: u64 = 60;
+
+/// How long the checks of a status request may take, in seconds.
+const STATUS_PROBE_TIMEOUT_SECS: u64 = 30;
+
+/// The cache key that status requests write to. Real keys are hex digests,
+/// so it can't clash with one. Every probe overwrites the same entry, so the
+/// storage only ever holds one of them.
+const STATUS_PROBE_KEY: &str = "sccache-status-probe";
+
 /// Get the time the server should idle for before shutting down.
 fn get_idle_timeout() -> u64 {
     // A value of 0 disables idle shutdown entirely.
@@ -76,6 +107,112 @@ fn get_idle_timeout() -> u64 {
         .unwrap_or(DEFAULT_IDLE_TIMEOUT)
 }
 
+/// Get the address to serve metrics on, if any.
+fn get_metrics_addr() -> Result<Option<SocketAddr>> {
+    match env::var("SCCACHE_METRICS_ADDR") {
+        Ok(addr) => {
+            let addr = addr.parse().chain_err(|| {
+                format!("invalid SCCACHE_METRICS_ADDR: {}", addr)
+            })?;
+            Ok(Some(addr))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Get the address of the distributed compilation scheduler, if any.
+fn get_dist_scheduler() -> Result<Option<SocketAddr>> {
+    match env::var("SCCACHE_DIST_SCHEDULER") {
+        Ok(addr) => {
+            let resolved = addr.to_socket_addrs().chain_err(|| {
+                format!("invalid SCCACHE_DIST_SCHEDULER: {}", addr)
+            })?.next();
+            match resolved {
+                Some(addr) => Ok(Some(addr)),
+                None => bail!("SCCACHE_DIST_SCHEDULER didn't resolve to an address: {}", addr),
+            }
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Get the directory to keep packaged toolchains in.
+fn get_toolchain_dir() -> Result<PathBuf> {
+    match env::var_os("SCCACHE_TOOLCHAIN_DIR") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => app_dir(AppDataType::UserData, &APP_INFO, "toolchains")
+            .chain_err(|| "failed to find a directory for toolchains"),
+    }
+}
+
+/// Get the fraction of cache hits to verify by compiling anyway, if any.
+fn get_verify_fraction() -> Result<Option<f64>> {
+    match env::var("SCCACHE_VERIFY_FRACTION") {
+        Ok(val) => {
+            match val.parse::<f64>() {
+                Ok(fraction) if fraction >= 0.0 && fraction <= 1.0 => Ok(Some(fraction)),
+                _ => bail!("invalid SCCACHE_VERIFY_FRACTION, expected a number from 0 to 1: {}", val),
+            }
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Get the normalizer for compiler outputs, if any normalization is enabled.
+fn get_normalizer() -> Result<Option<Normalizer>> {
+    let ar_timestamps = match env::var("SCCACHE_NORMALIZE_AR") {
+        Ok(ref val) if val == "1" => true,
+        _ => false,
+    };
+    let base_dir = env::var_os("SCCACHE_NORMALIZE_BASEDIR").map(PathBuf::from);
+    if !ar_timestamps && base_dir.is_none() {
+        return Ok(None);
+    }
+    let normalizer = Normalizer::new(ar_timestamps, base_dir.as_ref().map(|d| d.as_path()))
+        .chain_err(|| "invalid SCCACHE_NORMALIZE_BASEDIR")?;
+    Ok(Some(normalizer))
+}
+
+/// Whether compiles should run in a sandbox.
+fn get_sandbox() -> bool {
+    match env::var("SCCACHE_SANDBOX") {
+        Ok(ref val) if val == "1" => true,
+        _ => false,
+    }
+}
+
+/// Get the path of the file to log compile events to, if any.
+fn get_event_log() -> Option<PathBuf> {
+    env::var_os("SCCACHE_EVENT_LOG").map(PathBuf::from)
+}
+
+/// Get the path of the file the server listening on `addr` saves its stats
+/// to.
+fn get_stats_file(addr: &ServerAddr) -> Option<PathBuf> {
+    env::var_os("SCCACHE_STATS_FILE")
+        .map(PathBuf::from)
+        .or_else(|| {
+            app_dir(AppDataType::UserData, &APP_INFO, "").ok()
+                .map(|d| d.join(stats_file_name(addr)))
+        })
+}
+
+/// Get the name of the default stats file of the server listening on
+/// `addr`, so that servers listening on different addresses don't
+/// overwrite each other's stats.
+fn stats_file_name(addr: &ServerAddr) -> String {
+    match *addr {
+        ServerAddr::Tcp(port) if port == DEFAULT_PORT => "stats.json".to_owned(),
+        ServerAddr::Tcp(port) => format!("stats-{}.json", port),
+        #[cfg(unix)]
+        ServerAddr::Unix(ref path) => {
+            let mut digest = Digest::new();
+            digest.update(path.to_string_lossy().as_bytes());
+            format!("stats-{}.json", &digest.finish()[..16])
+        }
+    }
+}
+
 fn notify_server_startup_internal<W: Write>(mut w: W, success: bool) -> io::Result<()> {
     let data = [ if success { 0 } else { 1 }; 1];
     try!(w.write_all(&data));
@@ -116,20 +253,80 @@ fn get_signal(_status: ExitStatus) -> i32 {
     panic!("no signals on windows")
 }
 
-/// Start an sccache server, listening on `port`.
+/// The address an sccache server listens on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerAddr {
+    /// A TCP port on 127.0.0.1.
+    Tcp(u16),
+    /// A Unix domain socket at this path.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerAddr::Tcp(port) => write!(f, "127.0.0.1:{}", port),
+            #[cfg(unix)]
+            ServerAddr::Unix(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A listener for client connections.
+enum Listener {
+    Tcp(TcpListener),
+    /// A Unix socket listener, and the path of the socket it's bound to.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Bind a Unix socket listener at `path`.
+///
+/// The socket is only accessible to the current user. A socket left behind
+/// by a server that didn't shut down cleanly is replaced, but binding fails
+/// if another server is still listening on it.
+#[cfg(unix)]
+fn bind_unix(path: &Path, handle: &Handle) -> Result<UnixListener> {
+    use std::os::unix::net::UnixStream;
+
+    if fs::symlink_metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("A server is already listening on {}", path.display());
+        }
+        fs::remove_file(path).chain_err(|| {
+            format!("failed to remove stale socket {}", path.display())
+        })?;
+    }
+    // Create the socket accessible only to the current user, rather than
+    // changing its permissions once others may have connected.
+    let umask = unsafe { ::libc::umask(0o177) };
+    let listener = UnixListener::bind(path, handle);
+    unsafe { ::libc::umask(umask); }
+    listener.chain_err(|| format!("failed to bind {}", path.display()))
+}
+
+/// Start an sccache server, listening on `addr`.
 ///
 /// Spins an event loop handling client connections until a client
 /// requests a shutdown.
-pub fn start_server(port: u16) -> Result<()> {
+pub fn start_server(addr: &ServerAddr) -> Result<()> {
     trace!("start_server");
-    let client = unsafe { Client::new() };
+    // The server outlives the build that started it, so it doesn't use that
+    // build's jobserver. Each compile request says how many job slots it
+    // holds in its own build instead.
+    let client = Client::new_num(num_cpus::get());
     let core = Core::new()?;
     let pool = CpuPool::new(20);
     let storage = storage_from_environment(&pool, &core.handle());
-    let res = SccacheServer::<ProcessCommandCreator>::new(port, pool, core, client, storage);
+    let res = SccacheServer::<ProcessCommandCreator>::new(addr, pool, core, client, storage);
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
     match res {
-        Ok(srv) => {
+        Ok(mut srv) => {
+            if let Err(e) = configure_server(&mut srv, addr) {
+                notify_server_startup(&notify, false)?;
+                return Err(e);
+            }
             notify_server_startup(&notify, true)?;
             srv.run(future::empty::<(), ()>())?;
             Ok(())
@@ -141,9 +338,38 @@ pub fn start_server(port: u16) -> Result<()> {
     }
 }
 
+/// Apply the optional server settings from the environment to `srv`.
+fn configure_server<C: CommandCreatorSync>(srv: &mut SccacheServer<C>,
+                                           addr: &ServerAddr)
+                                           -> Result<()> {
+    if let Some(path) = get_stats_file(addr) {
+        srv.set_stats_file(path);
+    }
+    if let Some(addr) = get_metrics_addr()? {
+        srv.set_metrics_addr(&addr)?;
+    }
+    if let Some(path) = get_event_log() {
+        srv.set_event_log(&path)?;
+    }
+    if let Some(addr) = get_dist_scheduler()? {
+        srv.set_dist_scheduler(addr, dist::get_token()?, &get_toolchain_dir()?)?;
+    }
+    if get_sandbox() {
+        srv.set_sandbox(Sandbox::new()?);
+    }
+    if let Some(fraction) = get_verify_fraction()? {
+        srv.set_verify_fraction(fraction);
+    }
+    if let Some(normalizer) = get_normalizer()? {
+        srv.set_normalizer(normalizer);
+    }
+    Ok(())
+}
+
 pub struct SccacheServer<C: CommandCreatorSync> {
     core: Core,
-    listener: TcpListener,
+    listener: Listener,
+    metrics_listener: Option<TcpListener>,
     rx: mpsc::Receiver<ServerMessage>,
     timeout: Duration,
     service: SccacheService<C>,
@@ -151,16 +377,22 @@ pub struct SccacheServer<C: CommandCreatorSync> {
 }
 
 impl<C: CommandCreatorSync> SccacheServer<C> {
-    pub fn new(port: u16,
+    pub fn new(addr: &ServerAddr,
                pool: CpuPool,
                core: Core,
                client: Client,
                storage: Arc<Storage>) -> Result<SccacheServer<C>> {
         let handle = core.handle();
-        let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
-        let listener = TcpListener::bind(&SocketAddr::V4(addr), &handle)?;
+        let listener = match *addr {
+            ServerAddr::Tcp(port) => {
+                let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
+                Listener::Tcp(TcpListener::bind(&SocketAddr::V4(addr), &handle)?)
+            }
+            #[cfg(unix)]
+            ServerAddr::Unix(ref path) => Listener::Unix(bind_unix(path, &handle)?, path.clone()),
+        };
 
-        // Prepare the service which we'll use to service all incoming TCP
+        // Prepare the service which we'll use to service all incoming client
         // connections.
         let (tx, rx) = mpsc::channel(1);
         let (wait, info) = WaitUntilZero::new();
@@ -174,6 +406,7 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         Ok(SccacheServer {
             core: core,
             listener: listener,
+            metrics_listener: None,
             rx: rx,
             service: service,
             timeout: Duration::from_secs(get_idle_timeout()),
@@ -193,6 +426,70 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         self.service.storage = storage;
     }
 
+    /// Save stats to `path` while running, starting from the stats last
+    /// saved there. If the stats there can't be read, they're left alone
+    /// and stats aren't saved.
+    pub fn set_stats_file(&mut self, path: PathBuf) {
+        match StatsSnapshot::load(&path) {
+            Ok(snapshot) => {
+                *self.service.saved_stats.borrow_mut() = snapshot;
+                self.service.stats_file = Some(path);
+            }
+            Err(e) => warn!("Not saving stats, can't read {}: {}", path.display(), e),
+        }
+    }
+
+    /// Append a record of each compile to the file at `path`.
+    pub fn set_event_log(&mut self, path: &Path) -> Result<()> {
+        let log = EventLog::open(path)?;
+        self.service.event_log = Some(Rc::new(RefCell::new(log)));
+        Ok(())
+    }
+
+    /// Run compiles for cache misses on build workers, using the scheduler
+    /// at `addr` with the cluster's `token`, and packaging compilers in
+    /// `toolchain_dir`.
+    pub fn set_dist_scheduler(&mut self,
+                              addr: SocketAddr,
+                              token: String,
+                              toolchain_dir: &Path)
+                              -> Result<()> {
+        self.service.dist = Some(Arc::new(dist::Client::new(addr, token, toolchain_dir)?));
+        Ok(())
+    }
+
+    /// Run local compiles in sandboxes made by `sandbox`.
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.service.sandbox = Some(Arc::new(sandbox));
+    }
+
+    /// Compile `fraction` of cache hits anyway, and check that the
+    /// results match the cache.
+    pub fn set_verify_fraction(&mut self, fraction: f64) {
+        self.service.verify_fraction = Some(fraction);
+    }
+
+    /// Normalize compiler outputs with `normalizer` before storing them in
+    /// the cache.
+    pub fn set_normalizer(&mut self, normalizer: Normalizer) {
+        self.service.normalizer = Some(Arc::new(normalizer));
+    }
+
+    /// Serve metrics in the Prometheus text format over HTTP on `addr`.
+    #[cfg(feature = "hyper")]
+    pub fn set_metrics_addr(&mut self, addr: &SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr, &self.core.handle()).chain_err(|| {
+            format!("failed to bind metrics listener on {}", addr)
+        })?;
+        self.metrics_listener = Some(listener);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "hyper"))]
+    pub fn set_metrics_addr(&mut self, _addr: &SocketAddr) -> Result<()> {
+        bail!("sccache was built without HTTP support, so it can't serve metrics")
+    }
+
     /// Returns a reference to a thread pool to run work on
     #[allow(dead_code)]
     pub fn pool(&self) -> &CpuPool {
@@ -205,10 +502,14 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         &self.service.creator
     }
 
-    /// Returns the port that this server is bound to
+    /// Returns the address that this server is bound to
     #[allow(dead_code)]
-    pub fn port(&self) -> u16 {
-        self.listener.local_addr().unwrap().port()
+    pub fn addr(&self) -> ServerAddr {
+        match self.listener {
+            Listener::Tcp(ref listener) => ServerAddr::Tcp(listener.local_addr().unwrap().port()),
+            #[cfg(unix)]
+            Listener::Unix(_, ref path) => ServerAddr::Unix(path.clone()),
+        }
     }
 
     /// Runs this server to completion.
@@ -225,16 +526,57 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     fn _run<'a>(self, shutdown: Box<Future<Item = (), Error = ()> + 'a>)
                 -> io::Result<()>
     {
-        let SccacheServer { mut core, listener, rx, service, timeout, wait } = self;
+        let SccacheServer { mut core, listener, metrics_listener, rx, service, timeout, wait } = self;
+
+        if let Some(metrics_listener) = metrics_listener {
+            let (storage, stats, saved_stats) = (service.storage.clone(),
+                                                 service.stats.clone(),
+                                                 service.saved_stats.clone());
+            let info = Rc::new(move || server_info(&*storage, &stats.borrow(), &saved_stats.borrow()));
+            serve_metrics(metrics_listener, &core.handle(), info, service.latencies.clone());
+        }
+
+        // Periodically save stats, so they survive the server being killed.
+        let stats = service.stats.clone();
+        let saved_stats = service.saved_stats.clone();
+        let stats_file = service.stats_file.clone();
+        if let Some(ref path) = stats_file {
+            let interval = Interval::new(Duration::from_secs(STATS_SNAPSHOT_INTERVAL), &core.handle())?;
+            let (stats, saved_stats, path) = (stats.clone(), saved_stats.clone(), path.clone());
+            core.handle().spawn(interval.for_each(move |()| {
+                save_stats(&path, &saved_stats.borrow(), &stats.borrow());
+                Ok(())
+            }).map_err(|e| warn!("Stats snapshot timer failed: {}", e)));
+        }
+
+        // The socket file is removed once we stop accepting connections, so
+        // that clients start a new server rather than failing to connect.
+        let socket_path: Option<PathBuf> = match listener {
+            #[cfg(unix)]
+            Listener::Unix(_, ref path) => Some(path.clone()),
+            _ => None,
+        };
 
         // Create our "server future" which will simply handle all incoming
         // connections in separate tasks.
         let handle = core.handle();
-        let server = listener.incoming().for_each(move |(socket, _addr)| {
-            trace!("incoming connection");
-            SccacheProto.bind_server(&handle, socket, service.clone());
-            Ok(())
-        });
+        let server: Box<Future<Item=(), Error=io::Error> + 'a> = match listener {
+            Listener::Tcp(listener) => {
+                Box::new(listener.incoming().for_each(move |(socket, _addr)| {
+                    trace!("incoming connection");
+                    SccacheProto.bind_server(&handle, socket, service.clone());
+                    Ok(())
+                }))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                Box::new(listener.incoming().for_each(move |(socket, _addr)| {
+                    trace!("incoming connection");
+                    SccacheProto.bind_server(&handle, socket, service.clone());
+                    Ok(())
+                }))
+            }
+        };
 
         // Right now there's a whole bunch of ways to shut down this server for
         // various purposes. These include:
@@ -255,7 +597,7 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         });
 
 	let mut futures = vec![
-            Box::new(server) as Box<Future<Item=_, Error=_>>,
+            server,
             Box::new(shutdown.map_err(|()| {
                 io::Error::new(io::ErrorKind::Other, "shutdown signal failed")
             })),
@@ -280,6 +622,10 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         core.run(server)
             .map_err(|p| p.0)?;
 
+        if let Some(path) = socket_path {
+            drop(fs::remove_file(path));
+        }
+
         info!("moving into the shutdown phase now, waiting at most 10 seconds \
               for all client requests to complete");
 
@@ -293,21 +639,87 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         core.run(wait.select(Timeout::new(Duration::new(10, 0), &handle)?))
             .map_err(|p| p.0)?;
 
+        if let Some(path) = stats_file {
+            save_stats(&path, &saved_stats.borrow(), &stats.borrow());
+        }
+
         info!("ok, fully shutting down now");
 
         Ok(())
     }
 }
 
+/// Serve metrics on `listener`.
+#[cfg(feature = "hyper")]
+fn serve_metrics(listener: TcpListener,
+                 handle: &Handle,
+                 info: Rc<Fn() -> ServerInfo>,
+                 latencies: Rc<RefCell<Latencies>>) {
+    ::metrics::serve(listener, handle, info, latencies);
+}
+
+#[cfg(not(feature = "hyper"))]
+fn serve_metrics(_listener: TcpListener,
+                 _handle: &Handle,
+                 _info: Rc<Fn() -> ServerInfo>,
+                 _latencies: Rc<RefCell<Latencies>>) {
+    unreachable!("set_metrics_addr fails without HTTP support");
+}
+
+/// Get info and stats about the cache in `storage`, given the stats from
+/// this server and those saved by earlier ones.
+fn server_info(storage: &Storage, stats: &ServerStats, saved_stats: &StatsSnapshot) -> ServerInfo {
+    let StatsSnapshot { stats, lifetime } = saved_stats.add(stats);
+    ServerInfo {
+        stats: stats,
+        lifetime_stats: lifetime,
+        cache_location: storage.location(),
+        cache_size: storage.current_size(),
+        max_cache_size: storage.max_size(),
+        version: Version::current().version,
+    }
+}
+
 /// Service implementation for sccache
 #[derive(Clone)]
 struct SccacheService<C: CommandCreatorSync> {
-    /// Server statistics.
+    /// Server statistics, since this server started or the stats were
+    /// last zeroed.
     stats: Rc<RefCell<ServerStats>>,
 
+    /// Statistics saved by earlier servers, which `stats` add to.
+    saved_stats: Rc<RefCell<StatsSnapshot>>,
+
+    /// Latency histograms, for metrics.
+    latencies: Rc<RefCell<Latencies>>,
+
+    /// Where to log compile events, if anywhere.
+    event_log: Option<Rc<RefCell<EventLog>>>,
+
+    /// Where to save statistics, if anywhere.
+    stats_file: Option<PathBuf>,
+
     /// Cache storage.
     storage: Arc<Storage>,
 
+    /// A client for running compiles on build workers, if enabled.
+    dist: Option<Arc<dist::Client>>,
+
+    /// Makes sandboxes for local compiles, if enabled.
+    sandbox: Option<Arc<Sandbox>>,
+
+    /// The fraction of cache hits to verify, if enabled.
+    verify_fraction: Option<f64>,
+
+    /// Normalizes compiler outputs before they're cached, if enabled.
+    normalizer: Option<Arc<Normalizer>>,
+
+    /// The number of compiles that are running or waiting to run.
+    active_compiles: Rc<Cell<usize>>,
+
+    /// Compiles in progress, by hash key.
+    in_flight: InFlight,
+
     /// A cache of known compiler info.
     compilers: Rc<RefCell<HashMap<PathBuf, Option<(Box<Compiler<C>>, FileTime)>>>>,
 
@@ -380,6 +792,16 @@ impl<C> Service for SccacheService<C>
                 self.zero_stats();
                 Response::Stats(self.get_info())
             }
+            Request::GetVersion => {
+                debug!("handle_client: get_version");
+                Response::Version(Version::current())
+            }
+            Request::Status => {
+                debug!("handle_client: status");
+                return Box::new(self.get_status().map(|status| {
+                    Message::WithoutBody(Response::Status(status))
+                }))
+            }
             Request::Shutdown => {
                 debug!("handle_client: shutdown");
                 let future = self.tx.clone().send(ServerMessage::Shutdown);
@@ -405,7 +827,17 @@ impl<C> SccacheService<C>
                info: ActiveInfo) -> SccacheService<C> {
         SccacheService {
             stats: Rc::new(RefCell::new(ServerStats::default())),
+            saved_stats: Rc::new(RefCell::new(StatsSnapshot::default())),
+            latencies: Rc::new(RefCell::new(Latencies::default())),
+            event_log: None,
+            stats_file: None,
             storage: storage,
+            dist: None,
+            sandbox: None,
+            verify_fraction: None,
+            normalizer: None,
+            active_compiles: Rc::new(Cell::new(0)),
+            in_flight: InFlight::new(),
             compilers: Rc::new(RefCell::new(HashMap::new())),
             pool: pool,
             creator: C::new(&handle, client),
@@ -417,17 +849,53 @@ impl<C> SccacheService<C>
 
     /// Get info and stats about the cache.
     fn get_info(&self) -> ServerInfo {
-        ServerInfo {
-            stats: self.stats.borrow().clone(),
+        server_info(&*self.storage, &self.stats.borrow(), &self.saved_stats.borrow())
+    }
+
+    /// Check the health of the server, by writing to its storage and
+    /// reading it back, and getting its storage credentials.
+    fn get_status(&self) -> SFuture<ServerStatus> {
+        let timeout = Duration::from_secs(STATUS_PROBE_TIMEOUT_SECS);
+        let probe = with_timeout(probe_storage(self.storage.clone()), timeout, &self.handle)
+            .then(|res| -> Result<_> { Ok(res) });
+        let credentials = with_timeout(self.storage.credentials_expire_at(), timeout, &self.handle)
+            .then(|res| -> Result<_> { Ok(res) });
+        let mut status = ServerStatus {
+            version: Version::current().version,
             cache_location: self.storage.location(),
-            cache_size: self.storage.current_size(),
-            max_cache_size: self.storage.max_size(),
-        }
+            storage_probe_duration: None,
+            storage_error: None,
+            credentials_expire_at: None,
+            credentials_error: None,
+            active_compiles: self.active_compiles.get(),
+            cached_compilers: self.compilers.borrow().len(),
+        };
+        Box::new(probe.join(credentials).map(move |(probe, credentials)| {
+            match probe {
+                Ok(duration) => status.storage_probe_duration = Some(duration),
+                Err(e) => status.storage_error = Some(error_chain_string(&e)),
+            }
+            match credentials {
+                Ok(expire_at) => status.credentials_expire_at = expire_at,
+                Err(e) => status.credentials_error = Some(error_chain_string(&e)),
+            }
+            status
+        }))
     }
 
     /// Zero stats about the cache.
+    ///
+    /// Lifetime stats are kept.
     fn zero_stats(&self) {
+        let lifetime = self.saved_stats.borrow().add(&self.stats.borrow()).lifetime;
+        *self.saved_stats.borrow_mut() = StatsSnapshot {
+            stats: ServerStats::default(),
+            lifetime: lifetime,
+        };
         *self.stats.borrow_mut() = ServerStats::default();
+        if let Some(ref path) = self.stats_file {
+            save_stats(path, &self.saved_stats.borrow(), &self.stats.borrow());
+        }
     }
 
 
@@ -443,9 +911,10 @@ impl<C> SccacheService<C>
         let cmd = compile.args;
         let cwd = compile.cwd;
         let env_vars = compile.env_vars;
+        let jobs = compile.extra_tokens + 1;
         let me = self.clone();
         Box::new(self.compiler_info(exe.into()).map(move |info| {
-            me.check_compiler(info, cmd, cwd.into(), env_vars)
+            me.check_compiler(info, cmd, cwd.into(), env_vars, jobs)
         }))
     }
 
@@ -493,7 +962,8 @@ impl<C> SccacheService<C>
                       compiler: Option<Box<Compiler<C>>>,
                       cmd: Vec<OsString>,
                       cwd: PathBuf,
-                      env_vars: Vec<(OsString, OsString)>) -> SccacheResponse
+                      env_vars: Vec<(OsString, OsString)>,
+                      jobs: usize) -> SccacheResponse
     {
         let mut stats = self.stats.borrow_mut();
         match compiler {
@@ -510,14 +980,29 @@ impl<C> SccacheService<C>
                         debug!("parse_arguments: Ok: {:?}", cmd);
                         stats.requests_executed += 1;
                         let (tx, rx) = Body::pair();
-                        self.start_compile_task(hasher, cmd, cwd, env_vars, tx);
+                        self.start_compile_task(c.kind(), vec![hasher], cmd, cwd, env_vars, jobs, tx);
+                        let res = CompileResponse::CompileStarted;
+                        return Message::WithBody(Response::Compile(res), rx)
+                    }
+                    CompilerArguments::Multiple(hashers) => {
+                        debug!("parse_arguments: Multiple({}): {:?}", hashers.len(), cmd);
+                        stats.requests_executed += hashers.len() as u64;
+                        let (tx, rx) = Body::pair();
+                        self.start_compile_task(c.kind(), hashers, cmd, cwd, env_vars, jobs, tx);
                         let res = CompileResponse::CompileStarted;
                         return Message::WithBody(Response::Compile(res), rx)
                     }
                     CompilerArguments::CannotCache(why) => {
-                        //TODO: save counts of why
                         debug!("parse_arguments: CannotCache({}): {:?}", why, cmd);
                         stats.requests_not_cacheable += 1;
+                        *stats.not_cached.entry(why.to_owned()).or_insert(0) += 1;
+                        if let Some(ref log) = self.event_log {
+                            let mut event = CompileEvent::new(&cwd, c.kind().as_str(),
+                                                              "not_cacheable",
+                                                              Duration::new(0, 0));
+                            event.not_cacheable_reason = Some(why.to_owned());
+                            log.borrow_mut().log(&event);
+                        }
                     }
                     CompilerArguments::NotCompilation => {
                         debug!("parse_arguments: NotCompilation: {:?}", cmd);
@@ -534,137 +1019,323 @@ impl<C> SccacheService<C>
     /// Given compiler arguments `arguments`, look up
     /// a compile result in the cache or execute the compilation and store
     /// the result in the cache.
+    ///
+    /// If `hashers` has several entries, the command compiles several
+    /// inputs, each of which is looked up and compiled separately. Their
+    /// outputs are combined in order into a single response, and at most
+    /// `jobs` of them are compiled at once.
     fn start_compile_task(&self,
-                          hasher: Box<CompilerHasher<C>>,
+                          kind: CompilerKind,
+                          hashers: Vec<Box<CompilerHasher<C>>>,
                           arguments: Vec<OsString>,
                           cwd: PathBuf,
                           env_vars: Vec<(OsString, OsString)>,
+                          jobs: usize,
                           tx: mpsc::Sender<Result<Response>>) {
         let force_recache = env_vars.iter().any(|&(ref k, ref _v)| {
             k.as_os_str() == OsStr::new("SCCACHE_RECACHE")
         });
-        let cache_control = if force_recache {
-            CacheControl::ForceRecache
+        let cache_control = match (force_recache, self.verify_fraction) {
+            (true, _) => CacheControl::ForceRecache,
+            (false, Some(fraction)) => CacheControl::Verify(fraction),
+            (false, None) => CacheControl::Default,
+        };
+        let kind = kind.as_str();
+
+        // Send the output of a single compile to the client as it's
+        // produced. The outputs of several compiles are only sent once they
+        // have all finished, so that they aren't interleaved. Keep track of
+        // how much was sent, so it isn't sent again in `CompileFinished`.
+        let (output, forward) = if hashers.len() == 1 {
+            let (output_tx, output_rx) = mpsc::unbounded();
+            let forward = output_rx.fold((Some(tx), 0, 0), |(tx, stdout_len, stderr_len), chunk| {
+                let (stdout_len, stderr_len) = match chunk {
+                    CompileOutput::Stdout(ref data) => (stdout_len + data.len(), stderr_len),
+                    CompileOutput::Stderr(ref data) => (stdout_len, stderr_len + data.len()),
+                };
+                // If the client has gone away, keep going so that the
+                // compile still gets cached.
+                let send: Box<Future<Item = _, Error = ()>> = match tx {
+                    Some(tx) => Box::new(tx.send(Ok(Response::CompileOutput(chunk)))
+                                         .then(|res| Ok(res.ok()))),
+                    None => Box::new(future::ok(None)),
+                };
+                send.map(move |tx| (tx, stdout_len, stderr_len))
+            });
+            (Some(output_tx), Box::new(forward) as Box<Future<Item = _, Error = ()>>)
         } else {
-            CacheControl::Default
+            (None, Box::new(future::ok((Some(tx), 0, 0))) as Box<Future<Item = _, Error = ()>>)
         };
-        let out_pretty = hasher.output_pretty().into_owned();
-        let result = hasher.get_cached_or_compile(self.creator.clone(),
-                                                  self.storage.clone(),
-                                                  arguments,
-                                                  cwd,
-                                                  env_vars,
-                                                  cache_control,
-                                                  self.pool.clone(),
-                                                  self.handle.clone());
+
+        // A single compile has nothing to wait for.
+        let limit = if hashers.len() > 1 { Some(JobLimit::new(jobs)) } else { None };
+        let tasks = hashers.into_iter().map(|hasher| {
+            let out_pretty = hasher.output_pretty().into_owned();
+            let language = hasher.language();
+            let start = Instant::now();
+            let active = self.active_compiles.clone();
+            active.set(active.get() + 1);
+            let result = hasher.get_cached_or_compile(self.creator.clone(),
+                                                      self.storage.clone(),
+                                                      arguments.clone(),
+                                                      cwd.clone(),
+                                                      env_vars.clone(),
+                                                      cache_control,
+                                                      self.pool.clone(),
+                                                      self.handle.clone(),
+                                                      output.clone(),
+                                                      limit.clone(),
+                                                      self.dist.clone(),
+                                                      self.sandbox.clone(),
+                                                      self.normalizer.clone(),
+                                                      Some(self.in_flight.clone()));
+            result.then(move |result| -> ::std::result::Result<_, ()> {
+                active.set(active.get() - 1);
+                Ok((out_pretty, language, start.elapsed(), result))
+            })
+        }).collect::<Vec<_>>();
         let me = self.clone();
-        let task = result.then(move |result| {
-            let mut cache_write = None;
-            let mut stats = me.stats.borrow_mut();
+        // Drop our end of the output channel, so that forwarding output
+        // finishes along with the compiles.
+        drop(output);
+        let task = future::join_all(tasks).join(forward).and_then(move |(results, forwarded)| {
+            let (tx, stdout_len, stderr_len) = forwarded;
+            let mut cache_writes = vec!();
             let mut res = CompileFinished::default();
-            match result {
-                Ok((compiled, out)) => {
-                    match compiled {
-                        CompileResult::Error => {
-                            stats.cache_errors += 1;
-                        }
-                        CompileResult::CacheHit(duration) => {
-                            stats.cache_hits += 1;
-                            stats.cache_read_hit_duration += duration;
-                        },
-                        CompileResult::CacheMiss(miss_type, duration, future) => {
-                            match miss_type {
-                                MissType::Normal => {}
-                                MissType::ForcedRecache => {
-                                    stats.forced_recaches += 1;
-                                }
-                                MissType::TimedOut => {
-                                    stats.cache_timeouts += 1;
-                                }
-                                MissType::CacheReadError => {
-                                    stats.cache_errors += 1;
-                                }
-                            }
-                            stats.cache_misses += 1;
-                            stats.cache_read_miss_duration += duration;
-                            cache_write = Some(future);
-                        }
-                        CompileResult::NotCacheable => {
-                            stats.cache_misses += 1;
-                            stats.non_cacheable_compilations += 1;
-                        }
-                        CompileResult::CompileFailed => {
-                            stats.compile_fails += 1;
-                        }
-                    };
-                    let Output { status, stdout, stderr } = out;
-                    trace!("CompileFinished retcode: {}", status);
-                    match status.code() {
-                        Some(code) => res.retcode = Some(code),
-                        None => res.signal = Some(get_signal(status)),
-                    };
-                    res.stdout = stdout;
-                    res.stderr = stderr;
-                }
-                Err(Error(ErrorKind::ProcessError(output), _)) => {
-                    debug!("Compilation failed: {:?}", output);
-                    stats.compile_fails += 1;
-                    match output.status.code() {
-                        Some(code) => res.retcode = Some(code),
-                        None => res.signal = Some(get_signal(output.status)),
-                    };
-                    res.stdout = output.stdout;
-                    res.stderr = output.stderr;
+            // sccache's own error messages, which weren't streamed.
+            let mut errors = vec!();
+            for (out_pretty, language, duration, result) in results {
+                let finished = me.finish_compile(&out_pretty, kind, language, &cwd, duration,
+                                                 result, &mut cache_writes);
+                if finished.server_error.is_some() {
+                    errors.extend(finished.stderr);
+                } else {
+                    res.stdout.extend(finished.stdout);
+                    res.stderr.extend(finished.stderr);
                 }
-                Err(err) => {
-                    use std::fmt::Write;
-
-                    error!("[{:?}] fatal error: {}", out_pretty, err);
-
-                    let mut error = format!("sccache: encountered fatal error\n");
-                    drop(writeln!(error, "sccache: error : {}", err));
-                    for e in err.iter() {
-                        error!("[{:?}] \t{}", out_pretty, e);
-                        drop(writeln!(error, "sccache:  cause: {}", e));
+                res.server_error = res.server_error.or(finished.server_error);
+                // Like the compiler would, report the status of the first
+                // compile that didn't succeed.
+                match (res.retcode, res.signal) {
+                    (None, None) | (Some(0), _) => {
+                        res.retcode = finished.retcode;
+                        res.signal = finished.signal;
                     }
-                    stats.cache_errors += 1;
-                    //TODO: figure out a better way to communicate this?
-                    res.retcode = Some(-2);
-                    res.stderr = error.into_bytes();
+                    _ => {}
                 }
+            }
+            let stdout_len = cmp::min(stdout_len, res.stdout.len());
+            let stderr_len = cmp::min(stderr_len, res.stderr.len());
+            res.stdout.drain(..stdout_len);
+            res.stderr.drain(..stderr_len);
+            res.stderr.extend(errors);
+            let send: Box<Future<Item = (), Error = ()>> = match tx {
+                Some(tx) => Box::new(tx.send(Ok(Response::CompileFinished(res))).then(|_| Ok(()))),
+                None => Box::new(future::ok(())),
             };
-            let send = tx.send(Ok(Response::CompileFinished(res)));
-
-            let me = me.clone();
-            let cache_write = cache_write.then(move |result| {
-                match result {
-                    Err(e) => {
-                        debug!("Error executing cache write: {}", e);
-                        me.stats.borrow_mut().cache_write_errors += 1;
-                    }
-                    //TODO: save cache stats!
-                    Ok(Some(info)) => {
-                        debug!("[{}]: Cache write finished in {}",
-                               info.object_file_pretty,
-                               fmt_duration_as_secs(&info.duration));
-                        me.stats.borrow_mut().cache_writes += 1;
-                        me.stats.borrow_mut().cache_write_duration += info.duration;
-                    }
 
-                    Ok(None) => {}
-                }
-                Ok(())
-            });
+            let cache_writes = cache_writes.into_iter().map(|(language, cache_write)| {
+                let me = me.clone();
+                cache_write.then(move |result| {
+                    match result {
+                        Err(e) => {
+                            debug!("Error executing cache write: {}", e);
+                            me.stats.borrow_mut().cache_write_errors += 1;
+                        }
+                        //TODO: save cache stats!
+                        Ok(info) => {
+                            debug!("[{}]: Cache write finished in {}",
+                                   info.object_file_pretty,
+                                   fmt_duration_as_secs(&info.duration));
+                            me.latencies.borrow_mut().cache_write.observe(info.duration);
+                            let mut stats = me.stats.borrow_mut();
+                            stats.cache_writes += 1;
+                            stats.cache_write_duration += info.duration;
+                            stats.record(kind, language, |s| {
+                                s.cache_writes += 1;
+                                s.cache_write_duration += info.duration;
+                            });
+                        }
+                    }
+                    Ok(())
+                })
+            }).collect::<Vec<_>>();
 
-            send.join(cache_write).then(|_| Ok(()))
+            send.join(future::join_all(cache_writes)).then(|_| Ok(()))
         });
 
         self.handle.spawn(task);
     }
+
+    /// Record the outcome `result` of a single compile of `language` by a
+    /// `kind` compiler in `cwd`, which took `duration`, in the server
+    /// statistics and the event log, and turn it into a `CompileFinished`
+    /// for the client.
+    ///
+    /// If the result still has to be written to the cache, the pending
+    /// write is pushed onto `cache_writes`.
+    fn finish_compile(&self,
+                      out_pretty: &str,
+                      kind: &'static str,
+                      language: &'static str,
+                      cwd: &Path,
+                      duration: Duration,
+                      result: Result<(CompileResult, Output, Option<String>)>,
+                      cache_writes: &mut Vec<(&'static str, SFuture<CacheWriteInfo>)>)
+                      -> CompileFinished
+    {
+        let mut stats = self.stats.borrow_mut();
+        let mut res = CompileFinished::default();
+        let mut event = CompileEvent::new(cwd, kind, "error", duration);
+        event.language = Some(language.to_owned());
+        event.output = Some(out_pretty.to_owned());
+        match result {
+            Ok((compiled, out, hash_key)) => {
+                event.hash_key = hash_key;
+                if let CompileResult::VerifyError(..) = compiled {
+                    stats.cache_verify_errors += 1;
+                }
+                match compiled {
+                    CompileResult::Error => {
+                        stats.cache_errors += 1;
+                    }
+                    CompileResult::CacheHit(duration, compile_time) |
+                    CompileResult::VerifyError(duration, compile_time) => {
+                        event.result = "hit".to_owned();
+                        event.cache_read_duration = Some(duration_as_secs_f64(&duration));
+                        event.compile_duration = compile_time.as_ref().map(duration_as_secs_f64);
+                        self.latencies.borrow_mut().cache_read.observe(duration);
+                        stats.cache_hits += 1;
+                        stats.cache_read_hit_duration += duration;
+                        if let Some(compile_time) = compile_time {
+                            // Cache entries from before compile times were
+                            // recorded don't tell us anything here.
+                            stats.time_saved_hits += 1;
+                            stats.time_saved += compile_time.checked_sub(duration)
+                                .unwrap_or(Duration::new(0, 0));
+                        }
+                        stats.record(kind, language, |s| {
+                            s.cache_hits += 1;
+                            s.cache_read_hit_duration += duration;
+                        });
+                    },
+                    CompileResult::CacheMiss(miss_type, duration, compile_duration, future) => {
+                        event.result = "miss".to_owned();
+                        event.miss_type = Some(miss_type.as_str().to_owned());
+                        event.cache_read_duration = Some(duration_as_secs_f64(&duration));
+                        event.compile_duration = Some(duration_as_secs_f64(&compile_duration));
+                        match miss_type {
+                            MissType::Normal => {}
+                            MissType::ForcedRecache => {
+                                stats.forced_recaches += 1;
+                            }
+                            MissType::TimedOut => {
+                                stats.cache_timeouts += 1;
+                            }
+                            MissType::CacheReadError => {
+                                stats.cache_errors += 1;
+                            }
+                        }
+                        let mut latencies = self.latencies.borrow_mut();
+                        latencies.cache_read.observe(duration);
+                        latencies.compile.observe(compile_duration);
+                        stats.cache_misses += 1;
+                        stats.cache_read_miss_duration += duration;
+                        stats.record(kind, language, |s| {
+                            s.cache_misses += 1;
+                            s.cache_read_miss_duration += duration;
+                            s.compiles += 1;
+                            s.compile_duration += compile_duration;
+                        });
+                        cache_writes.push((language, future));
+                    }
+                    CompileResult::NotCacheable => {
+                        event.result = "not_cacheable".to_owned();
+                        stats.cache_misses += 1;
+                        stats.non_cacheable_compilations += 1;
+                        stats.record(kind, language, |s| {
+                            s.cache_misses += 1;
+                            s.not_cacheable += 1;
+                        });
+                    }
+                    CompileResult::CompileFailed => {
+                        event.result = "compile_failed".to_owned();
+                        stats.compile_fails += 1;
+                        stats.record(kind, language, |s| s.compile_fails += 1);
+                    }
+                    CompileResult::Verified(duration, compile_duration, mismatches) => {
+                        event.result = "hit".to_owned();
+                        event.cache_read_duration = Some(duration_as_secs_f64(&duration));
+                        event.compile_duration = Some(duration_as_secs_f64(&compile_duration));
+                        let mut latencies = self.latencies.borrow_mut();
+                        latencies.cache_read.observe(duration);
+                        latencies.compile.observe(compile_duration);
+                        stats.cache_hits += 1;
+                        stats.cache_read_hit_duration += duration;
+                        stats.cache_verifications += 1;
+                        if !mismatches.is_empty() {
+                            stats.cache_verify_mismatches += 1;
+                        }
+                        stats.record(kind, language, |s| {
+                            s.cache_hits += 1;
+                            s.cache_read_hit_duration += duration;
+                        });
+                        event.verify_mismatches = Some(mismatches);
+                    }
+                };
+                let Output { status, stdout, stderr } = out;
+                trace!("CompileFinished retcode: {}", status);
+                match status.code() {
+                    Some(code) => res.retcode = Some(code),
+                    None => res.signal = Some(get_signal(status)),
+                };
+                res.stdout = stdout;
+                res.stderr = stderr;
+            }
+            Err(Error(ErrorKind::ProcessError(output), _)) => {
+                debug!("Compilation failed: {:?}", output);
+                event.result = "compile_failed".to_owned();
+                stats.compile_fails += 1;
+                stats.record(kind, language, |s| s.compile_fails += 1);
+                match output.status.code() {
+                    Some(code) => res.retcode = Some(code),
+                    None => res.signal = Some(get_signal(output.status)),
+                };
+                res.stdout = output.stdout;
+                res.stderr = output.stderr;
+            }
+            Err(err) => {
+                use std::fmt::Write;
+
+                error!("[{:?}] fatal error: {}", out_pretty, err);
+
+                let mut error = format!("sccache: encountered fatal error\n");
+                drop(writeln!(error, "sccache: error : {}", err));
+                for e in err.iter() {
+                    error!("[{:?}] \t{}", out_pretty, e);
+                    drop(writeln!(error, "sccache:  cause: {}", e));
+                }
+                event.error = Some(err.to_string());
+                res.server_error = Some(err.to_string());
+                stats.cache_errors += 1;
+                //TODO: figure out a better way to communicate this?
+                res.retcode = Some(-2);
+                res.stderr = error.into_bytes();
+            }
+        };
+        if let Some(ref log) = self.event_log {
+            log.borrow_mut().log(&event);
+        }
+        res
+    }
 }
 
 /// Statistics about the server.
+///
+/// Stats saved by older servers lack the newer fields, which default to
+/// zero.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct ServerStats {
     /// The count of client compile requests.
     pub compile_requests: u64,
@@ -674,6 +1345,8 @@ pub struct ServerStats {
     pub requests_not_compile: u64,
     /// The count of client requests that were not cacheable.
     pub requests_not_cacheable: u64,
+    /// The count of client requests that were not cacheable, by reason.
+    pub not_cached: HashMap<String, u64>,
     /// The count of client requests that were executed.
     pub requests_executed: u64,
     /// The count of errors handling compile requests.
@@ -700,17 +1373,88 @@ pub struct ServerStats {
     pub cache_read_hit_duration: Duration,
     /// The total time spent reading cache misses.
     pub cache_read_miss_duration: Duration,
+    /// The count of cache hits whose original compile time is known.
+    pub time_saved_hits: u64,
+    /// The estimated time saved by cache hits: how long their original
+    /// compiles took, minus the time taken to read them from the cache.
+    pub time_saved: Duration,
     /// The count of compilation failures.
     pub compile_fails: u64,
+    /// The count of cache hits that were compiled again to check them.
+    pub cache_verifications: u64,
+    /// The count of cache hits whose outputs didn't match when compiled
+    /// again.
+    pub cache_verify_mismatches: u64,
+    /// The count of cache hits that couldn't be compiled again to check
+    /// them.
+    pub cache_verify_errors: u64,
+    /// Stats broken down by kind of compiler.
+    pub by_compiler: HashMap<String, BreakdownStats>,
+    /// Stats broken down by source language.
+    pub by_language: HashMap<String, BreakdownStats>,
+}
+
+/// Statistics about the compiles done by one kind of compiler, or for one
+/// source language.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct BreakdownStats {
+    /// The count of cache hits.
+    pub cache_hits: u64,
+    /// The count of cache misses.
+    pub cache_misses: u64,
+    /// The count of compiles done for cache misses.
+    pub compiles: u64,
+    /// The total time spent compiling cache misses.
+    pub compile_duration: Duration,
+    /// The number of successful cache writes.
+    pub cache_writes: u64,
+    /// The total time spent writing cache entries.
+    pub cache_write_duration: Duration,
+    /// The total time spent reading cache hits.
+    pub cache_read_hit_duration: Duration,
+    /// The total time spent reading cache misses.
+    pub cache_read_miss_duration: Duration,
+    /// The count of cache misses whose results couldn't be cached.
+    pub not_cacheable: u64,
+    /// The count of compiles that failed.
+    pub compile_fails: u64,
+}
+
+/// The health of the server.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerStatus {
+    /// The version of the server.
+    pub version: String,
+    pub cache_location: String,
+    /// How long writing a small entry to the cache storage and reading it
+    /// back took, if it worked.
+    pub storage_probe_duration: Option<Duration>,
+    /// Why writing to the cache storage and reading back failed, if it did.
+    pub storage_error: Option<String>,
+    /// When the storage credentials expire, in seconds since the Unix
+    /// epoch, if they do.
+    pub credentials_expire_at: Option<i64>,
+    /// Why getting the storage credentials failed, if it did.
+    pub credentials_error: Option<String>,
+    /// The number of compiles that are running or waiting to run.
+    pub active_compiles: usize,
+    /// The number of compilers the server has cached information about.
+    pub cached_compilers: usize,
 }
 
 /// Info and stats about the server.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerInfo {
+    /// Stats since they were last zeroed.
     pub stats: ServerStats,
+    /// Stats since they were first saved, which zeroing doesn't reset.
+    pub lifetime_stats: ServerStats,
     pub cache_location: String,
     pub cache_size: Option<u64>,
     pub max_cache_size: Option<u64>,
+    /// The version of the server.
+    pub version: String,
 }
 
 impl Default for ServerStats {
@@ -720,6 +1464,7 @@ impl Default for ServerStats {
             requests_unsupported_compiler: u64::default(),
             requests_not_compile: u64::default(),
             requests_not_cacheable: u64::default(),
+            not_cached: HashMap::new(),
             requests_executed: u64::default(),
             cache_errors: u64::default(),
             cache_hits: u64::default(),
@@ -733,12 +1478,129 @@ impl Default for ServerStats {
             cache_write_duration: Duration::new(0, 0),
             cache_read_hit_duration: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
+            time_saved_hits: u64::default(),
+            time_saved: Duration::new(0, 0),
             compile_fails: u64::default(),
+            cache_verifications: u64::default(),
+            cache_verify_mismatches: u64::default(),
+            cache_verify_errors: u64::default(),
+            by_compiler: HashMap::new(),
+            by_language: HashMap::new(),
         }
     }
 }
 
+/// Server stats as saved to disk.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct StatsSnapshot {
+    /// Stats since they were last zeroed.
+    stats: ServerStats,
+    /// Stats since they were first saved.
+    lifetime: ServerStats,
+}
+
+impl StatsSnapshot {
+    /// Load the stats saved to `path`, or empty stats if there are none.
+    fn load(path: &Path) -> Result<StatsSnapshot> {
+        let f = match File::open(path) {
+            Ok(f) => f,
+            Err(_) => return Ok(StatsSnapshot::default()),
+        };
+        Ok(serde_json::from_reader(BufReader::new(f))?)
+    }
+
+    /// Atomically save these stats to `path`.
+    fn save(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(dir) => dir,
+            None => bail!("Stats file without a parent directory!"),
+        };
+        fs::create_dir_all(dir)?;
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(&mut tmp, self)?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+
+    /// Return these stats with `stats` added to both the since-zeroed and
+    /// lifetime stats.
+    fn add(&self, stats: &ServerStats) -> StatsSnapshot {
+        let mut snapshot = self.clone();
+        snapshot.stats.add(stats);
+        snapshot.lifetime.add(stats);
+        snapshot
+    }
+}
+
+/// Save `saved` with `stats` added to `path`, logging any failure.
+fn save_stats(path: &Path, saved: &StatsSnapshot, stats: &ServerStats) {
+    trace!("save_stats({})", path.display());
+    if let Err(e) = saved.add(stats).save(path) {
+        warn!("Failed to save stats to {}: {}", path.display(), e);
+    }
+}
+
+impl BreakdownStats {
+    /// Add the counts and durations in `other` to these stats.
+    fn add(&mut self, other: &BreakdownStats) {
+        self.cache_hits += other.cache_hits;
+        self.cache_misses += other.cache_misses;
+        self.compiles += other.compiles;
+        self.compile_duration += other.compile_duration;
+        self.cache_writes += other.cache_writes;
+        self.cache_write_duration += other.cache_write_duration;
+        self.cache_read_hit_duration += other.cache_read_hit_duration;
+        self.cache_read_miss_duration += other.cache_read_miss_duration;
+        self.not_cacheable += other.not_cacheable;
+        self.compile_fails += other.compile_fails;
+    }
+}
+
 impl ServerStats {
+    /// Add the counts and durations in `other` to these stats.
+    fn add(&mut self, other: &ServerStats) {
+        self.compile_requests += other.compile_requests;
+        self.requests_unsupported_compiler += other.requests_unsupported_compiler;
+        self.requests_not_compile += other.requests_not_compile;
+        self.requests_not_cacheable += other.requests_not_cacheable;
+        for (reason, count) in &other.not_cached {
+            *self.not_cached.entry(reason.clone()).or_insert(0) += *count;
+        }
+        self.requests_executed += other.requests_executed;
+        self.cache_errors += other.cache_errors;
+        self.cache_hits += other.cache_hits;
+        self.cache_misses += other.cache_misses;
+        self.cache_timeouts += other.cache_timeouts;
+        self.cache_read_errors += other.cache_read_errors;
+        self.non_cacheable_compilations += other.non_cacheable_compilations;
+        self.forced_recaches += other.forced_recaches;
+        self.cache_write_errors += other.cache_write_errors;
+        self.cache_writes += other.cache_writes;
+        self.cache_write_duration += other.cache_write_duration;
+        self.cache_read_hit_duration += other.cache_read_hit_duration;
+        self.cache_read_miss_duration += other.cache_read_miss_duration;
+        self.time_saved_hits += other.time_saved_hits;
+        self.time_saved += other.time_saved;
+        self.compile_fails += other.compile_fails;
+        self.cache_verifications += other.cache_verifications;
+        self.cache_verify_mismatches += other.cache_verify_mismatches;
+        self.cache_verify_errors += other.cache_verify_errors;
+        for (kind, stats) in &other.by_compiler {
+            self.by_compiler.entry(kind.clone()).or_insert_with(BreakdownStats::default).add(stats);
+        }
+        for (language, stats) in &other.by_language {
+            self.by_language.entry(language.clone()).or_insert_with(BreakdownStats::default).add(stats);
+        }
+    }
+
+    /// Update the stats for `kind` compilers and for `language` with `f`.
+    fn record<F>(&mut self, kind: &str, language: &str, f: F)
+        where F: Fn(&mut BreakdownStats)
+    {
+        f(self.by_compiler.entry(kind.to_owned()).or_insert_with(BreakdownStats::default));
+        f(self.by_language.entry(language.to_owned()).or_insert_with(BreakdownStats::default));
+    }
+
     /// Print stats to stdout in a human-readable format.
     ///
     /// Return the formatted width of each of the (name, value) columns.
@@ -772,6 +1634,9 @@ impl ServerStats {
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
         set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
+        set_stat!(stats_vec, self.cache_verifications, "Cache hits verified");
+        set_stat!(stats_vec, self.cache_verify_mismatches, "Cache verify mismatches");
+        set_stat!(stats_vec, self.cache_verify_errors, "Cache verify errors");
         set_stat!(stats_vec, self.compile_fails, "Compilation failures");
         set_stat!(stats_vec, self.cache_errors, "Cache errors");
         set_stat!(stats_vec, self.non_cacheable_compilations, "Non-cacheable compilations");
@@ -781,6 +1646,8 @@ impl ServerStats {
         set_duration_stat!(stats_vec, self.cache_write_duration, self.cache_writes, "Average cache write");
         set_duration_stat!(stats_vec, self.cache_read_miss_duration, self.cache_misses, "Average cache read miss");
         set_duration_stat!(stats_vec, self.cache_read_hit_duration, self.cache_hits, "Average cache read hit");
+        stats_vec.push(("Time saved", fmt_duration_as_secs(&self.time_saved), 2));
+        set_duration_stat!(stats_vec, self.time_saved, self.time_saved_hits, "Average time saved per hit");
         let name_width = stats_vec.iter().map(|&(ref n, _, _)| n.len()).max().unwrap();
         let stat_width = stats_vec.iter().map(|&(_, ref s, _)| s.len()).max().unwrap();
         for (name, stat, suffix_len) in stats_vec {
@@ -793,8 +1660,19 @@ impl ServerStats {
 impl ServerInfo {
     /// Print info to stdout in a human-readable format.
     pub fn print(&self) {
-        let (name_width, stat_width) = self.stats.print();
+        self.print_with_stats(&self.stats);
+    }
+
+    /// Print info to stdout in a human-readable format, with lifetime stats
+    /// instead of those since the stats were last zeroed.
+    pub fn print_lifetime(&self) {
+        self.print_with_stats(&self.lifetime_stats);
+    }
+
+    fn print_with_stats(&self, stats: &ServerStats) {
+        let (name_width, stat_width) = stats.print();
         println!("{:<name_width$} {}", "Cache location", self.cache_location, name_width=name_width);
+        println!("{:<name_width$} {}", "Server version", self.version, name_width=name_width);
         for &(name, val) in &[("Cache size", &self.cache_size),
                              ("Max cache size", &self.max_cache_size)] {
             if let &Some(val) = val {
@@ -805,6 +1683,134 @@ impl ServerInfo {
                 println!("{:<name_width$} {:>stat_width$} {}", name, val, suffix, name_width=name_width, stat_width=stat_width);
             }
         }
+        print_breakdown("Compiler", &stats.by_compiler);
+        print_breakdown("Language", &stats.by_language);
+        if !stats.not_cached.is_empty() {
+            println!("");
+            println!("Non-cacheable reasons:");
+            let mut reasons = stats.not_cached.iter().collect::<Vec<_>>();
+            reasons.sort_by(|&(a, a_count), &(b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+            let name_width = reasons.iter().map(|&(n, _)| n.len()).max().unwrap();
+            let stat_width = reasons.iter().map(|&(_, c)| c.to_string().len()).max().unwrap();
+            for (reason, count) in reasons {
+                println!("{:<name_width$} {:>stat_width$}", reason, count, name_width=name_width, stat_width=stat_width);
+            }
+        }
+    }
+}
+
+impl ServerStatus {
+    /// Get descriptions of what's wrong with the server, if anything.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec!();
+        if let Some(ref e) = self.storage_error {
+            problems.push(format!("cache storage isn't working: {}", e));
+        }
+        if let Some(ref e) = self.credentials_error {
+            problems.push(format!("couldn't get storage credentials: {}", e));
+        }
+        if let Some(expire_at) = self.credentials_expire_at {
+            if expire_at <= unix_time() {
+                problems.push("storage credentials have expired".to_owned());
+            }
+        }
+        problems
+    }
+
+    /// Print the status to stdout in a human-readable format.
+    pub fn print(&self) {
+        let probe = match self.storage_probe_duration {
+            Some(ref duration) => format!("ok in {}", fmt_duration_as_secs(duration)),
+            None => "failed".to_owned(),
+        };
+        let mut rows = vec![("Server version", self.version.clone()),
+                            ("Cache location", self.cache_location.clone()),
+                            ("Storage probe", probe)];
+        if let Some(expire_at) = self.credentials_expire_at {
+            let left = expire_at - unix_time();
+            let expiry = if left > 0 { format!("in {} s", left) } else { "expired".to_owned() };
+            rows.push(("Credentials expire", expiry));
+        }
+        rows.push(("Active compiles", self.active_compiles.to_string()));
+        rows.push(("Cached compilers", self.cached_compilers.to_string()));
+        let problems = self.problems();
+        let health = if problems.is_empty() { "healthy" } else { "unhealthy" };
+        rows.push(("Status", health.to_owned()));
+        let name_width = rows.iter().map(|&(name, _)| name.len()).max().unwrap();
+        for (name, val) in rows {
+            println!("{:<name_width$} {}", name, val, name_width=name_width);
+        }
+        for problem in problems {
+            println!("  {}", problem);
+        }
+    }
+}
+
+/// Get the current time, in seconds since the Unix epoch.
+fn unix_time() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Describe `err` along with its causes, on one line.
+fn error_chain_string(err: &Error) -> String {
+    err.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ")
+}
+
+/// Check that `storage` works by writing a small entry to it and reading
+/// it back. Returns how long that took.
+fn probe_storage(storage: Arc<Storage>) -> SFuture<Duration> {
+    let start = Instant::now();
+    let mut entry = CacheWrite::new();
+    ftry!(entry.put_object("probe", &mut &b"sccache"[..], None));
+    let put = storage.put(STATUS_PROBE_KEY, entry);
+    let get = put.and_then(move |_| storage.get(STATUS_PROBE_KEY));
+    Box::new(get.and_then(move |cache| {
+        match cache {
+            Cache::Hit(_) => Ok(start.elapsed()),
+            _ => Err("the entry written to the cache wasn't found".into()),
+        }
+    }))
+}
+
+/// Fail `future` if it doesn't finish within `timeout`.
+fn with_timeout<T: 'static>(future: SFuture<T>, timeout: Duration, handle: &Handle) -> SFuture<T> {
+    let timeout = future::result(Timeout::new(timeout, handle)).flatten()
+        .then(|_| -> Result<T> { bail!("timed out") });
+    Box::new(future.select(timeout).then(|res| {
+        match res {
+            Ok((t, _)) => Ok(t),
+            Err((e, _)) => Err(e),
+        }
+    }))
+}
+
+/// Print a table of `breakdown` to stdout, with one row per compiler kind or
+/// language and `title` heading the first column.
+fn print_breakdown(title: &str, breakdown: &HashMap<String, BreakdownStats>) {
+    if breakdown.is_empty() {
+        return;
+    }
+    let average = |total: Duration, count: u64| {
+        let d = if count > 0 { total / count as u32 } else { Duration::new(0, 0) };
+        fmt_duration_as_secs(&d)
+    };
+    let mut rows = breakdown.iter().collect::<Vec<_>>();
+    rows.sort_by(|&(a, _), &(b, _)| a.cmp(b));
+    let name_width = rows.iter().map(|&(n, _)| n.len()).chain(Some(title.len())).max().unwrap();
+    println!("");
+    println!("{:<name_width$} {:>8} {:>8} {:>12} {:>8} {:>13} {:>13} {:>13}",
+             title, "Hits", "Misses", "Uncacheable", "Failed", "Avg compile", "Avg read hit",
+             "Avg write", name_width=name_width);
+    for (name, stats) in rows {
+        println!("{:<name_width$} {:>8} {:>8} {:>12} {:>8} {:>13} {:>13} {:>13}",
+                 name, stats.cache_hits, stats.cache_misses, stats.not_cacheable,
+                 stats.compile_fails,
+                 average(stats.compile_duration, stats.compiles),
+                 average(stats.cache_read_hit_duration, stats.cache_hits),
+                 average(stats.cache_write_duration, stats.cache_writes),
+                 name_width=name_width);
     }
 }
 
@@ -20,23 +20,32 @@ use ::commands::{
     do_compile,
     request_shutdown,
     request_stats,
+    request_status,
+    request_zero_stats,
 };
 use env_logger;
+use ::event_log::CompileEvent;
 use futures::sync::oneshot::{self, Sender};
 use futures_cpupool::CpuPool;
 use jobserver::Client;
 use ::mock_command::*;
+use ::protocol::{Request, Response, Version};
+use serde_json;
 use ::server::{
+    ServerAddr,
     ServerMessage,
     SccacheServer,
 };
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{
+    BufRead,
+    BufReader,
     Cursor,
+    Read,
     Write,
 };
 use std::net::TcpListener;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::{Arc,Mutex,mpsc};
 use std::thread;
@@ -52,18 +61,24 @@ struct ServerOptions {
     idle_timeout: Option<u64>,
     /// The maximum size of the disk cache.
     cache_size: Option<u64>,
+    /// The address to listen on, instead of a free TCP port.
+    addr: Option<ServerAddr>,
+    /// The file to save stats to.
+    stats_file: Option<PathBuf>,
+    /// The file to log compile events to.
+    event_log: Option<PathBuf>,
 }
 
 /// Run a server on a background thread, and return a tuple of useful things.
 ///
-/// * The port on which the server is listening.
+/// * The address on which the server is listening.
 /// * A `Sender` which can be used to send messages to the server.
 ///   (Most usefully, ServerMessage::Shutdown.)
 /// * An `Arc`-and-`Mutex`-wrapped `MockCommandCreator` which the server will
 ///   use for all process creation.
 /// * The `JoinHandle` for the server thread.
 fn run_server_thread<T>(cache_dir: &Path, options: T)
-                        -> (u16, Sender<ServerMessage>, Arc<Mutex<MockCommandCreator>>, thread::JoinHandle<()>)
+                        -> (ServerAddr, Sender<ServerMessage>, Arc<Mutex<MockCommandCreator>>, thread::JoinHandle<()>)
     where T: Into<Option<ServerOptions>> + Send + 'static
 {
     let options = options.into();
@@ -73,6 +88,9 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
                             .and_then(|o| o.cache_size.as_ref())
                             .map(|s| *s)
                             .unwrap_or(u64::MAX);
+    let addr = options.as_ref()
+                      .and_then(|o| o.addr.clone())
+                      .unwrap_or(ServerAddr::Tcp(0));
     let pool = CpuPool::new(1);
     let storage = Arc::new(DiskCache::new(&cache_dir, cache_size, &pool));
 
@@ -82,29 +100,35 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
     let handle = thread::spawn(move || {
         let core = Core::new().unwrap();
         let client = unsafe { Client::new() };
-        let srv = SccacheServer::new(0, pool, core, client, storage).unwrap();
+        let srv = SccacheServer::new(&addr, pool, core, client, storage).unwrap();
         let mut srv: SccacheServer<Arc<Mutex<MockCommandCreator>>> = srv;
-        assert!(srv.port() > 0);
+        assert!(srv.addr() != ServerAddr::Tcp(0));
         if let Some(options) = options {
             if let Some(timeout) = options.idle_timeout {
                  srv.set_idle_timeout(Duration::from_millis(timeout));
             }
+            if let Some(path) = options.stats_file {
+                srv.set_stats_file(path);
+            }
+            if let Some(path) = options.event_log {
+                srv.set_event_log(&path).unwrap();
+            }
         }
-        let port = srv.port();
+        let addr = srv.addr();
         let creator = srv.command_creator().clone();
-        tx.send((port, creator)).unwrap();
+        tx.send((addr, creator)).unwrap();
         srv.run(shutdown_rx).unwrap();
     });
-    let (port, creator) = rx.recv().unwrap();
-    (port, shutdown_tx, creator, handle)
+    let (addr, creator) = rx.recv().unwrap();
+    (addr, shutdown_tx, creator, handle)
 }
 
 #[test]
 fn test_server_shutdown() {
     let f = TestFixture::new();
-    let (port, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let (addr, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
     // Connect to the server.
-    let conn = connect_to_server(port).unwrap();
+    let conn = connect_to_server(&addr).unwrap();
     // Ask it to shut down
     request_shutdown(conn).unwrap();
     // Ensure that it shuts down.
@@ -116,7 +140,7 @@ fn test_server_shutdown() {
 fn test_server_shutdown_no_idle() {
     let f = TestFixture::new();
     // Set a ridiculously low idle timeout.
-    let (port, _sender, _storage, child) = run_server_thread(
+    let (addr, _sender, _storage, child) = run_server_thread(
         &f.tempdir.path(),
         ServerOptions {
             idle_timeout: Some(0),
@@ -124,7 +148,7 @@ fn test_server_shutdown_no_idle() {
         },
     );
 
-    let conn = connect_to_server(port).unwrap();
+    let conn = connect_to_server(&addr).unwrap();
     request_shutdown(conn).unwrap();
     child.join().unwrap();
 }
@@ -133,7 +157,7 @@ fn test_server_shutdown_no_idle() {
 fn test_server_idle_timeout() {
     let f = TestFixture::new();
     // Set a ridiculously low idle timeout.
-    let (_port, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), ServerOptions { idle_timeout: Some(1), .. Default::default() });
+    let (_addr, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), ServerOptions { idle_timeout: Some(1), .. Default::default() });
     // Don't connect to it.
     // Ensure that it shuts down.
     // It would be nice to have an explicit timeout here so we don't hang
@@ -144,9 +168,9 @@ fn test_server_idle_timeout() {
 #[test]
 fn test_server_stats() {
     let f = TestFixture::new();
-    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let (addr, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
     // Connect to the server.
-    let conn = connect_to_server(port).unwrap();
+    let conn = connect_to_server(&addr).unwrap();
     // Ask it for stats.
     let info = request_stats(conn).unwrap();
     assert_eq!(0, info.stats.compile_requests);
@@ -156,12 +180,97 @@ fn test_server_stats() {
     child.join().unwrap();
 }
 
+#[cfg(unix)]
+#[test]
+fn test_server_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let f = TestFixture::new();
+    let path = f.tempdir.path().join("server.sock");
+    // A socket left behind by a server that went away shouldn't get in the way.
+    drop(::std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let (addr, sender, _storage, child) = run_server_thread(
+        &f.tempdir.path(),
+        ServerOptions {
+            addr: Some(ServerAddr::Unix(path.clone())),
+            ..Default::default()
+        },
+    );
+    assert_eq!(ServerAddr::Unix(path.clone()), addr);
+    let mode = ::std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(0o600, mode & 0o777);
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+    // The socket is removed once the server shuts down.
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_server_stats_saved() {
+    let f = TestFixture::new();
+    let stats_file = f.tempdir.path().join("stats.json");
+    let options = || ServerOptions {
+        stats_file: Some(stats_file.clone()),
+        ..Default::default()
+    };
+    let (addr, _sender, server_creator, child) = run_server_thread(&f.tempdir.path(), options());
+    // The server will check the compiler, so pretend to be an unsupported
+    // compiler.
+    server_creator.lock().unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "hello", "error")));
+    let client_creator = new_creator();
+    client_creator.lock().unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "", "")));
+    let conn = connect_to_server(&addr).unwrap();
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, do_compile(client_creator, &mut core, conn, &f.bins[0], cmdline, f.tempdir.path(),
+                             Some(f.paths.clone()), vec![], &mut stdout, &mut stderr).unwrap());
+    request_shutdown(connect_to_server(&addr).unwrap()).unwrap();
+    child.join().unwrap();
+    assert!(stats_file.exists());
+
+    // A new server picks up where the last one left off.
+    let (addr, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), options());
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(1, info.stats.compile_requests);
+    assert_eq!(1, info.lifetime_stats.compile_requests);
+    // Zeroing the stats keeps the lifetime stats.
+    let info = request_zero_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    assert_eq!(1, info.lifetime_stats.compile_requests);
+    request_shutdown(connect_to_server(&addr).unwrap()).unwrap();
+    child.join().unwrap();
+
+    let (addr, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), options());
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    assert_eq!(1, info.lifetime_stats.compile_requests);
+    request_shutdown(connect_to_server(&addr).unwrap()).unwrap();
+    child.join().unwrap();
+
+    // Stats that can't be read are left alone.
+    File::create(&stats_file).unwrap().write_all(b"{\"stats\": [").unwrap();
+    let (addr, _sender, _storage, child) = run_server_thread(&f.tempdir.path(), options());
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(0, info.lifetime_stats.compile_requests);
+    request_shutdown(connect_to_server(&addr).unwrap()).unwrap();
+    child.join().unwrap();
+    let mut contents = String::new();
+    File::open(&stats_file).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!("{\"stats\": [", contents);
+}
+
 #[test]
 fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
-    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let (addr, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
     // Connect to the server.
-    let conn = connect_to_server(port).unwrap();
+    let conn = connect_to_server(&addr).unwrap();
     {
         let mut c = server_creator.lock().unwrap();
         // The server will check the compiler, so pretend to be an unsupported
@@ -197,6 +306,137 @@ fn test_server_unsupported_compiler() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_not_cacheable_reasons() {
+    let f = TestFixture::new();
+    let (addr, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+    }
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    for _ in 0..2 {
+        // The server can't cache this, so the client runs it.
+        client_creator.lock().unwrap()
+            .next_command_spawns(Ok(MockChild::new(exit_status(0), "", "")));
+        let conn = connect_to_server(&addr).unwrap();
+        let cmdline = vec!["-c".into(), "-o".into(), "file.o".into()];
+        let mut stdout = Cursor::new(Vec::new());
+        let mut stderr = Cursor::new(Vec::new());
+        let path = Some(f.paths.clone());
+        let mut core = Core::new().unwrap();
+        assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    }
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(2, info.stats.requests_not_cacheable);
+    assert_eq!(Some(&2), info.stats.not_cached.get("no input file"));
+    // Zeroing the stats forgets the reasons too.
+    let info = request_zero_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert!(info.stats.not_cached.is_empty());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_status() {
+    let f = TestFixture::new();
+    let (addr, sender, _, child) = run_server_thread(&f.tempdir.path(), None);
+    let status = request_status(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(env!("CARGO_PKG_VERSION"), status.version);
+    // The disk cache works, and needs no credentials.
+    assert!(status.storage_probe_duration.is_some());
+    assert_eq!(None, status.storage_error);
+    assert_eq!(None, status.credentials_expire_at);
+    assert_eq!(0, status.active_compiles);
+    assert_eq!(0, status.cached_compilers);
+    assert!(status.problems().is_empty());
+    // Probes overwrite the same entry.
+    fn count_files(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().map(|e| {
+            let path = e.unwrap().path();
+            if path.is_dir() { count_files(&path) } else { 1 }
+        }).sum()
+    }
+    let files = count_files(f.tempdir.path());
+    request_status(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(files, count_files(f.tempdir.path()));
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_version() {
+    let f = TestFixture::new();
+    let (addr, sender, _, child) = run_server_thread(&f.tempdir.path(), None);
+    let mut conn = connect_to_server(&addr).unwrap();
+    match conn.request(Request::GetVersion).unwrap() {
+        Response::Version(version) => assert_eq!(Version::current(), version),
+        r => panic!("Unexpected response: {:?}", r),
+    }
+    // Stats say which version they came from too.
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(env!("CARGO_PKG_VERSION"), info.version);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_event_log() {
+    let f = TestFixture::new();
+    let log = f.tempdir.path().join("events.jsonl");
+    let (addr, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), ServerOptions {
+        event_log: Some(log.clone()),
+        ..Default::default()
+    });
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // Preprocessor invocation.
+        c.next_command_calls(|args| preprocessor_child(args, "preprocessor output"));
+        // Compiler invocation.
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            let mut f = File::create(&obj)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+    }
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    // The server can't cache the second compile, so the client runs it.
+    client_creator.lock().unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "", "")));
+    for cmdline in vec![ovec!["-c", "file.c", "-o", "file.o"], ovec!["-c", "-o", "file.o"]] {
+        let conn = connect_to_server(&addr).unwrap();
+        let mut stdout = Cursor::new(Vec::new());
+        let mut stderr = Cursor::new(Vec::new());
+        let path = Some(f.paths.clone());
+        let mut core = Core::new().unwrap();
+        assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    }
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+
+    let events = BufReader::new(File::open(&log).unwrap()).lines()
+        .map(|l| serde_json::from_str::<CompileEvent>(&l.unwrap()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(2, events.len());
+    assert_eq!("gcc", events[0].compiler);
+    assert_eq!(Some("c".to_owned()), events[0].language);
+    assert_eq!("miss", events[0].result);
+    assert_eq!(Some("normal".to_owned()), events[0].miss_type);
+    assert!(events[0].hash_key.is_some());
+    assert!(events[0].compile_duration.is_some());
+    assert_eq!(cwd.to_string_lossy(), events[0].cwd);
+    assert_eq!("not_cacheable", events[1].result);
+    assert_eq!(Some("no input file".to_owned()), events[1].not_cacheable_reason);
+}
+
 #[test]
 fn test_server_compile() {
     match env_logger::init() {
@@ -204,13 +444,13 @@ fn test_server_compile() {
         Err(_) => {},
     }
     let f = TestFixture::new();
-    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let (addr, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
     // Connect to the server.
     const PREPROCESSOR_STDOUT : &'static [u8] = b"preprocessor stdout";
     const PREPROCESSOR_STDERR : &'static [u8] = b"preprocessor stderr";
     const STDOUT : &'static [u8] = b"some stdout";
     const STDERR : &'static [u8] = b"some stderr";
-    let conn = connect_to_server(port).unwrap();
+    let conn = connect_to_server(&addr).unwrap();
     {
         let mut c = server_creator.lock().unwrap();
         // The server will check the compiler. Pretend it's GCC.
@@ -250,6 +490,173 @@ fn test_server_compile() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_compile_failed_stats() {
+    let f = TestFixture::new();
+    let (addr, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // Preprocessor invocation.
+        c.next_command_calls(|args| preprocessor_child(args, "preprocessor output"));
+        // Compiler invocation, which fails.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(1), "", "error")));
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths.clone());
+    let mut core = Core::new().unwrap();
+    let conn = connect_to_server(&addr).unwrap();
+    assert_eq!(1, do_compile(client_creator, &mut core, conn, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    // Failed compiles are counted for their compiler and language too.
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(1, info.stats.compile_fails);
+    assert_eq!(1, info.stats.by_compiler["gcc"].compile_fails);
+    assert_eq!(1, info.stats.by_language["c"].compile_fails);
+    assert_eq!(0, info.stats.by_language["c"].cache_misses);
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_error_fallback() {
+    let f = TestFixture::new();
+    let (addr, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // The preprocessor can't even be run, which isn't the compiler's fault.
+        c.next_command_spawns(Err("failed to spawn".into()));
+    }
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    // So the client runs the compile itself.
+    client_creator.lock().unwrap()
+        .next_command_spawns(Ok(MockChild::new(exit_status(0), "local stdout", "")));
+    let conn = connect_to_server(&addr).unwrap();
+    let cmdline = ovec!["-c", "file.c", "-o", "file.o"];
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths.clone());
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    assert_eq!(b"local stdout", stdout.into_inner().as_slice());
+    assert!(stderr.into_inner().is_empty());
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(1, info.stats.cache_errors);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_error_after_compile() {
+    let f = TestFixture::new();
+    let (addr, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        c.next_command_calls(|args| preprocessor_child(args, "preprocessor output"));
+        // The compiler runs and prints something, but doesn't write its
+        // output, so it can't be cached.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "compiler stdout", "")));
+    }
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    // The client doesn't run the compile again, since its output was
+    // already printed. It would panic if it tried.
+    let client_creator = new_creator();
+    let conn = connect_to_server(&addr).unwrap();
+    let cmdline = ovec!["-c", "file.c", "-o", "file.o"];
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths.clone());
+    let mut core = Core::new().unwrap();
+    assert_eq!(-2, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    assert_eq!(b"compiler stdout", stdout.into_inner().as_slice());
+    // The server's error is still reported.
+    let stderr = String::from_utf8(stderr.into_inner()).unwrap();
+    assert!(stderr.starts_with("sccache: encountered fatal error\n"), "{}", stderr);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_multiple_inputs() {
+    match env_logger::init() {
+        Ok(_) => {},
+        Err(_) => {},
+    }
+    let f = TestFixture::new();
+    let (addr, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let conn = connect_to_server(&addr).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // The preprocessor and compiler invocations for each input may happen
+        // in any order, so answer each one based on its arguments.
+        let dir = f.tempdir.path().to_path_buf();
+        for _ in 0..4 {
+            let dir = dir.clone();
+            c.next_command_calls(move |args| {
+                let input = args.iter()
+                    .find(|a| a.to_string_lossy().ends_with(".c"))
+                    .map(|a| a.to_string_lossy().into_owned())
+                    .unwrap();
+                if args.iter().any(|a| a == "-E") {
+                    return Ok(MockChild::new(exit_status(0), input, ""));
+                }
+                let obj = args.iter()
+                    .skip_while(|a| *a != "-o")
+                    .nth(1)
+                    .unwrap();
+                let mut f = File::create(dir.join(obj))?;
+                f.write_all(b"file contents")?;
+                Ok(MockChild::new(exit_status(0), format!("{} stdout\n", input), ""))
+            });
+        }
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "a.c".into(), "b.c".into()];
+    let cwd = f.tempdir.path();
+    // This creator shouldn't create any processes. It will assert if
+    // it tries to.
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Output is combined in the order of the inputs.
+    assert_eq!(&b"a.c stdout\nb.c stdout\n"[..], stdout.into_inner().as_slice());
+    assert!(f.tempdir.path().join("a.o").exists());
+    assert!(f.tempdir.path().join("b.o").exists());
+    let info = request_stats(connect_to_server(&addr).unwrap()).unwrap();
+    assert_eq!(1, info.stats.compile_requests);
+    assert_eq!(2, info.stats.requests_executed);
+    assert_eq!(2, info.stats.cache_misses);
+    assert_eq!(2, info.stats.by_compiler["gcc"].cache_misses);
+    assert_eq!(2, info.stats.by_compiler["gcc"].compiles);
+    assert_eq!(2, info.stats.by_language["c"].cache_misses);
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_port_in_use() {
     // Bind an arbitrary free port.
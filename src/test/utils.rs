@@ -19,7 +19,7 @@ use std::collections::HashMap;
 use std::env;
 use std::ffi::OsString;
 use std::fs::{self,File};
-use std::io;
+use std::io::{self, Write};
 use std::path::{Path,PathBuf};
 
 use std::sync::{Arc,Mutex};
@@ -85,6 +85,17 @@ pub fn next_command_calls<C: Fn(&[OsString]) -> Result<MockChild> + Send + 'stat
     creator.lock().unwrap().next_command_calls(call);
 }
 
+/// Pretend to be a gcc or clang preprocessor run with `args`, printing
+/// `output`. Like them, write an empty dependency list to the file given
+/// with `-MF`, if any.
+pub fn preprocessor_child(args: &[OsString], output: &str) -> Result<MockChild> {
+    if let Some(i) = args.iter().position(|a| a == "-MF") {
+        let mut f = File::create(&args[i + 1])?;
+        f.write_all(b"output.o:\n")?;
+    }
+    Ok(MockChild::new(exit_status(0), output, ""))
+}
+
 pub fn find_sccache_binary() -> PathBuf {
     // Older versions of cargo put the test binary next to the sccache binary.
     // Newer versions put it in the deps/ subdirectory.
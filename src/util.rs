@@ -12,9 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use futures::Future;
+use futures::{Future, future};
+use futures::future::Loop;
+use futures::sync::mpsc;
 use futures_cpupool::CpuPool;
 use mock_command::{CommandChild, RunCommand};
+use protocol::CompileOutput;
 use ring::digest::{SHA512, Context};
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
@@ -23,10 +26,14 @@ use std::io::BufReader;
 use std::io::prelude::*;
 use std::path::PathBuf;
 use std::process::{self,Stdio};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio_io::AsyncRead;
 
 use errors::*;
 
+/// Where to send the output of a process as it is produced.
+pub type OutputSink = mpsc::UnboundedSender<CompileOutput>;
+
 pub struct Digest {
     inner: Context,
 }
@@ -67,6 +74,21 @@ impl Digest {
     }
 }
 
+/// Calculate the digest of each file in `files` on background threads
+/// in `pool`.
+pub fn hash_all<T>(files: Vec<T>, pool: &CpuPool) -> SFuture<Vec<String>>
+    where T: Into<PathBuf>
+{
+    let start = Instant::now();
+    let count = files.len();
+    let pool = pool.clone();
+    Box::new(future::join_all(files.into_iter().map(move |f| Digest::file(f, &pool)))
+             .map(move |hashes| {
+                 trace!("Hashed {} files in {}", count, fmt_duration_as_secs(&start.elapsed()));
+                 hashes
+             }))
+}
+
 fn hex(bytes: &[u8]) -> String {
     let mut s = String::with_capacity(bytes.len() * 2);
     for &byte in bytes {
@@ -89,25 +111,63 @@ pub fn fmt_duration_as_secs(duration: &Duration) -> String
     format!("{}.{:03} s", duration.as_secs(), duration.subsec_nanos() / 1000_000)
 }
 
+/// Convert `duration` to fractional seconds.
+pub fn duration_as_secs_f64(duration: &Duration) -> f64
+{
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Read all of `io`, also sending each chunk read to `sink`, wrapped with
+/// `chunk`, if it is `Some`.
+fn read_to_end_streaming<R>(io: R,
+                            sink: Option<OutputSink>,
+                            chunk: fn(Vec<u8>) -> CompileOutput)
+                            -> SFuture<Vec<u8>>
+    where R: AsyncRead + 'static,
+{
+    use tokio_io::io::{read, read_to_end};
+    let sink = match sink {
+        Some(sink) => sink,
+        None => return Box::new(read_to_end(io, Vec::new()).map(|p| p.1).map_err(Error::from)),
+    };
+    Box::new(future::loop_fn((io, Vec::new()), move |(io, mut out)| {
+        let sink = sink.clone();
+        read(io, vec![0; 4096]).map(move |(io, buf, n)| {
+            if n == 0 {
+                return Loop::Break(out);
+            }
+            out.extend_from_slice(&buf[..n]);
+            // Nobody listening for the output isn't a problem, since it's
+            // all returned anyway.
+            drop(sink.unbounded_send(chunk(buf[..n].to_vec())));
+            Loop::Continue((io, out))
+        })
+    }).map_err(Error::from))
+}
+
 /// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output.
 ///
+/// If `output` is `Some`, the output is also sent to it as it is read.
+///
 /// This was lifted from `std::process::Child::wait_with_output` and modified
 /// to also write to stdin.
-fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>)
+fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>, output: Option<OutputSink>)
                              -> SFuture<process::Output>
     where T: CommandChild + 'static,
 {
-    use tokio_io::io::{write_all, read_to_end};
+    use tokio_io::io::write_all;
     let stdin = input.and_then(|i| {
         child.take_stdin().map(|stdin| {
             write_all(stdin, i).chain_err(|| "failed to write stdin")
         })
     });
     let stdout = child.take_stdout().map(|io| {
-        read_to_end(io, Vec::new()).chain_err(|| "failed to read stdout")
+        read_to_end_streaming(io, output.clone(), CompileOutput::Stdout)
+            .chain_err(|| "failed to read stdout")
     });
     let stderr = child.take_stderr().map(|io| {
-        read_to_end(io, Vec::new()).chain_err(|| "failed to read stderr")
+        read_to_end_streaming(io, output, CompileOutput::Stderr)
+            .chain_err(|| "failed to read stderr")
     });
 
     // Finish writing stdin before waiting, because waiting drops stdin.
@@ -116,9 +176,7 @@ fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>)
         child.wait().chain_err(|| "failed to wait for child")
     });
 
-    Box::new(status.join3(stdout, stderr).map(|(status, out, err)| {
-        let stdout = out.map(|p| p.1);
-        let stderr = err.map(|p| p.1);
+    Box::new(status.join3(stdout, stderr).map(|(status, stdout, stderr)| {
         process::Output {
             status: status,
             stdout: stdout.unwrap_or_default(),
@@ -131,9 +189,20 @@ fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>)
 ///
 /// If the command returns a non-successful exit status, an error of `ErrorKind::ProcessError`
 /// will be returned containing the process output.
-pub fn run_input_output<C>(mut command: C, input: Option<Vec<u8>>)
+pub fn run_input_output<C>(command: C, input: Option<Vec<u8>>)
                            -> SFuture<process::Output>
     where C: RunCommand
+{
+    run_input_output_streaming(command, input, None)
+}
+
+/// Like `run_input_output`, but if `output` is `Some`, also send the
+/// command's output to it as it is produced.
+pub fn run_input_output_streaming<C>(mut command: C,
+                                     input: Option<Vec<u8>>,
+                                     output: Option<OutputSink>)
+                                     -> SFuture<process::Output>
+    where C: RunCommand
 {
     let child = command
         .no_console()
@@ -144,7 +213,7 @@ pub fn run_input_output<C>(mut command: C, input: Option<Vec<u8>>)
 
     Box::new(child
              .and_then(|child| {
-                 wait_with_input_output(child, input).and_then(|output| {
+                 wait_with_input_output(child, input, output).and_then(|output| {
                      if output.status.success() {
                          f_ok(output)
                      } else {
@@ -272,8 +341,12 @@ impl<'a> Hasher for HashToDigest<'a> {
 
 #[cfg(test)]
 mod tests {
+    use futures::{Future, Stream};
+    use futures::sync::mpsc;
+    use protocol::CompileOutput;
     use std::ffi::{OsStr, OsString};
-    use super::OsStrExt;
+    use std::io::Cursor;
+    use super::{read_to_end_streaming, OsStrExt};
 
     #[test]
     fn simple_starts_with() {
@@ -301,4 +374,17 @@ mod tests {
         assert_eq!(a.split_prefix("foo2"), None);
         assert_eq!(a.split_prefix("b"), None);
     }
+
+    #[test]
+    fn test_read_to_end_streaming() {
+        let (tx, rx) = mpsc::unbounded();
+        let data = vec![b'x'; 5000];
+        let out = read_to_end_streaming(Cursor::new(data.clone()), Some(tx), CompileOutput::Stderr)
+            .wait().unwrap();
+        assert_eq!(data, out);
+        let chunks = rx.collect().wait().unwrap();
+        assert_eq!(vec![CompileOutput::Stderr(vec![b'x'; 4096]),
+                        CompileOutput::Stderr(vec![b'x'; 904])],
+                   chunks);
+    }
 }